@@ -0,0 +1,29 @@
+use std::{io, net::{SocketAddr, UdpSocket}, time::{Duration, Instant}};
+use common::discovery;
+
+/// Listens for server announcements on `port` during `window`, returning every server
+/// heard from in the order they were first heard.
+pub fn discover(port: u16, window: Duration) -> io::Result<Vec<SocketAddr>> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let deadline = Instant::now() + window;
+    let mut servers = Vec::new();
+    let mut buf = [0; 64];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(err) => return Err(err),
+        };
+
+        if let Some(tcp_port) = discovery::decode(&buf[..len]) {
+            let server = SocketAddr::new(from.ip(), tcp_port);
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+    }
+
+    Ok(servers)
+}
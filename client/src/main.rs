@@ -1,40 +1,1137 @@
-use std::{collections::HashMap, env, fs::{self, File}, io::{self, BufRead, BufReader, Write}, net::TcpStream, path::{Path, PathBuf}, process, str, thread, time::Duration};
-use common::{initialize_handlers, priority_list, Chunk, FileList, Packet};
+use std::{cmp::Reverse, collections::{HashMap, HashSet}, env, fmt, fs::{self, File, OpenOptions}, io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, process, str, sync::{mpsc::{self, Receiver, TryRecvError}, Arc}, thread, time::{Duration, Instant, SystemTime}};
+use common::{digest, download::is_safe_name, grow, priority_list, to_hex, protocol, recv_file_list, Codec, Digest, Error, FileList, FlagList, HashList, HintList, MtimeList, Packet, RangeList, Stat, Stream, TransferObserver, RANGE_TO_END};
+use progress_log::{ProgressLog, Tee};
+use template::Template;
+use throttle::RateLimiter;
+use transfer::Transfer;
+use ui::{BarStyle, RedrawMode, TerminalUi, Throughput};
+use watch::{InputWatcher, WatchMode};
+use writer::Backlog;
+
+mod discovery;
+mod mirror;
+mod progress_log;
+mod segmented;
+mod session;
+mod sums;
+mod template;
+mod throttle;
+mod transfer;
+mod ui;
+mod watch;
+mod writer;
+
+const SESSION_SAVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often an idle client asks the server for files it started serving since.
+const LIST_INTERVAL: Duration = Duration::from_secs(5);
+/// A line of the input file, or typed at the prompt, asking for that right away.
+const REFRESH: &str = "REFRESH";
+/// Starts a `PIN name` line of the input file, asking for that file alone until it's done.
+const PIN: &str = "PIN";
+/// Lines of the input file longer than this many bytes, far more than a `name PRIORITY`
+/// line needs, are skipped so that a file that isn't a list of names can't fill memory.
+const MAX_INPUT_LINE: usize = 4096;
+/// How long `--discover` listens for announcements, servers send one every second.
+const DISCOVERY_WINDOW: Duration = Duration::from_secs(3);
 
 struct Config {
     output_dir: PathBuf,
+    temp_dir: PathBuf,
+    input_path: PathBuf,
+    watch_mode: WatchMode,
+    poll_interval: Duration,
+    session_path: PathBuf,
+    strict: bool,
+    /// Leave files whose destination already exists alone instead of overwriting them.
+    no_clobber: bool,
+    max_active: Option<usize>,
+    /// Download this file on its own before any other.
+    pin: Option<String>,
+    compression: Codec,
+    flat: bool,
+    /// Where every file is saved under the output directory, instead of its name.
+    output_template: Option<Template>,
+    ping_interval: Option<Duration>,
+    bar_width: Option<usize>,
+    bar_style: BarStyle,
+    redraw_interval: Duration,
+    compact: bool,
+    redraw: RedrawMode,
+    no_color: bool,
+    /// How often a line with the bytes received since the last one is printed.
+    throughput_interval: Option<Duration>,
+    show_hashes: bool,
+    /// Print the digest of every file, asking the server to compute missing ones, and exit
+    /// without downloading anything.
+    hashes_only: bool,
+    /// Write this file to stdout and exit, without touching the output directory.
+    cat: Option<String>,
+    /// Print what the server knows about this file and exit without downloading it.
+    stat: Option<String>,
+    connect_retries: u32,
+    file_retries: u32,
+    fetch: Option<String>,
+    /// Only learn about the files under this directory of the server.
+    subpath: Option<String>,
+    segments: u64,
+    /// Bytes per second, shared by every file being downloaded.
+    rate_limit: Option<u64>,
+    /// Bytes of every file gathered before they're written to disk.
+    write_buffer: usize,
+    /// Write every file on a thread of its own, so that disk I/O overlaps receiving.
+    offload_writes: bool,
+    /// Bytes waiting for the writer threads at most, across all files.
+    max_buffered: Option<usize>,
+    /// Bytes of a file after which it's flushed and synced, for whoever reads the `.part` file.
+    fsync_interval: Option<u64>,
+    /// Rounds the server may send ahead of the ones received, if it's to wait for them.
+    window: Option<u32>,
+    max_file_size: Option<u64>,
+    max_total: Option<u64>,
+    sums_path: Option<PathBuf>,
+    /// The sums file came from `--manifest`: it names exactly the files to download, and
+    /// its digests are trusted instead of the server's.
+    manifest: bool,
+    progress_log: Option<PathBuf>,
+    /// Bytes the progress log may grow to before it's rotated.
+    progress_log_max: u64,
+    check_space: SpaceCheck,
+    /// Remove the files of the output directory the server doesn't serve once the
+    /// requested ones are finished.
+    delete_extras: bool,
+    /// Only print what `delete_extras` would remove.
+    dry_run: bool,
+    discover: bool,
+    discovery_port: u16,
+    /// Priorities given as `name=PRIORITY` arguments, used instead of the input file.
+    requested: Vec<(String, u8)>,
+    /// Download the files the server recommends, unless the user gives them a priority.
+    use_server_hints: bool,
+    /// Read `name PRIORITY` lines from stdin instead of watching the input file.
+    interactive: bool,
+    /// The input file is `-`: its lines arrive on stdin like with `interactive`, but once
+    /// stdin is closed the client exits as soon as the requested files are finished.
+    stdin_input: bool,
+    /// Download every file modified after this many seconds since the Unix epoch instead
+    /// of reading the input file.
+    newer_than: Option<u64>,
+    /// Whether `newer_than` selects files the server doesn't know the modification time of.
+    include_unknown_mtime: bool,
+    exit_when_done: bool,
+    /// The certificate of the server, for `quic://` addresses.
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    quic_cert: Option<PathBuf>,
+}
+
+fn expect_value(arg_iter: &mut impl Iterator<Item = String>, flag: &str, what: &str) -> String {
+    match arg_iter.next() {
+        Some(value) => value,
+        None => {
+            eprintln!("ERROR: `{flag}` expects {what}");
+            process::exit(1);
+        }
+    }
 }
 
 impl Config {
     fn get() -> Self {
+        let mut input_path = None;
+        let mut watch_mode = WatchMode::Notify;
+        let mut poll_interval = Duration::from_secs(2);
+        let mut resume_session = None;
+        let mut strict = false;
+        let mut no_clobber = false;
+        let mut max_active = None;
+        let mut pin = None;
+        let mut compression = Codec::None;
+        let mut flat = false;
+        let mut output_template = None;
+        let mut ping_interval = None;
+        let mut bar_width = None;
+        let mut bar_style = BarStyle::Unicode;
+        let mut redraw_interval = Duration::from_millis(100);
+        let mut compact = false;
+        let mut no_bars = false;
+        let mut no_color = false;
+        let mut redraw = None;
+        let mut throughput_interval = None;
+        let mut show_hashes = false;
+        let mut hashes_only = false;
+        let mut stat = None;
+        let mut cat = None;
+        let mut connect_retries = 0;
+        let mut file_retries = 0;
+        let mut fetch = None;
+        let mut subpath = None;
+        let mut segments = 1;
+        let mut write_buffer = 64 << 10;
+        let mut offload_writes = false;
+        let mut max_buffered = None;
+        let mut fsync_interval = None;
+        let mut window = None;
+        let mut rate_limit = match env::var("CLIENT_RATE_LIMIT") {
+            Ok(limit) => match limit.parse() {
+                Ok(0) => None,
+                Ok(bytes) => Some(bytes),
+                Err(_) => {
+                    eprintln!("ERROR: `CLIENT_RATE_LIMIT` expects a number of bytes per second");
+                    process::exit(1);
+                },
+            },
+            Err(_) => None,
+        };
+        let mut max_file_size = None;
+        let mut max_total = None;
+        let mut progress_log = None;
+        let mut progress_log_max = 10 << 20;
+        let mut check_space = SpaceCheck::Warn;
+        let mut delete_extras = false;
+        let mut dry_run = false;
+        let mut sums_path = None;
+        let mut manifest_path = None;
+        let mut discover = false;
+        let mut requested = Vec::new();
+        let mut use_server_hints = false;
+        let mut interactive = false;
+        let mut newer_than = None;
+        let mut include_unknown_mtime = false;
+        let mut exit_when_done = false;
+        let mut quic_cert = None;
+
+        let mut arg_iter = env::args();
+        arg_iter.next();
+        while let Some(arg) = arg_iter.next() {
+            match arg.as_str() {
+                "--resume-session" => {
+                    resume_session = Some(expect_value(&mut arg_iter, &arg, "a file path").into());
+                },
+                "--strict" => strict = true,
+                "--no-clobber" => no_clobber = true,
+                "--flat" => flat = true,
+                "--output-template" => output_template = Some(expect_value(&mut arg_iter, &arg, "a template")),
+                "--compact" => compact = true,
+                "--no-bars" => no_bars = true,
+                "--no-color" => no_color = true,
+                "--redraw" => match expect_value(&mut arg_iter, &arg, "a mode").as_str() {
+                    "auto" => redraw = None,
+                    "in-place" => redraw = Some(RedrawMode::InPlace),
+                    "line" => redraw = Some(RedrawMode::Line),
+                    "reprint" => redraw = Some(RedrawMode::Reprint),
+                    mode => {
+                        eprintln!("ERROR: Unknown redraw mode `{mode}`, expected `auto`, `in-place`, `line` or `reprint`");
+                        process::exit(1);
+                    },
+                },
+                "--throughput-interval" => match expect_value(&mut arg_iter, &arg, "a number of seconds").parse() {
+                    Ok(0) => throughput_interval = None,
+                    Ok(secs) => throughput_interval = Some(Duration::from_secs(secs)),
+                    Err(_) => {
+                        eprintln!("ERROR: `--throughput-interval` expects a number of seconds");
+                        process::exit(1);
+                    },
+                },
+                "--show-hashes" => show_hashes = true,
+                "--hashes-only" => hashes_only = true,
+                "--cat" => cat = Some(expect_value(&mut arg_iter, &arg, "a file name")),
+                "--pin" => pin = Some(expect_value(&mut arg_iter, &arg, "a file name")),
+                "--stat" => stat = Some(expect_value(&mut arg_iter, &arg, "a file name")),
+                "--discover" => discover = true,
+                "--exit-when-done" => exit_when_done = true,
+                "--use-server-hints" => use_server_hints = true,
+                "--offload-writes" => offload_writes = true,
+                "--max-buffered" => match expect_value(&mut arg_iter, &arg, "a number of bytes").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--max-buffered` expects a positive number of bytes");
+                        process::exit(1);
+                    },
+                    Ok(bytes) => max_buffered = Some(bytes),
+                },
+                "--interactive" => interactive = true,
+                "--delete" => delete_extras = true,
+                "--dry-run" => dry_run = true,
+                "--include-unknown-mtime" => include_unknown_mtime = true,
+                "--newer-than" => match expect_value(&mut arg_iter, &arg, "a Unix timestamp").parse() {
+                    Ok(secs) => newer_than = Some(secs),
+                    Err(_) => {
+                        eprintln!("ERROR: `--newer-than` expects a Unix timestamp in seconds");
+                        process::exit(1);
+                    },
+                },
+                "--max-active" => match expect_value(&mut arg_iter, &arg, "a number").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--max-active` expects a positive number");
+                        process::exit(1);
+                    },
+                    Ok(count) => max_active = Some(count),
+                },
+                "--ping-interval" => match expect_value(&mut arg_iter, &arg, "a number of seconds").parse() {
+                    Ok(0) => ping_interval = None,
+                    Ok(secs) => ping_interval = Some(Duration::from_secs(secs)),
+                    Err(_) => {
+                        eprintln!("ERROR: `--ping-interval` expects a number of seconds");
+                        process::exit(1);
+                    },
+                },
+                "--bar-width" => match expect_value(&mut arg_iter, &arg, "a number").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--bar-width` expects a positive number");
+                        process::exit(1);
+                    },
+                    Ok(width) => bar_width = Some(width),
+                },
+                "--bar-style" => match expect_value(&mut arg_iter, &arg, "a style").as_str() {
+                    "unicode" => bar_style = BarStyle::Unicode,
+                    "ascii" => bar_style = BarStyle::Ascii,
+                    style => {
+                        eprintln!("ERROR: Unknown bar style `{style}`, expected `unicode` or `ascii`");
+                        process::exit(1);
+                    },
+                },
+                "--watch" => match expect_value(&mut arg_iter, &arg, "a backend").as_str() {
+                    "notify" => watch_mode = WatchMode::Notify,
+                    "poll" => watch_mode = WatchMode::Poll,
+                    backend => {
+                        eprintln!("ERROR: Unknown watch backend `{backend}`, expected `notify` or `poll`");
+                        process::exit(1);
+                    },
+                },
+                "--poll-interval" => match expect_value(&mut arg_iter, &arg, "a number of milliseconds").parse() {
+                    Ok(millis) => poll_interval = Duration::from_millis(millis),
+                    Err(_) => {
+                        eprintln!("ERROR: `--poll-interval` expects a number of milliseconds");
+                        process::exit(1);
+                    },
+                },
+                "--redraw-interval" => match expect_value(&mut arg_iter, &arg, "a number of milliseconds").parse() {
+                    Ok(millis) => redraw_interval = Duration::from_millis(millis),
+                    Err(_) => {
+                        eprintln!("ERROR: `--redraw-interval` expects a number of milliseconds");
+                        process::exit(1);
+                    },
+                },
+                "--connect-retries" => match expect_value(&mut arg_iter, &arg, "a number").parse() {
+                    Ok(count) => connect_retries = count,
+                    Err(_) => {
+                        eprintln!("ERROR: `--connect-retries` expects a number");
+                        process::exit(1);
+                    },
+                },
+                "--file-retries" => match expect_value(&mut arg_iter, &arg, "a number").parse() {
+                    Ok(count) => file_retries = count,
+                    Err(_) => {
+                        eprintln!("ERROR: `--file-retries` expects a number");
+                        process::exit(1);
+                    },
+                },
+                "--fetch" => fetch = Some(expect_value(&mut arg_iter, &arg, "a file name")),
+                "--path" => {
+                    let path = expect_value(&mut arg_iter, &arg, "a directory");
+                    if protocol::check_subpath(&path).is_none() {
+                        eprintln!("ERROR: `--path` expects a relative directory without `.` or `..`, got `{path}`");
+                        process::exit(1);
+                    }
+                    subpath = Some(path);
+                },
+                "--segments" => match expect_value(&mut arg_iter, &arg, "a number").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--segments` expects a positive number");
+                        process::exit(1);
+                    },
+                    Ok(count) => segments = count,
+                },
+                "--write-buffer" => match expect_value(&mut arg_iter, &arg, "a number of bytes").parse() {
+                    Ok(bytes) => write_buffer = bytes,
+                    Err(_) => {
+                        eprintln!("ERROR: `--write-buffer` expects a number of bytes");
+                        process::exit(1);
+                    },
+                },
+                "--fsync-interval" => match expect_value(&mut arg_iter, &arg, "a number of bytes").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--fsync-interval` expects a positive number of bytes");
+                        process::exit(1);
+                    },
+                    Ok(bytes) => fsync_interval = Some(bytes),
+                },
+                "--window" => match expect_value(&mut arg_iter, &arg, "a number of rounds").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--window` expects a positive number of rounds");
+                        process::exit(1);
+                    },
+                    Ok(rounds) => window = Some(rounds),
+                },
+                "--limit" => match expect_value(&mut arg_iter, &arg, "a number of bytes per second").parse() {
+                    Ok(0) => rate_limit = None,
+                    Ok(bytes) => rate_limit = Some(bytes),
+                    Err(_) => {
+                        eprintln!("ERROR: `--limit` expects a number of bytes per second");
+                        process::exit(1);
+                    },
+                },
+                "--max-file-size" => match expect_value(&mut arg_iter, &arg, "a number of bytes").parse() {
+                    Ok(bytes) => max_file_size = Some(bytes),
+                    Err(_) => {
+                        eprintln!("ERROR: `--max-file-size` expects a number of bytes");
+                        process::exit(1);
+                    },
+                },
+                "--max-total" => match expect_value(&mut arg_iter, &arg, "a number of bytes").parse() {
+                    Ok(bytes) => max_total = Some(bytes),
+                    Err(_) => {
+                        eprintln!("ERROR: `--max-total` expects a number of bytes");
+                        process::exit(1);
+                    },
+                },
+                "--progress-log" => progress_log = Some(expect_value(&mut arg_iter, &arg, "a file path").into()),
+                "--progress-log-max" => match expect_value(&mut arg_iter, &arg, "a number of bytes").parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `--progress-log-max` expects a positive number of bytes");
+                        process::exit(1);
+                    },
+                    Ok(bytes) => progress_log_max = bytes,
+                },
+                "--check-space" => match expect_value(&mut arg_iter, &arg, "a mode").as_str() {
+                    "off" => check_space = SpaceCheck::Off,
+                    "warn" => check_space = SpaceCheck::Warn,
+                    "abort" => check_space = SpaceCheck::Abort,
+                    mode => {
+                        eprintln!("ERROR: Unknown space check `{mode}`, expected `off`, `warn` or `abort`");
+                        process::exit(1);
+                    },
+                },
+                "--sums-file" => sums_path = Some(expect_value(&mut arg_iter, &arg, "a file path").into()),
+                "--manifest" => manifest_path = Some(expect_value(&mut arg_iter, &arg, "a file path").into()),
+                "--compression" => {
+                    let name = expect_value(&mut arg_iter, &arg, "a codec name");
+                    compression = Codec::from_name(&name).unwrap_or_else(|| {
+                        eprintln!("ERROR: Unknown codec `{name}`, expected `none`, `deflate` or `zstd`");
+                        process::exit(1);
+                    });
+                },
+                "--quic-cert" => quic_cert = Some(expect_value(&mut arg_iter, &arg, "a file path").into()),
+                _ => match arg.split_once('=') {
+                    Some((name, priority)) => match priority_list::parse(priority) {
+                        Some(priority) => requested.push((name.to_string(), priority)),
+                        None => {
+                            eprintln!("ERROR: Unknown priority `{priority}`, expected `NORMAL`, `HIGH`, `CRITICAL` or a share from `1%` to `100%`");
+                            process::exit(1);
+                        },
+                    },
+                    None => input_path = Some(arg.into()),
+                },
+            }
+        }
+
+        let output_dir: PathBuf = if let Ok(input_dir) = env::var("OUTPUT_DIR") {
+            input_dir.into()
+        } else {
+            "output".into()
+        };
+
+        // `-` can't be watched, its lines are applied as they're read.
+        let stdin_input = input_path.as_ref().is_some_and(|path: &PathBuf| path.as_os_str() == "-");
         Self {
-            output_dir: if let Ok(input_dir) = env::var("OUTPUT_DIR") {
-                input_dir.into()
+            session_path: resume_session.unwrap_or_else(|| output_dir.join(".session")),
+            temp_dir: if let Ok(temp_dir) = env::var("TEMP_DIR") {
+                temp_dir.into()
             } else {
-                "output".into()
+                output_dir.clone()
+            },
+            output_dir,
+            input_path: input_path.unwrap_or_else(|| "input.txt".into()),
+            watch_mode,
+            poll_interval,
+            strict,
+            no_clobber,
+            max_active,
+            pin,
+            compression,
+            flat,
+            output_template: output_template.map(|template| {
+                if flat {
+                    eprintln!("ERROR: `--flat` and `--output-template` can't be used together");
+                    process::exit(1);
+                }
+                Template::parse(&template, SystemTime::now()).unwrap_or_else(|err| {
+                    eprintln!("ERROR: Invalid `--output-template`: {err}");
+                    process::exit(1);
+                })
+            }),
+            ping_interval,
+            bar_width,
+            bar_style,
+            redraw_interval,
+            compact,
+            no_color,
+            redraw: if no_bars { RedrawMode::Off } else { redraw.unwrap_or_else(RedrawMode::detect) },
+            throughput_interval,
+            show_hashes,
+            hashes_only,
+            stat,
+            cat,
+            connect_retries,
+            file_retries,
+            fetch,
+            subpath,
+            segments,
+            rate_limit,
+            write_buffer,
+            offload_writes,
+            max_buffered,
+            fsync_interval,
+            window,
+            max_file_size,
+            max_total,
+            progress_log,
+            progress_log_max,
+            check_space,
+            delete_extras,
+            dry_run,
+            manifest: manifest_path.is_some(),
+            sums_path: match (manifest_path, sums_path) {
+                (Some(_), Some(_)) => {
+                    eprintln!("ERROR: `--manifest` and `--sums-file` can't be used together");
+                    process::exit(1);
+                },
+                (manifest_path, sums_path) => manifest_path.or(sums_path),
             },
+            discover,
+            requested,
+            use_server_hints,
+            interactive,
+            stdin_input,
+            newer_than,
+            include_unknown_mtime,
+            exit_when_done,
+            discovery_port: match env::var("DISCOVERY_PORT") {
+                Ok(port) => match port.parse() {
+                    Ok(0) | Err(_) => {
+                        eprintln!("ERROR: `DISCOVERY_PORT` must be a number from 1 to 65535, got `{port}`");
+                        process::exit(1);
+                    },
+                    Ok(port) => port,
+                },
+                Err(_) => common::discovery::PORT,
+            },
+            quic_cert,
         }
     }
 }
 
-fn read_input(input_path: &Path, inverse_map: &HashMap<&str, usize>, out: &mut [u8]) {
-    if let Ok(input_file) = File::open(input_path) {
-        for line in BufReader::new(input_file).lines() {
-            if let Ok(line) = line {
-                let mut iter = line.split_whitespace();
-                if let Some(filename) = iter.next() {
-                    if let Some(idx) = inverse_map.get(filename) {
-                        out[*idx] = match iter.next() {
-                            Some("NORMAL")   => 1,
-                            Some("HIGH")     => 4,
-                            Some("CRITICAL") => 10,
-                            _                => continue
-                        };
-                    }
-                }
+/// Reads the priorities from the input file. Lines naming a file that isn't served or an
+/// unknown priority are skipped, with a warning the first time each of them is seen, and
+/// so are lines that are too long or aren't UTF-8. `pin` is set to the file of the last
+/// `PIN name` line, if any. Returns whether the file has a `REFRESH` line, asking for the
+/// files the server started serving since.
+fn read_input(input_path: &Path, inverse_map: &HashMap<Box<str>, usize>, out: &mut [u8], pin: &mut Option<usize>, warned: &mut HashSet<String>) -> bool {
+    let mut refresh = false;
+    *pin = None;
+    let Ok(input_file) = File::open(input_path) else {
+        return refresh;
+    };
+    let mut reader = BufReader::new(input_file);
+    let mut buf = Vec::new();
+    for line_no in 1.. {
+        buf.clear();
+        // One byte past the limit tells a line that fits without its newline from one
+        // that doesn't.
+        match (&mut reader).take(MAX_INPUT_LINE as u64 + 1).read_until(b'\n', &mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {},
+        }
+        if buf.last() != Some(&b'\n') && buf.len() > MAX_INPUT_LINE {
+            let _ = reader.skip_until(b'\n');
+            let warning = format!("Skipping line {line_no} of `{}`, it's longer than {MAX_INPUT_LINE} bytes", input_path.display());
+            if warned.insert(warning.clone()) {
+                eprintln!("WARNING: {warning}");
+            }
+            continue;
+        }
+        let Ok(line) = str::from_utf8(&buf) else {
+            let warning = format!("Skipping line {line_no} of `{}`, it isn't valid UTF-8", input_path.display());
+            if warned.insert(warning.clone()) {
+                eprintln!("WARNING: {warning}");
+            }
+            continue;
+        };
+
+        // Like `lines`, drop LF and CRLF endings. Editors may also prepend a BOM.
+        let line = line.strip_suffix('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)).unwrap_or(line);
+        let line = if line_no == 1 { line.trim_start_matches('\u{feff}') } else { line };
+        if line.trim().eq_ignore_ascii_case(REFRESH) {
+            refresh = true;
+            continue;
+        }
+        if let Some(name) = line.trim().strip_prefix(PIN).filter(|name| name.starts_with(char::is_whitespace)) {
+            let name = name.trim();
+            match inverse_map.get(name) {
+                Some(idx) => *pin = Some(*idx),
+                None => if warned.insert(line.trim().to_string()) {
+                    eprintln!("WARNING: {}, pinned in `{}`", LineError::NotServed(name), input_path.display());
+                },
+            }
+            continue;
+        }
+        match parse_line(line, inverse_map) {
+            Ok(Some((idx, priority))) => out[idx] = priority,
+            Ok(None) => {},
+            Err(err) => if warned.insert(line.trim().to_string()) {
+                eprintln!("WARNING: {err}, listed in `{}`", input_path.display());
+            },
+        }
+    }
+    refresh
+}
+
+/// Why a `name PRIORITY` line is skipped.
+enum LineError<'a> {
+    NotServed(&'a str),
+    UnknownPriority(&'a str, &'a str),
+}
+
+impl fmt::Display for LineError<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineError::NotServed(name) => write!(f, "The server doesn't serve `{name}`"),
+            LineError::UnknownPriority(name, priority) => {
+                write!(f, "Unknown priority `{priority}` for `{name}`, expected `NORMAL`, `HIGH`, `CRITICAL` or a share from `1%` to `100%`")
+            },
+        }
+    }
+}
+
+/// Parses a line of the input file or the prompt into the index of a file and its
+/// priority. Blank lines and names without a priority ask for nothing.
+fn parse_line<'a>(line: &'a str, inverse_map: &HashMap<Box<str>, usize>) -> Result<Option<(usize, u8)>, LineError<'a>> {
+    let mut iter = line.split_whitespace();
+    let Some(filename) = iter.next() else {
+        return Ok(None);
+    };
+    let Some(idx) = inverse_map.get(filename) else {
+        return Err(LineError::NotServed(filename));
+    };
+    match iter.next() {
+        Some(priority) => match priority_list::parse(priority) {
+            Some(priority) => Ok(Some((*idx, priority))),
+            None => Err(LineError::UnknownPriority(filename, priority)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Applies the lines typed at the prompt so far to `requested`, setting `refresh` when
+/// asked to. Returns `None` once the user typed `quit` or closed the input, and otherwise
+/// whether anything was asked for.
+fn apply_commands(commands: &Receiver<String>, inverse_map: &HashMap<Box<str>, usize>, requested: &mut Vec<(usize, u8)>, refresh: &mut bool) -> Option<bool> {
+    let mut changed = false;
+    loop {
+        let line = match commands.try_recv() {
+            Ok(line) => line,
+            Err(TryRecvError::Empty) => return Some(changed),
+            Err(TryRecvError::Disconnected) => return None,
+        };
+        if line.trim() == "quit" {
+            return None;
+        }
+        if line.trim().eq_ignore_ascii_case(REFRESH) {
+            *refresh = true;
+            changed = true;
+            continue;
+        }
+        match parse_line(&line, inverse_map) {
+            Ok(Some(request)) => {
+                requested.push(request);
+                changed = true;
+            },
+            Ok(None) if line.trim().is_empty() => {},
+            Ok(None) => eprintln!("WARNING: Expected `name PRIORITY`, got `{}`", line.trim()),
+            Err(err) => eprintln!("WARNING: {err}"),
+        }
+    }
+}
+
+/// Fails once `total` bytes would exceed the `--max-total` cap.
+fn check_total(total: u64, max_total: Option<u64>) -> io::Result<()> {
+    match max_total {
+        Some(max_total) if total > max_total => Err(io::Error::other(format!(
+            "downloading {} would exceed the limit of {}", format_size(total), format_size(max_total)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// What to do when the files about to be downloaded don't fit on the disk.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SpaceCheck {
+    Off,
+    Warn,
+    Abort,
+}
+
+/// Compares the bytes still to be received against the free space of the output
+/// directory, when it's known.
+fn check_space(needed: u64, available: Option<u64>, mode: SpaceCheck) -> io::Result<()> {
+    let Some(available) = available.filter(|available| needed > *available) else {
+        return Ok(());
+    };
+    let msg = format!("the requested files need {} more but only {} are free", format_size(needed), format_size(available));
+    match mode {
+        SpaceCheck::Off => Ok(()),
+        SpaceCheck::Warn => {
+            eprintln!("WARNING: The download may fill the disk, {msg}");
+            Ok(())
+        },
+        SpaceCheck::Abort => Err(io::Error::new(io::ErrorKind::StorageFull, msg)),
+    }
+}
+
+/// Bytes that may still be written to the filesystem holding `path`, by this user.
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::statvfs(path.as_ptr(), &mut stat) };
+    (result == 0).then(|| stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Removes the files of the output directory that aren't served, keeping the partial
+/// downloads and the files of the client itself.
+fn delete_extras(transfer: &Transfer, opt: &Config) {
+    let mut keep: HashSet<PathBuf> = transfer.paths.iter().chain(transfer.part_paths.iter()).cloned().collect();
+    keep.extend([opt.session_path.clone(), opt.input_path.clone()]);
+    keep.extend(opt.sums_path.clone());
+    if let Some(path) = &opt.progress_log {
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        keep.extend([path.clone(), rotated.into()]);
+    }
+    // Paths given as arguments may be spelled differently than the ones found.
+    let canonical: Vec<_> = keep.iter().filter_map(|path| path.canonicalize().ok()).collect();
+    keep.extend(canonical);
+
+    let extras = match mirror::extras(&opt.output_dir, &keep) {
+        Ok(extras) => extras,
+        Err(err) => {
+            eprintln!("ERROR: Failed to look for files to delete in `{}`: {err}", opt.output_dir.display());
+            return;
+        },
+    };
+    for path in extras {
+        if opt.dry_run {
+            println!("Would delete `{}`", path.display());
+        } else if let Err(err) = fs::remove_file(&path) {
+            eprintln!("ERROR: Failed to delete `{}`: {err}", path.display());
+        } else {
+            println!("Deleted `{}`", path.display());
+        }
+    }
+}
+
+/// Identifies one version of the input file. Edits made within the timestamp resolution
+/// of the filesystem keep the modification time, so the size and content are compared too.
+#[derive(PartialEq, Eq)]
+struct InputStamp {
+    modified: SystemTime,
+    len: u64,
+    digest: Digest,
+}
+
+impl InputStamp {
+    /// `None` while the input file doesn't exist, as when an editor deletes it before
+    /// writing the new version. That is the same as requesting nothing.
+    fn of(input_path: &Path) -> io::Result<Option<Self>> {
+        let mut file = match File::open(input_path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let metadata = file.metadata()?;
+        Ok(Some(Self {
+            modified: metadata.modified()?,
+            len: metadata.len(),
+            digest: digest(&mut file)?,
+        }))
+    }
+}
+
+/// Marks the files from `from` on whose destination already exists as done without
+/// downloading them, for `--no-clobber`.
+fn keep_existing(transfer: &mut Transfer, from: usize) {
+    for idx in from..transfer.downloadables.len() {
+        if !transfer.files[idx].done && transfer.paths[idx].symlink_metadata().is_ok() {
+            eprintln!("WARNING: `{}` already exists, not downloading `{}` over it", transfer.paths[idx].display(), transfer.downloadables[idx].0);
+            transfer.files[idx].done = true;
+        }
+    }
+}
+
+/// What is asked for of `next_priorities` while `pin` is downloaded: that file alone, even
+/// if it has no priority of its own.
+fn pinned(next_priorities: &mut [u8], pin: Option<usize>) -> Box<[u8]> {
+    let Some(pin) = pin else {
+        return next_priorities.into();
+    };
+    next_priorities[pin] = next_priorities[pin].max(1);
+    let mut wanted = priority_list::new(next_priorities.len());
+    wanted[pin] = next_priorities[pin];
+    wanted
+}
+
+/// Keeps the highest priority requests that aren't being downloaded yet, so that at most
+/// `max_active` files are in progress at once. Ties are broken by list order.
+fn limit_active(requested: &[u8], current: &[u8], active: usize, max_active: usize) -> Box<[u8]> {
+    let mut pending: Vec<usize> = (0..requested.len())
+        .filter(|idx| requested[*idx] != 0 && current[*idx] == 0)
+        .collect();
+    pending.sort_by_key(|idx| Reverse(requested[*idx]));
+
+    let mut limited = priority_list::new(requested.len());
+    for idx in pending.into_iter().take(max_active.saturating_sub(active)) {
+        limited[idx] = requested[idx];
+    }
+    limited
+}
+
+/// Places every file directly in `output_path`, dropping the directory part of its name.
+/// Names that collide after flattening get a `~N` suffix before their extension.
+fn flat_paths(downloadables: &FileList, output_path: &Path, used: &mut HashSet<String>) -> Vec<PathBuf> {
+    downloadables.iter().map(|(name, _)| {
+        let base = name.rsplit('/').next().unwrap_or(name);
+        let mut flat_name = base.to_string();
+        let mut counter = 1;
+        while !used.insert(flat_name.clone()) {
+            flat_name = match base.rsplit_once('.') {
+                Some((stem, ext)) if !stem.is_empty() => format!("{stem}~{counter}.{ext}"),
+                _ => format!("{base}~{counter}"),
+            };
+            counter += 1;
+        }
+
+        if flat_name != base {
+            eprintln!("WARNING: `{name}` collides with another file, saving it as `{flat_name}`");
+        }
+        output_path.join(flat_name)
+    }).collect()
+}
+
+/// Where the files are saved. `flat_names` are the names already taken in `--flat` mode.
+/// Fails on names that would leave `output_dir`, as served or as `template` renders them,
+/// so nothing is written outside of it.
+fn output_paths(downloadables: &FileList, output_dir: &Path, flat: bool, template: Option<&Template>, flat_names: &mut HashSet<String>) -> common::Result<Vec<PathBuf>> {
+    if let Some((name, _)) = downloadables.iter().find(|(name, _)| !is_safe_name(name)) {
+        return Err(Error::InvalidData(format!("the server sent the unsafe name `{name}`")));
+    }
+    Ok(if flat {
+        flat_paths(downloadables, output_dir, flat_names)
+    } else if let Some(template) = template {
+        downloadables.iter()
+            .map(|(name, _)| match template.render(name) {
+                Some(path) => Ok(output_dir.join(path)),
+                None => Err(Error::InvalidData(format!("`--output-template` would save `{name}` outside the output directory"))),
+            })
+            .collect::<common::Result<_>>()?
+    } else {
+        downloadables.iter()
+            .map(|(name, _)| output_dir.join(name.as_ref()))
+            .collect()
+    })
+}
+
+/// Where a file is downloaded to before it's moved to `path`. The name is the path under
+/// `output_dir` with `/` escaped as `%2F`, so that files of the same name in different
+/// directories don't share one.
+fn part_path(path: &Path, output_dir: &Path, temp_dir: &Path) -> PathBuf {
+    let relative = path.strip_prefix(output_dir).unwrap_or(path);
+    let mut name = relative.components()
+        .map(|component| component.as_os_str().to_string_lossy().replace('%', "%25"))
+        .collect::<Vec<_>>()
+        .join("%2F");
+    name.push_str(".part");
+    temp_dir.join(name)
+}
+
+/// What the server told about itself during the handshake.
+struct Handshake {
+    stream: Stream,
+    codec: Codec,
+    files: FileList,
+    hashes: HashList,
+    flags: FlagList,
+    hints: HintList,
+    mtimes: MtimeList,
+    /// The most chunks the server sends in a round.
+    round_budget: Option<u32>,
+    /// The optional `protocol::FEATURE_*` both sides support.
+    features: u32,
+}
+
+/// Connects to the server and runs the handshake, up to receiving the file list and
+/// what's known about the files, only the ones under `subpath` if it's given. With a
+/// `window`, the server is asked to wait for the `ACK` of rounds that many behind.
+fn connect(addr: &str, compression: Codec, subpath: Option<&str>, window: Option<u32>) -> common::Result<Handshake> {
+    let mut stream = Stream::connect(addr)?;
+
+    stream.write_all(&[compression as u8])?;
+    let codec = {
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf)?;
+        Codec::from_id(buf[0]).ok_or(Error::Protocol("server picked an unknown codec"))?
+    };
+
+    let mut offered = protocol::FEATURES;
+    if subpath.is_none() {
+        offered &= !protocol::FEATURE_SUBPATH;
+    }
+    if window.is_none() {
+        offered &= !protocol::FEATURE_WINDOW;
+    }
+    protocol::send_features(&mut stream, offered)?;
+    let features = protocol::recv_features(&mut stream)?;
+    if features & protocol::REJECTED != 0 {
+        return Err(Error::MissingFeatures(features & !protocol::REJECTED));
+    }
+    let features = features & offered;
+    if let Some(subpath) = subpath {
+        if features & protocol::FEATURE_SUBPATH == 0 {
+            return Err(Error::Protocol("the server can't list a subpath, `--path` needs a newer server"));
+        }
+        protocol::send_subpath(&mut stream, subpath)?;
+    }
+    if let Some(window) = window {
+        if features & protocol::FEATURE_WINDOW == 0 {
+            eprintln!("WARNING: The server can't wait for rounds to be received, `--window` is ignored");
+        } else {
+            protocol::send_window(&mut stream, window)?;
+            // An `ACK` held back until the last one is answered would stall the server.
+            stream.set_nodelay(true)?;
+        }
+    }
+
+    let downloadables = recv_file_list(&mut stream, features & protocol::FEATURE_COMPRESSED_LIST != 0)?;
+    let hashes = if features & protocol::FEATURE_HASHES != 0 {
+        HashList::recv(&mut stream)?
+    } else {
+        vec![None; downloadables.len()].into()
+    };
+    if hashes.len() != downloadables.len() {
+        return Err(Error::InvalidData("hash list doesn't match the file list".into()));
+    }
+    let flags = if features & protocol::FEATURE_FLAGS != 0 {
+        FlagList::recv(&mut stream)?
+    } else {
+        vec![0; downloadables.len()].into()
+    };
+    if flags.len() != downloadables.len() {
+        return Err(Error::InvalidData("flag list doesn't match the file list".into()));
+    }
+    let hints = if features & protocol::FEATURE_HINTS != 0 {
+        HintList::recv(&mut stream)?
+    } else {
+        priority_list::new(downloadables.len())
+    };
+    if hints.len() != downloadables.len() {
+        return Err(Error::InvalidData("hint list doesn't match the file list".into()));
+    }
+    let mtimes = if features & protocol::FEATURE_MTIMES != 0 {
+        MtimeList::recv(&mut stream)?
+    } else {
+        vec![None; downloadables.len()].into()
+    };
+    if mtimes.len() != downloadables.len() {
+        return Err(Error::InvalidData("mtime list doesn't match the file list".into()));
+    }
+    let round_budget = if features & protocol::FEATURE_ROUND_BUDGET != 0 {
+        Some(protocol::recv_round_budget(&mut stream)?)
+    } else {
+        None
+    };
+    Ok(Handshake { stream, codec, files: downloadables, hashes, flags, hints, mtimes, round_budget, features })
+}
+
+/// Whether connecting again may succeed. Anything else, such as a server that speaks
+/// another protocol, will fail the same way every time.
+fn is_transient(err: &Error) -> bool {
+    let Error::Io(err) = err else {
+        return false;
+    };
+    matches!(err.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Connects like `connect`, retrying transient failures up to `retries` times with an
+/// exponential backoff.
+fn connect_with_retries(addr: &str, compression: Codec, subpath: Option<&str>, window: Option<u32>, retries: u32) -> common::Result<Handshake> {
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 0;
+    loop {
+        match connect(addr, compression, subpath, window) {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                attempt += 1;
+                eprintln!("WARNING: Failed to connect: {err}, retrying in {}ms ({attempt}/{retries})", delay.as_millis());
+                thread::sleep(delay);
+                delay = (delay * 2).min(Duration::from_secs(30));
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Checks that the server still answers between rounds, waiting at most `timeout`. With
+/// `outstanding`, the reply to an earlier ping that timed out is waited for instead of
+/// sending another, since it may still arrive.
+fn ping(stream: &mut Stream, timeout: Duration, outstanding: bool) -> io::Result<()> {
+    if !outstanding {
+        stream.write_all(&[protocol::PING])?;
+    }
+    stream.set_read_timeout(Some(timeout))?;
+    let mut reply = [0; 1];
+    let result = stream.read_exact(&mut reply);
+    stream.set_read_timeout(None)?;
+    result?;
+
+    if reply[0] != protocol::PONG {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected reply to ping"));
+    }
+    Ok(())
+}
+
+/// Asks the server for the files it started serving since the client last asked, along
+/// with their flags and modification times.
+fn list_added(stream: &mut Stream, features: u32) -> common::Result<(FileList, FlagList, MtimeList)> {
+    stream.write_all(&[protocol::LIST])?;
+    let added = recv_file_list(stream, features & protocol::FEATURE_COMPRESSED_LIST != 0)?;
+    let flags = if features & protocol::FEATURE_FLAGS != 0 {
+        FlagList::recv(stream)?
+    } else {
+        vec![0; added.len()].into()
+    };
+    if flags.len() != added.len() {
+        return Err(Error::InvalidData("flag list doesn't match the file list".into()));
+    }
+    let mtimes = if features & protocol::FEATURE_MTIMES != 0 {
+        MtimeList::recv(stream)?
+    } else {
+        vec![None; added.len()].into()
+    };
+    if mtimes.len() != added.len() {
+        return Err(Error::InvalidData("mtime list doesn't match the file list".into()));
+    }
+    Ok((added, flags, mtimes))
+}
+
+/// Asks the server for the digests of the `len` files the client knows about, which it
+/// computes if it didn't advertise them.
+fn request_hashes(stream: &mut Stream, len: usize) -> common::Result<HashList> {
+    stream.write_all(&[protocol::HASHES])?;
+    let hashes = HashList::recv(stream)?;
+    if hashes.len() != len {
+        return Err(Error::InvalidData("hash list doesn't match the file list".into()));
+    }
+    Ok(hashes)
+}
+
+/// The files of `mtimes`, numbered from `first`, that `--newer-than` selects at `NORMAL`
+/// priority.
+fn newer_files(mtimes: &MtimeList, first: usize, opt: &Config) -> Vec<(usize, u8)> {
+    let Some(newer_than) = opt.newer_than else {
+        return Vec::new();
+    };
+    mtimes.iter().enumerate()
+        .filter(|(_, mtime)| mtime.map_or(opt.include_unknown_mtime, |mtime| mtime > newer_than))
+        .map(|(idx, _)| (first + idx, 1))
+        .collect()
+}
+
+/// Waits for server announcements and lets the user pick one if several servers answer.
+fn discover_server(port: u16, out: &mut dyn Write) -> io::Result<String> {
+    writeln!(out, "Looking for servers on the local network...")?;
+    let servers = discovery::discover(port, DISCOVERY_WINDOW)?;
+    match servers.as_slice() {
+        [] => {
+            eprintln!("ERROR: No server announced itself");
+            process::exit(1);
+        },
+        [server] => return Ok(server.to_string()),
+        _ => {},
+    }
+
+    writeln!(out, "Found several servers:")?;
+    for (nth, server) in servers.iter().enumerate() {
+        writeln!(out, " {}. {server}", nth + 1)?;
+    }
+    write!(out, "Pick a server [1]: ")?;
+    out.flush()?;
+
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice)?;
+    let choice = choice.trim();
+    if choice.is_empty() {
+        return Ok(servers[0].to_string());
+    }
+    match choice.parse::<usize>() {
+        Ok(nth) if (1..=servers.len()).contains(&nth) => Ok(servers[nth - 1].to_string()),
+        _ => {
+            eprintln!("ERROR: `{choice}` isn't one of the listed servers");
+            process::exit(1);
+        },
+    }
+}
+
+/// Downloads the files given as `name=PRIORITY` arguments, or else the ones the input file
+/// asks for, over QUIC, every one of them on a stream of its own, into the output
+/// directory under their names. Everything else the client does needs a TCP connection.
+#[cfg(feature = "quic")]
+fn download_quic(addr: &str, opt: &Config) -> io::Result<()> {
+    use common::download::ProgressEvent;
+
+    let Some(cert_path) = &opt.quic_cert else {
+        eprintln!("ERROR: `quic://` addresses need the certificate of the server, given with `--quic-cert`");
+        process::exit(1);
+    };
+    let cert = fs::read(cert_path).map_err(|err| io::Error::new(err.kind(), format!("can't read `{}`: {err}", cert_path.display())))?;
+    fs::create_dir_all(&opt.output_dir)?;
+
+    println!("Connecting to server at `quic://{addr}`... ");
+    let summary = common::quic::download_all(addr, &cert, &opt.output_dir, &mut |files| {
+        let inverse_map: HashMap<Box<str>, usize> = files.iter()
+            .enumerate()
+            .map(|(idx, (name, _))| (name.clone(), idx))
+            .collect();
+        let mut priorities = priority_list::new(files.len());
+        if opt.requested.is_empty() {
+            read_input(&opt.input_path, &inverse_map, &mut priorities, &mut None, &mut HashSet::new());
+        }
+        for (name, priority) in opt.requested.iter() {
+            match inverse_map.get(name.as_str()) {
+                Some(idx) => priorities[*idx] = *priority,
+                None => eprintln!("WARNING: The server doesn't serve `{name}`"),
             }
         }
+        priorities
+    }, &mut |event| match event {
+        ProgressEvent::Started { name, size } => println!("Downloading `{name}` ({})", format_size(size)),
+        ProgressEvent::Completed { name } => println!("Finished downloading `{name}`"),
+        ProgressEvent::Failed { name, err } => eprintln!("ERROR: Failed to download `{name}`: {err}"),
+        ProgressEvent::Progress { .. } => {},
+    })?;
+    if !summary.failed.is_empty() {
+        process::exit(1);
     }
+    Ok(())
+}
+
+#[cfg(not(feature = "quic"))]
+fn download_quic(_addr: &str, _opt: &Config) -> io::Result<()> {
+    eprintln!("ERROR: `quic://` addresses need a client built with the `quic` feature");
+    process::exit(1);
 }
 
 fn format_size(mut x: u64) -> String {
@@ -48,146 +1145,800 @@ fn format_size(mut x: u64) -> String {
     format!("{x}{}", suffixes[current])
 }
 
+/// Picks up where the session of `entries` left off: finished files still on disk are
+/// kept, partial ones continue from the bytes both the session and the `.part` file have,
+/// and `ranges` asks the server only for the rest of them.
+fn resume_session(transfer: &mut Transfer, entries: Vec<(usize, session::Entry)>, ranges: &mut RangeList, next_priorities: &mut [u8]) -> io::Result<()> {
+    for (idx, entry) in entries {
+        let size = transfer.downloadables[idx].1;
+        if entry.size.is_some_and(|recorded| recorded != size) {
+            eprintln!("WARNING: `{}` changed on the server since the last session, starting over", transfer.downloadables[idx].0);
+            continue;
+        }
+
+        let on_disk = |path: &Path| path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if entry.done && on_disk(&transfer.paths[idx]) == size {
+            transfer.files[idx].done = true;
+            transfer.progress[idx] = size;
+            ranges[idx].0 = size;
+            continue;
+        }
+
+        let offset = entry.offset.min(on_disk(&transfer.part_paths[idx])).min(size);
+        if offset > 0 {
+            let mut file = OpenOptions::new().write(true).open(&transfer.part_paths[idx])?;
+            file.set_len(offset)?;
+            file.seek(SeekFrom::End(0))?;
+            transfer.files[idx].file = Some(transfer.output(file));
+            transfer.progress[idx] = offset;
+            ranges[idx].0 = offset;
+        }
+        next_priorities[idx] = entry.priority;
+    }
+    Ok(())
+}
+
 fn main() -> io::Result<()> {
     let opt = Config::get();
-    let addr = {
+    if opt.dry_run && !opt.delete_extras {
+        eprintln!("WARNING: `--dry-run` only applies to `--delete`");
+    }
+    // With `--cat` stdout carries the file, everything said before it goes to stderr.
+    let mut out: Box<dyn Write> = match opt.cat {
+        Some(_) => Box::new(io::stderr()),
+        None => Box::new(io::stdout()),
+    };
+    let addr = if opt.discover {
+        discover_server(opt.discovery_port, &mut out)?
+    } else {
         let mut addr = String::new();
-        let mut stdout = std::io::stdout();
-        stdout.write_all("Enter the server address: ".as_bytes())?;
-        stdout.flush()?;
+        out.write_all("Enter the server address: ".as_bytes())?;
+        out.flush()?;
         std::io::stdin().read_line(&mut addr)?;
         addr.truncate(addr.trim_end().len());
         addr
     };
-    
-    let mut arg_iter = env::args();
-    arg_iter.next();
+    let input_path = &opt.input_path;
 
-    let input_path: PathBuf = if let Some(path) = arg_iter.next() {
-        path.into()
-    } else {
-        "input.txt".into()
-    };
+    if let Some(addr) = addr.strip_prefix("quic://") {
+        return download_quic(addr, &opt);
+    }
 
-    let output_path = Path::new(&opt.output_dir);
-    if !output_path.exists() {
-        fs::create_dir(output_path)?;
-    } else {
-        if !output_path.is_dir() {
-            eprintln!("ERROR: Can't create output directory!");
+    if let Some(name) = &opt.cat {
+        writeln!(out, "Connecting to server at `{addr}`... ")?;
+        let Handshake { mut stream, codec, files, .. } = connect_with_retries(&addr, opt.compression, opt.subpath.as_deref(), None, opt.connect_retries)?;
+        let Some(idx) = files.iter().position(|(served, _)| served.as_ref() == name) else {
+            eprintln!("ERROR: The server doesn't serve `{name}`");
             process::exit(1);
+        };
+
+        let limiter = opt.rate_limit.map(RateLimiter::new);
+        let mut stdout = io::stdout().lock();
+        let received = segmented::fetch(&mut stream, codec, files.len(), idx, (0, RANGE_TO_END), limiter.as_ref(), &mut stdout)?;
+        stdout.flush()?;
+        let size = files[idx].1;
+        if received != size {
+            eprintln!("ERROR: `{name}` ended after {received} of {size} bytes");
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let output_path = Path::new(&opt.output_dir);
+    // A symlink is followed, but only to a directory, since creating the directory would
+    // otherwise fail with a confusing error or, for a broken link, create it elsewhere.
+    if output_path.is_symlink() {
+        match fs::metadata(output_path) {
+            Ok(meta) if meta.is_dir() => {},
+            Ok(_) => {
+                eprintln!("ERROR: Output path `{}` is a symlink to a file, not a directory", output_path.display());
+                process::exit(1);
+            },
+            Err(err) => {
+                eprintln!("ERROR: Output path `{}` is a broken symlink: {err}", output_path.display());
+                process::exit(1);
+            },
         }
     }
+    // Another client may be creating the same directory, so existing is fine as long as
+    // it turns out to be a directory.
+    match fs::create_dir_all(output_path) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {
+            eprintln!("ERROR: Can't create output directory `{}`: {err}", output_path.display());
+            process::exit(1);
+        },
+        _ => {},
+    }
+    if !output_path.is_dir() {
+        eprintln!("ERROR: Output path `{}` isn't a directory", output_path.display());
+        process::exit(1);
+    }
+    fs::create_dir_all(&opt.temp_dir)?;
 
     println!("Connecting to server at `{addr}`... ");
-    let mut stream = TcpStream::connect(addr)?;
+    // `--fetch` reads its file straight through, without acknowledging rounds.
+    let window = opt.window.filter(|_| opt.fetch.is_none());
+    let Handshake { mut stream, codec, files: downloadables, hashes, flags, hints, mtimes, round_budget, features } = connect_with_retries(&addr, opt.compression, opt.subpath.as_deref(), window, opt.connect_retries)?;
 
     println!("Connection established");
-    let downloadables = FileList::recv(&mut stream)?;
+    if codec != opt.compression {
+        eprintln!("WARNING: Server doesn't support `{:?}` compression, using `{codec:?}`", opt.compression);
+    }
+    // Without `LIST` no files can come up later, and the server has already hung up.
+    if downloadables.is_empty() && features & protocol::FEATURE_APPEND == 0 {
+        eprintln!("WARNING: The server has no files to serve");
+        return Ok(());
+    }
 
-    let file_lens: Box<[usize]> = downloadables
-        .iter()
-        .map(|(name, _)| name.chars().count())
-        .collect();
+    if opt.hashes_only {
+        // No file is requested, the ranges only finish the handshake.
+        RangeList::from(vec![(0, RANGE_TO_END); downloadables.len()]).send(&mut stream)?;
+        let hashes = if hashes.iter().all(Option::is_some) {
+            hashes
+        } else if features & protocol::FEATURE_HASH_REQUEST != 0 {
+            request_hashes(&mut stream, downloadables.len())?
+        } else {
+            eprintln!("ERROR: The server can't compute hashes on request");
+            process::exit(1);
+        };
+        // Names come last since they may contain spaces. Files the server failed to hash
+        // get `-` instead of a digest.
+        for ((name, size), hash) in downloadables.iter().zip(hashes.iter()) {
+            let hash = hash.as_ref().map_or_else(|| "-".to_string(), to_hex);
+            println!("{hash} {size} {name}");
+        }
+        return Ok(());
+    }
 
-    let paths: Box<[PathBuf]> = downloadables.iter()
-        .map(|(name, _)| output_path.join(name.as_ref()))
-        .collect();
+    if let Some(name) = &opt.stat {
+        let Some(idx) = downloadables.iter().position(|(served, _)| served.as_ref() == name) else {
+            eprintln!("ERROR: The server doesn't serve `{name}`");
+            process::exit(1);
+        };
+        if features & protocol::FEATURE_STAT == 0 {
+            eprintln!("ERROR: The server can't answer `--stat`");
+            process::exit(1);
+        }
+        RangeList::from(vec![(0, RANGE_TO_END); downloadables.len()]).send(&mut stream)?;
+        protocol::send_stat(&mut stream, idx)?;
+        let stat = Stat::recv(&mut stream)?;
+        // Like `--hashes-only`, with `-` for what the server doesn't know.
+        let hash = stat.digest.as_ref().map_or_else(|| "-".to_string(), to_hex);
+        let mtime = stat.mtime.map_or_else(|| "-".to_string(), |mtime| mtime.to_string());
+        println!("{hash} {} {mtime} {name}", stat.size);
+        return Ok(());
+    }
 
-    let inverse_map: HashMap<&str, usize> = downloadables.iter()
+    let mut flat_names = HashSet::new();
+    let paths: Box<[PathBuf]> = output_paths(&downloadables, &opt.output_dir, opt.flat, opt.output_template.as_ref(), &mut flat_names)?.into();
+    let part_paths: Box<[PathBuf]> = paths.iter().map(|path| part_path(path, &opt.output_dir, &opt.temp_dir)).collect();
+
+    let mut inverse_map: HashMap<Box<str>, usize> = downloadables.iter()
         .enumerate()
-        .map(|(idx, (name, _))| (name.as_ref(), idx))
+        .map(|(idx, (name, _))| (name.clone(), idx))
         .collect();
 
     println!();
     println!("Files available for download:");
-    let max_len = file_lens.iter().cloned().max().unwrap_or(0);
-    for (name, size) in downloadables.iter() {
-        println!(" - {0:1$} - {2}", name, max_len, format_size(*size));
+    let max_len = downloadables.iter().map(|(name, _)| name.chars().count()).max().unwrap_or(0);
+    let sizes: Vec<_> = downloadables.iter().map(|(_, size)| format_size(*size)).collect();
+    let max_size_len = sizes.iter().map(|size| size.len()).max().unwrap_or(0);
+    for (((name, _), size), hash) in downloadables.iter().zip(sizes.iter()).zip(hashes.iter()) {
+        match hash {
+            Some(hash) if opt.show_hashes => println!(" - {0:1$} - {2:3$} - {4}", name, max_len, size, max_size_len, to_hex(hash)),
+            _ => println!(" - {0:1$} - {2}", name, max_len, size),
+        }
+    }
+
+    let mut requested: Vec<(usize, u8)> = opt.requested.iter()
+        .filter_map(|(name, priority)| match inverse_map.get(name.as_str()) {
+            Some(idx) => Some((*idx, *priority)),
+            None => {
+                eprintln!("WARNING: The server doesn't serve `{name}`");
+                None
+            },
+        })
+        .collect();
+    if opt.newer_than.is_some() && features & protocol::FEATURE_MTIMES == 0 {
+        eprintln!("WARNING: The server doesn't advertise modification times, `--newer-than` treats them all as unknown");
+    }
+    requested.extend(newer_files(&mtimes, 0, &opt));
+    // Priorities given as arguments replace the input file and its watch.
+    let use_input = opt.requested.is_empty() && opt.newer_than.is_none() && !opt.interactive && !opt.stdin_input && !opt.manifest;
+
+    let (sums, unknown): (HashList, _) = match &opt.sums_path {
+        Some(sums_path) => sums::load(sums_path, &inverse_map).unwrap_or_else(|err| {
+            eprintln!("ERROR: Failed to read sums file `{}`: {err}", sums_path.display());
+            process::exit(1);
+        }),
+        None => (vec![None; downloadables.len()].into(), Vec::new()),
+    };
+    let hashes = if opt.manifest {
+        // Fetching exactly what the manifest lists, so a server serving less is an error.
+        if !unknown.is_empty() {
+            eprintln!("ERROR: The server doesn't serve `{}` from the manifest", unknown.join("`, `"));
+            process::exit(1);
+        }
+        requested.extend(sums.iter().enumerate().filter(|(_, sum)| sum.is_some()).map(|(idx, _)| (idx, 1)));
+        // The server isn't trusted, the files only have to match the manifest.
+        vec![None; downloadables.len()].into()
+    } else {
+        hashes
+    };
+
+    let too_large = |size: u64| opt.max_file_size.is_some_and(|max| size > max);
+    for (name, _) in downloadables.iter().filter(|(_, size)| too_large(*size)) {
+        eprintln!("WARNING: `{name}` is larger than the limit of {}, it won't be downloaded", format_size(opt.max_file_size.unwrap_or(0)));
+    }
+
+    let pin_arg = opt.pin.as_ref().map(|name| match inverse_map.get(name.as_str()) {
+        Some(idx) => *idx,
+        None => {
+            eprintln!("ERROR: The server doesn't serve `{name}`, it can't be pinned");
+            process::exit(1);
+        },
+    });
+
+    let limiter = opt.rate_limit.map(RateLimiter::new);
+    if let Some(name) = &opt.fetch {
+        let Some(idx) = inverse_map.get(name.as_str()).copied() else {
+            eprintln!("ERROR: The server doesn't serve `{name}`");
+            process::exit(1);
+        };
+        let size = downloadables[idx].1;
+        if too_large(size) {
+            eprintln!("ERROR: Refusing to fetch `{name}`");
+            process::exit(1);
+        }
+        check_total(size, opt.max_total)?;
+        if opt.no_clobber && paths[idx].symlink_metadata().is_ok() {
+            eprintln!("WARNING: `{}` already exists, not fetching `{name}` over it", paths[idx].display());
+            return Ok(());
+        }
+        segmented::download(stream, &addr, opt.compression, opt.subpath.as_deref(), codec, &downloadables, hashes[idx], sums[idx], flags[idx], idx, opt.segments, limiter.as_ref(), &part_paths[idx], &paths[idx])?;
+        println!("Finished downloading `{name}`");
+        return Ok(());
+    }
+
+    let mut transfer = Transfer::new(downloadables, paths, part_paths, opt.strict);
+    transfer.codec = codec;
+    transfer.hashes = hashes;
+    transfer.flags = flags;
+    transfer.sums = sums;
+    transfer.limiter = limiter;
+    transfer.write_buffer = opt.write_buffer;
+    transfer.offload_writes = opt.offload_writes;
+    // Without the writer threads every file is written as it's received, holding no more
+    // than its write buffer.
+    match opt.max_buffered {
+        Some(_) if !opt.offload_writes => eprintln!("WARNING: `--max-buffered` only applies to `--offload-writes`"),
+        Some(limit) => transfer.backlog = Some(Arc::new(Backlog::new(limit))),
+        None => {},
+    }
+    transfer.fsync_interval = opt.fsync_interval;
+    transfer.round_budget = round_budget;
+    if features & protocol::FEATURE_RESTART != 0 {
+        transfer.retries = opt.file_retries;
+    } else if opt.file_retries > 0 {
+        eprintln!("WARNING: The server can't send files again, `--file-retries` is ignored");
+    }
+    transfer.no_clobber = opt.no_clobber;
+    let mut next_priorities = priority_list::new(transfer.downloadables.len());
+    let mut ranges: RangeList = vec![(0, RANGE_TO_END); transfer.downloadables.len()].into();
+
+    let session_path = &opt.session_path;
+    match session::load(session_path, &inverse_map) {
+        Ok(entries) => resume_session(&mut transfer, entries, &mut ranges, &mut next_priorities)?,
+        Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {},
+        Err(err) => {
+            eprintln!("WARNING: Ignoring session file `{}`: {err}", session_path.display());
+        }
+    }
+
+    // After the session, so that files it completed aren't warned about.
+    if opt.no_clobber {
+        keep_existing(&mut transfer, 0);
     }
 
-    let mut files = initialize_handlers(downloadables.len());
-    let mut priorities = priority_list::new(downloadables.len());
-    let mut next_priorities = priority_list::new(downloadables.len());
+    // Read before every round, the priorities of the user replace the hints.
+    if opt.use_server_hints {
+        for (priority, hint) in next_priorities.iter_mut().zip(hints.iter()) {
+            if *priority == 0 {
+                *priority = *hint;
+            }
+        }
+    }
 
-    let mut progress: Box<[usize]> = vec![0; downloadables.len()].into();
+    ranges.send(&mut stream)?;
+
+    let save_session = |transfer: &Transfer| {
+        if let Err(err) = session::save(session_path, &transfer.downloadables, &transfer.files, &transfer.priorities, &transfer.progress) {
+            eprintln!("WARNING: Failed to save session file `{}`: {err}", session_path.display());
+        }
+    };
+
+    let mut progress_log = opt.progress_log.as_ref().and_then(|path| {
+        ProgressLog::open(path.clone(), opt.progress_log_max, &transfer.downloadables).map_err(|err| {
+            eprintln!("WARNING: Can't open progress log `{}`: {err}", path.display());
+        }).ok()
+    });
+    let mut ui = TerminalUi::new(
+        &transfer.downloadables, opt.bar_width, opt.bar_style, opt.redraw_interval, opt.compact,
+        opt.redraw, opt.throughput_interval.map(Throughput::new),
+    );
+    ui.set_color(opt.no_color);
+
+    let mut watcher = use_input.then(|| InputWatcher::new(input_path, opt.watch_mode, opt.poll_interval));
+    let mut commands = (opt.interactive || opt.stdin_input).then(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        receiver
+    });
+    let mut last_ping = Instant::now();
+    let mut last_list = None;
+    let mut unreachable = false;
+    let mut warned_lines = HashSet::new();
+    let mut refresh = false;
+    let mut input_closed = false;
+    let mut input_pin = None;
+    // Rounds received over the connection, which the server numbers the same way.
+    let mut rounds = 0;
 
     println!();
+    if opt.interactive {
+        println!("Type `name PRIORITY` to download a file, `refresh` to look for new files, or `quit` to exit");
+    }
 
     loop {
-        let last_changed = input_path.metadata()?.modified()?;
-        read_input(&input_path, &inverse_map, &mut next_priorities);
-        let mut to_download = priority_list::merge(&mut priorities, &next_priorities);
-        stream.write_all(&priorities)?;
+        let last_stamp = if use_input {
+            let stamp = InputStamp::of(input_path)?;
+            refresh |= read_input(input_path, &inverse_map, &mut next_priorities, &mut input_pin, &mut warned_lines);
+            Some(stamp)
+        } else {
+            for (idx, priority) in requested.iter() {
+                next_priorities[*idx] = *priority;
+            }
+            None
+        };
+        for ((priority, handler), (_, size)) in next_priorities.iter_mut().zip(transfer.files.iter()).zip(transfer.downloadables.iter()) {
+            if handler.done || too_large(*size) {
+                *priority = 0;
+            }
+        }
+        // The pinned file is asked for on its own, the others stay queued until it's done.
+        // Once the input file stops pinning it, they're asked for again like before.
+        let pin = input_pin.or(pin_arg)
+            .filter(|pin| !transfer.files[*pin].done && !too_large(transfer.downloadables[*pin].1));
+        let wanted = pinned(&mut next_priorities, pin);
+        let newly_requested = if let Some(max_active) = opt.max_active {
+            let active = transfer.priorities.iter().zip(transfer.files.iter())
+                .filter(|(priority, handler)| **priority != 0 && !handler.done)
+                .count();
+            let limited = limit_active(&wanted, &transfer.priorities, active, max_active);
+            priority_list::merge_changed(&mut transfer.priorities, &limited)
+        } else {
+            priority_list::merge_changed(&mut transfer.priorities, &wanted)
+        };
+        ui.on_requested(&newly_requested);
+        let mut to_download = newly_requested.len();
+        let queued = (0..transfer.downloadables.len())
+            .filter(|idx| next_priorities[*idx] != 0 && transfer.priorities[*idx] == 0)
+            .collect();
+        ui.set_queued(queued);
+        let committed = transfer.downloadables.iter().zip(transfer.priorities.iter().zip(transfer.files.iter()))
+            .filter(|(_, (priority, handler))| **priority != 0 || handler.done)
+            .map(|((_, size), _)| size)
+            .sum();
+        check_total(committed, opt.max_total)?;
+        if to_download > 0 && opt.check_space != SpaceCheck::Off {
+            // Resumed files only need what's left of them.
+            let needed = (0..transfer.downloadables.len())
+                .filter(|idx| transfer.priorities[*idx] != 0 && !transfer.files[*idx].done)
+                .map(|idx| transfer.downloadables[idx].1.saturating_sub(transfer.progress[idx]))
+                .sum();
+            check_space(needed, free_space(output_path), opt.check_space)?;
+        }
+        if to_download > 0 {
+            priority_list::send(&mut stream, &transfer.priorities)?;
+        }
 
+        let mut last_saved = Instant::now();
         while to_download > 0 {
-            for idx in 0..downloadables.len() {
-                let handler = &mut files[idx];
-                let priority = priorities[idx];
-                if priority == 0 || handler.done {
-                    continue;
+            let round = transfer.receive_round(&mut stream, &mut Tee(&mut ui, progress_log.as_mut())).and_then(|completed| {
+                let received = transfer.progress.iter().sum();
+                check_total(received, opt.max_total)?;
+                Ok(completed)
+            });
+            match round {
+                Ok(completed) => {
+                    to_download -= completed;
+                    rounds += 1;
+                    if features & protocol::FEATURE_WINDOW != 0 {
+                        protocol::send_ack(&mut stream, rounds)?;
+                    }
+                },
+                Err(err) => {
+                    Tee(&mut ui, progress_log.as_mut()).on_error(&err);
+                    if opt.strict {
+                        transfer.discard_partial();
+                    }
+                    save_session(&transfer);
+                    return Err(err.into());
                 }
+            }
+
+            if last_saved.elapsed() >= SESSION_SAVE_INTERVAL {
+                save_session(&transfer);
+                last_saved = Instant::now();
+            }
+        }
+        save_session(&transfer);
 
-                let opened = handler.file.get_or_insert_with(|| {
-                    File::create(&paths[idx]).unwrap()
-                });
+        let restarts = transfer.take_restarts();
+        for idx in restarts.iter() {
+            protocol::send_restart(&mut stream, *idx)?;
+        }
 
-                for _ in 0..priority {
-                    let chunk = Chunk::recv(&mut stream)?;
-                    progress[idx] += chunk.len;
+        let held_back = next_priorities.iter().zip(transfer.priorities.iter())
+            .any(|(requested, current)| *requested != 0 && *current == 0);
+        let edited = match &last_stamp {
+            Some(last_stamp) => InputStamp::of(input_path)? != *last_stamp,
+            None => false,
+        };
+        let typed = match commands.as_ref().map(|commands| apply_commands(commands, &inverse_map, &mut requested, &mut refresh)) {
+            Some(Some(typed)) => typed,
+            Some(None) if opt.stdin_input => {
+                commands = None;
+                input_closed = true;
+                false
+            },
+            Some(None) => return Ok(()),
+            None => false,
+        };
+        if held_back || edited || typed || !restarts.is_empty() {
+            continue;
+        }
 
-                    if chunk.write(opened)? {
-                        handler.done = true;
-                        drop(handler.file.take());
-                        println!("Finished downloading `{}`", downloadables[idx].0);
-                        to_download -= 1;
-                        break;
+        if opt.delete_extras {
+            delete_extras(&transfer, &opt);
+        }
+
+        if opt.exit_when_done || input_closed {
+            if transfer.any_failed() {
+                eprintln!("ERROR: Some files failed to download");
+                process::exit(1);
+            }
+            return Ok(());
+        }
+
+        let frames = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+        let mut last_status = String::new();
+        let mut wake = false;
+        for frame in frames.iter().cycle() {
+            if let Some(interval) = opt.ping_interval {
+                if last_ping.elapsed() >= interval {
+                    unreachable = match ping(&mut stream, interval, unreachable) {
+                        Ok(()) => false,
+                        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => true,
+                        Err(err) => return Err(err),
                     };
+                    last_ping = Instant::now();
+                }
+            }
+            // A late reply to a ping would be read as the start of a round, so nothing is
+            // sent until it arrived.
+            if wake && !unreachable {
+                break;
+            }
+
+            if refresh && features & protocol::FEATURE_APPEND == 0 {
+                if warned_lines.insert(REFRESH.to_string()) {
+                    eprintln!("WARNING: The server doesn't serve files found after the connection, there's nothing to refresh");
+                }
+                refresh = false;
+            }
+            let list_due = refresh || last_list.is_none_or(|last_list: Instant| last_list.elapsed() >= LIST_INTERVAL);
+            if features & protocol::FEATURE_APPEND != 0 && !unreachable && list_due {
+                refresh = false;
+                last_list = Some(Instant::now());
+                let (added, added_flags, added_mtimes) = list_added(&mut stream, features)?;
+                if !added.is_empty() {
+                    let known = transfer.downloadables.len();
+                    requested.extend(newer_files(&added_mtimes, known, &opt));
+                    for (offset, (name, size)) in added.iter().enumerate() {
+                        println!("New file available for download: {name} - {}", format_size(*size));
+                        inverse_map.insert(name.clone(), known + offset);
+                    }
+                    let paths = output_paths(&added, &opt.output_dir, opt.flat, opt.output_template.as_ref(), &mut flat_names)?;
+                    let part_paths = paths.iter().map(|path| part_path(path, &opt.output_dir, &opt.temp_dir)).collect();
+                    transfer.append(&added, paths, part_paths, &added_flags);
+                    if opt.no_clobber {
+                        keep_existing(&mut transfer, known);
+                    }
+                    grow(&mut next_priorities, transfer.downloadables.len(), || 0);
+                    ui.append(&added);
+                    if let Some(progress_log) = &mut progress_log {
+                        progress_log.append(&added);
+                    }
+                    // The input file may already list the new files.
+                    break;
+                }
+            }
+
+            // The spinner would draw over what's being typed.
+            if let Some(applied) = commands.as_ref().map(|commands| apply_commands(commands, &inverse_map, &mut requested, &mut refresh)) {
+                match applied {
+                    Some(true) => {
+                        wake = true;
+                        continue;
+                    },
+                    Some(false) => {
+                        thread::sleep(Duration::from_millis(200));
+                        continue;
+                    },
+                    // Whatever came before the end is requested by now, so it's finished.
+                    None if opt.stdin_input => {
+                        commands = None;
+                        input_closed = true;
+                        wake = true;
+                        continue;
+                    },
+                    None => return Ok(()),
                 }
             }
 
-            let downloading_files: Box<[usize]> = (0..downloadables.len()).filter(|idx| {
-                !files[*idx].done && progress[*idx] != 0
-            }).collect();
+            let status = if unreachable {
+                "Server unreachable, waiting for it to answer".to_string()
+            } else if let Some(None) = last_stamp {
+                format!("Waiting for `{}` to be created", input_path.display())
+            } else if use_input {
+                format!("Edit `{}` to start downloading", input_path.display())
+            } else {
+                "All requested files are finished".to_string()
+            };
+            // Without moving the cursor the spinner would print a line every frame.
+            if opt.redraw == RedrawMode::InPlace {
+                println!();
+                println!(" {frame} {status}");
+                print!("\x1b[A\x1b[K\x1b[A\x1b[K");
+            } else if status != last_status {
+                println!("{status}");
+                last_status = status;
+            }
+            let changed = match &mut watcher {
+                Some(watcher) => watcher.wait(Duration::from_millis(200)),
+                None => {
+                    thread::sleep(Duration::from_millis(200));
+                    false
+                },
+            };
+            wake |= changed;
+        }
+    }
+}
 
-            let max_downloading_len = downloading_files.iter().map(|idx| file_lens[*idx]).max().unwrap_or(0);
+#[cfg(test)]
+mod tests {
+    use common::Chunk;
+    use super::*;
 
-            for idx in downloading_files.iter() {
-                let full_block = '█';
-                let blocks = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+    /// Records the files completed, and ignores everything else.
+    #[derive(Default)]
+    struct Completions(Vec<usize>);
 
-                const PROGRESS_LEN: usize = 64;
-                let resolution = PROGRESS_LEN * blocks.len();
-                let (name, size) = &downloadables[*idx];
-                let pos = progress[*idx] * resolution / (*size as usize);
-                let full = pos / blocks.len();
+    impl TransferObserver for Completions {
+        fn on_start(&mut self, _idx: usize, _name: &str, _size: u64) {}
+        fn on_progress(&mut self, _idx: usize, _received: u64) {}
+        fn on_complete(&mut self, idx: usize) {
+            self.0.push(idx);
+        }
+        fn on_failed(&mut self, idx: usize, err: &io::Error) {
+            panic!("file {idx} failed: {err}");
+        }
+        fn on_retry(&mut self, _idx: usize, _err: &io::Error, _attempt: u32) {}
+        fn on_error(&mut self, _err: &Error) {}
+    }
 
-                let mut progress_bar = [' '; PROGRESS_LEN];
-                for c in progress_bar[..full].iter_mut() {
-                    *c = full_block;
-                }
-                if full < progress_bar.len() {
-                    progress_bar[full] = blocks[pos % blocks.len()];
-                }
-                let progress_str: String = progress_bar.iter().collect();
-                println!("Downloading file {0:1$} [{2}] {3}%", name, max_downloading_len, progress_str, progress[*idx] * 100 / (*size as usize));
-            }
+    /// What the server sends of `data` in `chunks` chunks from `start` on.
+    pub(crate) fn chunks_of(data: &[u8], start: usize, chunks: usize) -> Vec<u8> {
+        let mut rest = &data[start..];
+        let mut out = Vec::new();
+        for _ in 0..chunks {
+            let chunk = Chunk::read(&mut rest).unwrap();
+            chunk.send_with(&mut out, Codec::None, 0).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn session_resumes_after_a_crash_without_redoing_finished_files() {
+        let dir = env::temp_dir().join(format!("client-test-{}-session", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (small, large) = (vec![1; 3000], vec![2; 5000]);
+        let downloadables: FileList = [("small.bin".into(), 3000), ("large.bin".into(), 5000)].into_iter().collect();
+        let inverse_map = HashMap::from([("small.bin".into(), 0), ("large.bin".into(), 1)]);
+        let paths: Box<[PathBuf]> = ["small.bin", "large.bin"].iter().map(|name| dir.join(name)).collect();
+        let part_paths: Box<[PathBuf]> = ["small.bin.part", "large.bin.part"].iter().map(|name| dir.join(name)).collect();
+        let session_path = dir.join(".session");
+
+        // The first round finishes `small.bin` and 2 chunks of `large.bin`, then the client dies.
+        let mut transfer = Transfer::new(downloadables.clone(), paths.clone(), part_paths.clone(), false);
+        transfer.priorities = [4, 2].into();
+        let mut round = chunks_of(&small, 0, 3);
+        round.extend(chunks_of(&large, 0, 2));
+        let mut completions = Completions::default();
+        let mut stream = &round[..];
+        transfer.receive_round(&mut stream, &mut completions).unwrap();
+        assert!(stream.is_empty());
+        assert_eq!(completions.0, [0]);
+        session::save(&session_path, &transfer.downloadables, &transfer.files, &transfer.priorities, &transfer.progress).unwrap();
+        drop(transfer);
+
+        let mut transfer = Transfer::new(downloadables, paths, part_paths, false);
+        let mut ranges: RangeList = vec![(0, RANGE_TO_END); 2].into();
+        let mut next_priorities = priority_list::new(2);
+        let entries = session::load(&session_path, &inverse_map).unwrap();
+        resume_session(&mut transfer, entries, &mut ranges, &mut next_priorities).unwrap();
+        assert!(transfer.files[0].done);
+        assert_eq!(&ranges[..], [(3000, RANGE_TO_END), (2048, RANGE_TO_END)]);
+        assert_eq!(&next_priorities[..], [0, 2]);
+
+        // The server only sends the rest of `large.bin`, nothing of `small.bin`.
+        transfer.priorities = next_priorities;
+        let rest = chunks_of(&large, 2048, 3);
+        let mut stream = &rest[..];
+        let mut completions = Completions::default();
+        while completions.0.is_empty() {
+            transfer.receive_round(&mut stream, &mut completions).unwrap();
+        }
+        assert!(stream.is_empty());
+        assert_eq!(completions.0, [1]);
+        assert_eq!(fs::read(dir.join("small.bin")).unwrap(), small);
+        assert_eq!(fs::read(dir.join("large.bin")).unwrap(), large);
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-            for _ in 0..downloading_files.len() {
-                print!("\x1b[A\x1b[K");
+    #[test]
+    fn pinned_file_finishes_first() {
+        let dir = env::temp_dir().join(format!("client-test-{}-pin", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input_path = dir.join("input.txt");
+        fs::write(&input_path, "first.bin HIGH\nPIN pinned.bin\n").unwrap();
+        let (first, pinned_data) = (vec![1; 3000], vec![2; 1500]);
+        let downloadables: FileList = [("first.bin".into(), 3000), ("pinned.bin".into(), 1500)].into_iter().collect();
+        let inverse_map = HashMap::from([("first.bin".into(), 0), ("pinned.bin".into(), 1)]);
+        let paths: Box<[PathBuf]> = ["first.bin", "pinned.bin"].iter().map(|name| dir.join(name)).collect();
+        let part_paths = paths.iter().map(|path| path.with_extension("part")).collect();
+        let mut transfer = Transfer::new(downloadables, paths, part_paths, false);
+
+        let mut next_priorities = priority_list::new(2);
+        let mut pin = None;
+        read_input(&input_path, &inverse_map, &mut next_priorities, &mut pin, &mut HashSet::new());
+        assert_eq!(pin, Some(1));
+        let wanted = pinned(&mut next_priorities, pin);
+        assert_eq!(priority_list::merge_changed(&mut transfer.priorities, &wanted), [1]);
+
+        // Only the pinned file is sent until it's done, however high the others are.
+        let rest = chunks_of(&pinned_data, 0, 2);
+        let mut stream = &rest[..];
+        let mut completions = Completions::default();
+        while completions.0.is_empty() {
+            transfer.receive_round(&mut stream, &mut completions).unwrap();
+        }
+        assert!(stream.is_empty());
+
+        let wanted = pinned(&mut next_priorities, pin.filter(|pin| !transfer.files[*pin].done));
+        assert_eq!(priority_list::merge_changed(&mut transfer.priorities, &wanted), [0]);
+        let rest = chunks_of(&first, 0, 3);
+        let mut stream = &rest[..];
+        transfer.receive_round(&mut stream, &mut completions).unwrap();
+        assert_eq!(completions.0, [1, 0]);
+        assert_eq!(fs::read(dir.join("first.bin")).unwrap(), first);
+        assert_eq!(fs::read(dir.join("pinned.bin")).unwrap(), pinned_data);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refresh_line_ignores_case() {
+        let path = env::temp_dir().join(format!("client-test-{}-refresh", process::id()));
+        let inverse_map = HashMap::from([("a.bin".into(), 0)]);
+        let mut out = [0];
+        let mut pin = None;
+        for refresh in ["REFRESH", "refresh", "  Refresh\r"] {
+            fs::write(&path, format!("a.bin HIGH\n{refresh}\n")).unwrap();
+            assert!(read_input(&path, &inverse_map, &mut out, &mut pin, &mut HashSet::new()), "{refresh:?}");
+        }
+        fs::write(&path, "a.bin HIGH\n").unwrap();
+        assert!(!read_input(&path, &inverse_map, &mut out, &mut pin, &mut HashSet::new()));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn names_leaving_the_output_directory_are_not_written() {
+        let dir = env::temp_dir().join(format!("client-test-{}-unsafe", process::id()));
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        for name in ["../evil.bin", "a/../../evil.bin", "/evil.bin", ".."] {
+            let files: FileList = [("a.bin".into(), 1), (name.into(), 1)].into_iter().collect();
+            for flat in [false, true] {
+                let paths = output_paths(&files, &out, flat, None, &mut HashSet::new());
+                assert!(matches!(paths, Err(Error::InvalidData(_))), "{name:?}: {paths:?}");
             }
         }
 
-        if input_path.metadata()?.modified()? > last_changed {
-            continue;
+        // `--delete` only looks below the output directory, even for a path that leads out of it.
+        fs::write(dir.join("evil.bin"), "").unwrap();
+        fs::write(out.join("extra.bin"), "").unwrap();
+        let keep = HashSet::from([out.join("a.bin"), out.join("..").join("kept.bin")]);
+        assert_eq!(mirror::extras(&out, &keep).unwrap(), [out.join("extra.bin")]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_of_the_same_name_resume_from_their_own_part_files() {
+        let dir = env::temp_dir().join(format!("client-test-{}-parts", process::id()));
+        let (out, temp) = (dir.join("out"), dir.join("temp"));
+        fs::create_dir_all(&temp).unwrap();
+        let downloadables: FileList = ["a/readme.txt", "b/readme.txt", "a%2Freadme.txt"].into_iter()
+            .map(|name| (name.into(), 3000))
+            .collect();
+        let paths: Box<[PathBuf]> = output_paths(&downloadables, &out, false, None, &mut HashSet::new()).unwrap().into();
+        let part_paths: Box<[PathBuf]> = paths.iter().map(|path| part_path(path, &out, &temp)).collect();
+        assert_eq!(part_paths[0], temp.join("a%2Freadme.txt.part"));
+        assert_eq!(part_paths[2], temp.join("a%252Freadme.txt.part"));
+
+        for (idx, part_path) in part_paths.iter().enumerate() {
+            fs::write(part_path, vec![idx as u8; 1000 * (idx + 1)]).unwrap();
         }
+        let entries = (0..3).map(|idx| (idx, session::Entry { done: false, priority: 1, offset: 2500, size: Some(3000) })).collect();
+        let mut transfer = Transfer::new(downloadables, paths, part_paths, false);
+        let mut ranges: RangeList = vec![(0, RANGE_TO_END); 3].into();
+        resume_session(&mut transfer, entries, &mut ranges, &mut priority_list::new(3)).unwrap();
+        assert_eq!(transfer.progress[..], [1000, 2000, 2500]);
+        assert_eq!(ranges.iter().map(|range| range.0).collect::<Vec<_>>(), [1000, 2000, 2500]);
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-        let frames = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
-        for frame in frames {
-            println!();
-            println!(" {frame} Edit `{}` to start downloading", input_path.display());
-            print!("\x1b[A\x1b[K\x1b[A\x1b[K");
-            thread::sleep(Duration::from_millis(200));
+    #[test]
+    fn only_connection_failures_are_retried() {
+        for kind in [io::ErrorKind::ConnectionRefused, io::ErrorKind::ConnectionReset, io::ErrorKind::TimedOut, io::ErrorKind::UnexpectedEof] {
+            assert!(is_transient(&Error::Io(kind.into())), "{kind:?}");
+        }
+        let fatal = [
+            Error::Io(io::ErrorKind::PermissionDenied.into()),
+            Error::Io(io::ErrorKind::InvalidData.into()),
+            Error::Protocol("server picked an unknown codec"),
+            Error::InvalidData("hash list doesn't match the file list".into()),
+            Error::MissingFeatures(protocol::FEATURE_HASHES),
+        ];
+        for err in fatal {
+            assert!(!is_transient(&err), "{err}");
+        }
+    }
+
+    #[test]
+    fn flattened_names_that_collide_get_a_suffix() {
+        let out = Path::new("out");
+        let names = ["a/readme.txt", "b/readme.txt", "c/readme.txt", "readme~1.txt", "x/Makefile", "y/Makefile", ".bashrc", "d/.bashrc"];
+        let files: FileList = names.iter().map(|name| (Box::from(*name), 1)).collect();
+        let mut used = HashSet::new();
+        let paths = output_paths(&files, out, true, None, &mut used).unwrap();
+        let flat: Vec<_> = paths.iter().map(|path| path.strip_prefix(out).unwrap().to_str().unwrap()).collect();
+        assert_eq!(flat, ["readme.txt", "readme~1.txt", "readme~2.txt", "readme~1~1.txt", "Makefile", "Makefile~1", ".bashrc", ".bashrc~1"]);
+
+        // Files served later don't take the names of earlier ones either.
+        let added: FileList = [("e/readme.txt".into(), 1)].into_iter().collect();
+        assert_eq!(output_paths(&added, out, true, None, &mut used).unwrap(), [out.join("readme~3.txt")]);
+
+        for name in ["..", "a/.."] {
+            let files: FileList = [(name.into(), 1)].into_iter().collect();
+            assert!(output_paths(&files, out, true, None, &mut used).is_err(), "{name}");
         }
     }
 }
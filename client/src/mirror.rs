@@ -0,0 +1,43 @@
+use std::{collections::HashSet, fs, io, path::{Component, Path, PathBuf}};
+
+/// Finds the files under `output_dir` that aren't in `keep`, for `--delete`. Only the
+/// output directory itself and the directories the kept files are in are looked at, so
+/// that unrelated directories are never touched, and directories are never removed.
+pub fn extras(output_dir: &Path, keep: &HashSet<PathBuf>) -> io::Result<Vec<PathBuf>> {
+    let mut dirs: Vec<&Path> = keep.iter()
+        .filter_map(|path| path.parent())
+        .filter(|dir| is_under(dir, output_dir))
+        .chain([output_dir])
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+
+    let mut extras = Vec::new();
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            // Nothing was downloaded into it yet.
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+            let path = dir.join(entry.file_name());
+            if !keep.contains(&path) && !path.canonicalize().is_ok_and(|path| keep.contains(&path)) {
+                extras.push(path);
+            }
+        }
+    }
+    extras.sort();
+    Ok(extras)
+}
+
+/// Whether `dir` is `output_dir` or below it. Paths are compared as written, so a `..`
+/// is never taken to lead back into `output_dir`.
+fn is_under(dir: &Path, output_dir: &Path) -> bool {
+    dir.strip_prefix(output_dir)
+        .is_ok_and(|rest| rest.components().all(|component| matches!(component, Component::Normal(_))))
+}
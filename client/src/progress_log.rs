@@ -0,0 +1,134 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Write}, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+use common::{Error, FileList, TransferObserver};
+
+/// Appends a line for every file started, finished or retried to a file that outlives the
+/// terminal output. Once it grows past `max_size` it's moved to `<path>.1`, replacing the
+/// previous one, so at most twice that much is kept.
+pub struct ProgressLog {
+    path: PathBuf,
+    file: Option<File>,
+    written: u64,
+    max_size: u64,
+    names: Box<[Box<str>]>,
+    sizes: Box<[u64]>,
+}
+
+impl ProgressLog {
+    pub fn open(path: PathBuf, max_size: u64, downloadables: &FileList) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file: Some(file),
+            written,
+            max_size,
+            names: downloadables.iter().map(|(name, _)| name.clone()).collect(),
+            sizes: downloadables.iter().map(|(_, size)| *size).collect(),
+        })
+    }
+
+    /// Adds files the server started serving after the handshake.
+    pub fn append(&mut self, files: &FileList) {
+        self.names = self.names.iter().cloned().chain(files.iter().map(|(name, _)| name.clone())).collect();
+        self.sizes = self.sizes.iter().copied().chain(files.iter().map(|(_, size)| *size)).collect();
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        drop(self.file.take());
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = Some(File::create(&self.path)?);
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Writes one line prefixed with the Unix time. The log is given up on the first
+    /// failure, which doesn't concern the transfer itself.
+    fn log(&mut self, event: &str) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = format!("{}.{:03} {event}\n", now.as_secs(), now.subsec_millis());
+
+        let mut result = file.write_all(line.as_bytes());
+        self.written += line.len() as u64;
+        if result.is_ok() && self.written > self.max_size {
+            result = self.rotate();
+        }
+        if let Err(err) = result {
+            eprintln!("WARNING: Stopped writing progress log `{}`: {err}", self.path.display());
+            self.file = None;
+        }
+    }
+}
+
+impl TransferObserver for ProgressLog {
+    fn on_start(&mut self, _idx: usize, name: &str, size: u64) {
+        self.log(&format!("start {name} {size}"));
+    }
+
+    // Progress is left to the terminal, it would bury the events worth keeping.
+    fn on_progress(&mut self, _idx: usize, _received: u64) {}
+
+    fn on_complete(&mut self, idx: usize) {
+        self.log(&format!("complete {} {}", self.names[idx], self.sizes[idx]));
+    }
+
+    fn on_failed(&mut self, idx: usize, err: &io::Error) {
+        self.log(&format!("failed {}: {err}", self.names[idx]));
+    }
+
+    fn on_retry(&mut self, idx: usize, err: &io::Error, attempt: u32) {
+        self.log(&format!("retry {} (attempt {attempt}): {err}", self.names[idx]));
+    }
+
+    fn on_error(&mut self, err: &Error) {
+        self.log(&format!("error: {err}"));
+    }
+}
+
+/// Passes every event to `observer` and then the progress log, if there is one.
+pub struct Tee<'a>(pub &'a mut dyn TransferObserver, pub Option<&'a mut ProgressLog>);
+
+impl TransferObserver for Tee<'_> {
+    fn on_start(&mut self, idx: usize, name: &str, size: u64) {
+        self.0.on_start(idx, name, size);
+        if let Some(log) = &mut self.1 {
+            log.on_start(idx, name, size);
+        }
+    }
+
+    fn on_progress(&mut self, idx: usize, received: u64) {
+        self.0.on_progress(idx, received);
+    }
+
+    fn on_complete(&mut self, idx: usize) {
+        self.0.on_complete(idx);
+        if let Some(log) = &mut self.1 {
+            log.on_complete(idx);
+        }
+    }
+
+    fn on_failed(&mut self, idx: usize, err: &io::Error) {
+        self.0.on_failed(idx, err);
+        if let Some(log) = &mut self.1 {
+            log.on_failed(idx, err);
+        }
+    }
+
+    fn on_retry(&mut self, idx: usize, err: &io::Error, attempt: u32) {
+        self.0.on_retry(idx, err, attempt);
+        if let Some(log) = &mut self.1 {
+            log.on_retry(idx, err, attempt);
+        }
+    }
+
+    fn on_error(&mut self, err: &Error) {
+        self.0.on_error(err);
+        if let Some(log) = &mut self.1 {
+            log.on_error(err);
+        }
+    }
+}
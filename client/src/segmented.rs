@@ -0,0 +1,92 @@
+use std::{fs::{File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::Path, thread};
+use common::{priority_list, Chunk, Codec, Digest, Error, FileList, Packet, RangeList, Stream, RANGE_TO_END};
+use crate::{connect, throttle::RateLimiter, transfer::{apply_flags, move_file, verify}, Handshake};
+
+/// Splits `size` bytes into at most `segments` contiguous, non-empty ranges. The last
+/// range absorbs the remainder of the division.
+pub fn split_ranges(size: u64, segments: u64) -> Vec<(u64, u64)> {
+    let segments = segments.clamp(1, size.max(1));
+    let step = size / segments;
+    (0..segments)
+        .map(|nth| {
+            let start = nth * step;
+            let end = if nth + 1 == segments { size } else { start + step };
+            (start, end)
+        })
+        .collect()
+}
+
+/// Requests `range` of file `idx` alone, right after the handshake, and writes it to
+/// `output` as it arrives. Returns how many bytes were received.
+pub fn fetch<S: Read + Write>(stream: &mut S, codec: Codec, file_count: usize, idx: usize, range: (u64, u64), limiter: Option<&RateLimiter>, output: &mut dyn Write) -> common::Result<u64> {
+    let mut ranges: RangeList = vec![(0, RANGE_TO_END); file_count].into();
+    ranges[idx] = range;
+    ranges.send(stream)?;
+
+    let mut priorities = priority_list::new(file_count);
+    priorities[idx] = 10;
+    priority_list::send(stream, &priorities)?;
+
+    let mut received = 0;
+    loop {
+        let chunk = Chunk::recv_with(stream, codec)?;
+        received += chunk.len as u64;
+        if let Some(limiter) = limiter {
+            limiter.consume(chunk.len as u64);
+        }
+        if chunk.write(output)? {
+            return Ok(received);
+        }
+    }
+}
+
+/// Requests `start..end` of file `idx` alone and writes it at the same position in `output`.
+fn fetch_range<S: Read + Write>(stream: &mut S, codec: Codec, file_count: usize, idx: usize, (start, end): (u64, u64), limiter: Option<&RateLimiter>, output: &Path) -> common::Result<()> {
+    let mut file = OpenOptions::new().write(true).open(output)?;
+    file.seek(SeekFrom::Start(start))?;
+
+    let received = fetch(stream, codec, file_count, idx, (start, end), limiter, &mut file)?;
+    if received != end - start {
+        return Err(Error::InvalidData(format!(
+            "range {start}..{end} ended after {received} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// Downloads file `idx` over `segments` connections, each fetching its own byte range.
+/// `stream` is the already established connection, the others are opened to `addr` and
+/// ask for the same `subpath`, so that the file is numbered the same on all of them.
+#[allow(clippy::too_many_arguments)]
+pub fn download(
+    mut stream: Stream, addr: &str, compression: Codec, subpath: Option<&str>, codec: Codec,
+    downloadables: &FileList, hash: Option<Digest>, sum: Option<Digest>, flags: u8, idx: usize, segments: u64, limiter: Option<&RateLimiter>, part_path: &Path, path: &Path,
+) -> io::Result<()> {
+    let (name, size) = &downloadables[idx];
+    File::create(part_path)?.set_len(*size)?;
+
+    let ranges = split_ranges(*size, segments);
+    thread::scope(|scope| {
+        let workers: Vec<_> = ranges[1..].iter().map(|range| scope.spawn(move || {
+            let Handshake { mut stream, codec, files, .. } = connect(addr, compression, subpath, None)?;
+            if files.len() != downloadables.len() || files[idx] != downloadables[idx] {
+                return Err(Error::InvalidData(format!("the server no longer serves `{name}`")));
+            }
+            fetch_range(&mut stream, codec, files.len(), idx, *range, limiter, part_path)
+        })).collect();
+
+        fetch_range(&mut stream, codec, downloadables.len(), idx, ranges[0], limiter, part_path)?;
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+        Ok::<_, Error>(())
+    })?;
+
+    let assembled = part_path.metadata()?.len();
+    if assembled != *size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected {size} bytes, assembled {assembled}")));
+    }
+    verify(part_path, hash, sum)?;
+    move_file(part_path, path)?;
+    apply_flags(path, flags)
+}
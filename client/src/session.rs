@@ -0,0 +1,81 @@
+use std::{collections::HashMap, fs::{self, File}, io::{self, BufRead, BufReader, Write}, path::Path};
+use common::{DownloadableFile, Error, FileList};
+
+const HEADER: &str = "SESSION";
+const VERSION: u32 = 2;
+
+pub struct Entry {
+    pub done: bool,
+    pub priority: u8,
+    pub offset: u64,
+    /// Advertised size of the file when the entry was written, missing in version 1 files.
+    pub size: Option<u64>,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::InvalidData(msg.into())
+}
+
+pub fn load(path: &Path, inverse_map: &HashMap<Box<str>, usize>) -> common::Result<Vec<(usize, Entry)>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    let header = lines.next().ok_or_else(|| invalid("empty session file"))??;
+    let version = match header.split_once(' ') {
+        Some((HEADER, version)) => version.parse::<u32>().map_err(|_| invalid("bad session header"))?,
+        _ => return Err(invalid("bad session header")),
+    };
+    if version == 0 {
+        return Err(invalid("bad session header"));
+    }
+    if version > VERSION {
+        return Err(Error::VersionMismatch { found: version, supported: VERSION });
+    }
+
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line?;
+        let fields = if version == 1 { 4 } else { 5 };
+        let mut iter = line.splitn(fields, ' ');
+        let (Some(state), Some(priority), Some(offset)) = (iter.next(), iter.next(), iter.next()) else {
+            return Err(invalid("truncated session entry"));
+        };
+        let size = if version == 1 {
+            None
+        } else {
+            let size = iter.next().ok_or_else(|| invalid("truncated session entry"))?;
+            Some(size.parse().map_err(|_| invalid("bad size in session entry"))?)
+        };
+        let name = iter.next().ok_or_else(|| invalid("truncated session entry"))?;
+
+        let done = match state {
+            "DONE" => true,
+            "PART" => false,
+            _ => return Err(invalid("unknown session entry state")),
+        };
+        let priority = priority.parse().map_err(|_| invalid("bad priority in session entry"))?;
+        let offset = offset.parse().map_err(|_| invalid("bad offset in session entry"))?;
+
+        if let Some(idx) = inverse_map.get(name) {
+            entries.push((*idx, Entry { done, priority, offset, size }));
+        }
+    }
+
+    Ok(entries)
+}
+
+pub fn save<F>(path: &Path, downloadables: &FileList, files: &[DownloadableFile<F>], priorities: &[u8], progress: &[u64]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut out = io::BufWriter::new(File::create(&tmp_path)?);
+    writeln!(out, "{HEADER} {VERSION}")?;
+
+    for (idx, (name, size)) in downloadables.iter().enumerate() {
+        if priorities[idx] == 0 && !files[idx].done {
+            continue;
+        }
+        let state = if files[idx].done { "DONE" } else { "PART" };
+        writeln!(out, "{state} {} {} {size} {name}", priorities[idx], progress[idx])?;
+    }
+
+    out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+    fs::rename(tmp_path, path)
+}
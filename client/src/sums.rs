@@ -0,0 +1,33 @@
+use std::{collections::HashMap, fs::File, io::{self, BufRead, BufReader}, path::Path};
+use common::{from_hex, HashList};
+
+/// Loads a `sha256sum` style manifest, where each line is a hex digest followed by two
+/// spaces, or a space and `*` in binary mode, and the file name. The digests are returned
+/// in file list order, `None` for files the manifest doesn't mention, along with the
+/// names it mentions that aren't in the file list.
+pub fn load(path: &Path, inverse_map: &HashMap<Box<str>, usize>) -> io::Result<(HashList, Vec<String>)> {
+    let mut sums = vec![None; inverse_map.len()];
+    let mut unknown = Vec::new();
+
+    for (line_no, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry = line.split_once(' ').and_then(|(hex, rest)| {
+            let name = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+            Some((from_hex(hex)?, name))
+        });
+        let Some((digest, name)) = entry else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed line {}", line_no + 1)));
+        };
+
+        match inverse_map.get(name) {
+            Some(idx) => sums[*idx] = Some(digest),
+            None => unknown.push(name.to_string()),
+        }
+    }
+
+    Ok((sums.into(), unknown))
+}
@@ -0,0 +1,155 @@
+//! `--output-template`, which decides where each file is saved under the output directory.
+//! Placeholders are written in braces:
+//!
+//! - `{name}` the name the server lists the file under, with its directories
+//! - `{dir}` the directories of the name, empty when there are none
+//! - `{base}` the name without its directories
+//! - `{stem}` and `{ext}` the base name before and after its last `.`
+//! - `{date}` the day the client started, as `YYYY-MM-DD` in UTC
+//!
+//! Empty directories are dropped, so `{dir}/{base}` works for names without any.
+
+use std::{path::{Component, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+enum Part {
+    Literal(String),
+    Name,
+    Dir,
+    Base,
+    Stem,
+    Ext,
+    Date,
+}
+
+pub struct Template {
+    parts: Vec<Part>,
+    date: String,
+}
+
+/// Formats the UTC day of `time` as `YYYY-MM-DD`.
+fn utc_date(time: SystemTime) -> String {
+    let days = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs() / 86400).unwrap_or(0) as i64;
+    // Howard Hinnant's `civil_from_days`, counting in eras of 400 years from March 1st.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+impl Template {
+    /// Parses `template`, with `{date}` standing for the day of `now`. Templates that may
+    /// place a file outside the output directory are refused.
+    pub fn parse(template: &str, now: SystemTime) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find(['{', '}']) {
+            if rest[open..].starts_with('}') {
+                return Err(format!("`}}` without a matching `{{` in `{template}`"));
+            }
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            let Some(close) = rest[open..].find('}') else {
+                return Err(format!("`{{` without a matching `}}` in `{template}`"));
+            };
+            parts.push(match &rest[open + 1..open + close] {
+                "name" => Part::Name,
+                "dir" => Part::Dir,
+                "base" => Part::Base,
+                "stem" => Part::Stem,
+                "ext" => Part::Ext,
+                "date" => Part::Date,
+                other => return Err(format!(
+                    "unknown placeholder `{{{other}}}`, expected `{{name}}`, `{{dir}}`, `{{base}}`, `{{stem}}`, `{{ext}}` or `{{date}}`"
+                )),
+            });
+            rest = &rest[open + close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if template.starts_with('/') || template.split('/').any(|part| part == "..") {
+            return Err(format!("`{template}` would place files outside the output directory"));
+        }
+        if !parts.iter().any(|part| matches!(part, Part::Name | Part::Base | Part::Stem)) {
+            return Err(format!("`{template}` would save every file under the same name, use `{{name}}` or `{{base}}`"));
+        }
+        Ok(Self { parts, date: utc_date(now) })
+    }
+
+    /// Where file `name` is saved, relative to the output directory, or `None` if the name
+    /// itself would take it outside.
+    pub fn render(&self, name: &str) -> Option<PathBuf> {
+        let (dir, base) = name.rsplit_once('/').unwrap_or(("", name));
+        let (stem, ext) = match base.rsplit_once('.') {
+            Some((stem, ext)) if !stem.is_empty() => (stem, ext),
+            _ => (base, ""),
+        };
+
+        let mut rendered = String::new();
+        for part in &self.parts {
+            rendered += match part {
+                Part::Literal(text) => text,
+                Part::Name => name,
+                Part::Dir => dir,
+                Part::Base => base,
+                Part::Stem => stem,
+                Part::Ext => ext,
+                Part::Date => &self.date,
+            };
+        }
+
+        let path: PathBuf = rendered.split('/').filter(|part| !part.is_empty() && *part != ".").collect();
+        let inside = path.components().next().is_some()
+            && path.components().all(|component| matches!(component, Component::Normal(_)));
+        inside.then_some(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    /// 2024-02-29 12:00 UTC.
+    fn leap_day() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(19782 * 86400 + 12 * 3600)
+    }
+
+    #[test]
+    fn renders_sample_names() {
+        let template = Template::parse("{date}/{dir}/{stem}-copy.{ext}", leap_day()).unwrap();
+        assert_eq!(template.render("docs/guide/intro.md"), Some("2024-02-29/docs/guide/intro-copy.md".into()));
+        assert_eq!(template.render("readme.txt"), Some("2024-02-29/readme-copy.txt".into()));
+
+        let template = Template::parse("by-ext/{ext}/{base}", leap_day()).unwrap();
+        assert_eq!(template.render("a/b.tar.gz"), Some("by-ext/gz/b.tar.gz".into()));
+        assert_eq!(template.render("Makefile"), Some("by-ext/Makefile".into()));
+    }
+
+    #[test]
+    fn dates_are_utc_days() {
+        assert_eq!(utc_date(UNIX_EPOCH), "1970-01-01");
+        assert_eq!(utc_date(leap_day()), "2024-02-29");
+        assert_eq!(utc_date(leap_day() + Duration::from_secs(12 * 3600)), "2024-03-01");
+    }
+
+    #[test]
+    fn names_rendering_outside_the_output_directory_are_refused() {
+        let template = Template::parse("{stem}", leap_day()).unwrap();
+        assert_eq!(template.render("..."), None);
+        assert_eq!(template.render("x/.."), None);
+
+        for escaping in ["/{name}", "../{name}", "a/../../{name}"] {
+            assert!(Template::parse(escaping, leap_day()).is_err(), "{escaping}");
+        }
+        assert!(Template::parse("{date}/{ext}", leap_day()).is_err());
+    }
+}
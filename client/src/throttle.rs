@@ -0,0 +1,43 @@
+use std::{sync::Mutex, thread, time::{Duration, Instant}};
+
+/// How far the transfer may fall behind the limit before the lost time is forgotten, so
+/// that a pause doesn't turn into a burst at full speed afterwards.
+const MAX_DEBT: Duration = Duration::from_secs(1);
+
+struct Pace {
+    since: Instant,
+    bytes: u64,
+}
+
+/// Caps the download rate shared by every file and connection of the client. Reading
+/// slower makes the server wait on its writes, leaving the rest of the bandwidth free.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    pace: Mutex<Pace>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec,
+            pace: Mutex::new(Pace { since: Instant::now(), bytes: 0 }),
+        }
+    }
+
+    /// Accounts for `bytes` just received and sleeps until the limit allows them.
+    pub fn consume(&self, bytes: u64) {
+        let due = {
+            let mut pace = self.pace.lock().unwrap();
+            pace.bytes += bytes;
+            let due = pace.since + Duration::from_secs_f64(pace.bytes as f64 / self.bytes_per_sec as f64);
+
+            let now = Instant::now();
+            if now.saturating_duration_since(due) > MAX_DEBT {
+                *pace = Pace { since: now, bytes: 0 };
+                return;
+            }
+            due
+        };
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+    }
+}
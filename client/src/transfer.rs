@@ -0,0 +1,504 @@
+use std::{fs::{self, File}, io::{self, Read, Write}, mem, path::{Path, PathBuf}, sync::Arc};
+use common::{digest, grow, initialize_handlers, priority_list, to_hex, Chunk, Codec, Digest, DownloadableFile, FileList, FlagList, HashList, TransferObserver};
+use crate::{throttle::RateLimiter, writer::{Backlog, Output}};
+
+/// Moves a finished download into place. Renaming only works within one filesystem,
+/// so otherwise the file is copied next to its destination first and renamed there.
+pub fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    // Names of files served out of an archive may contain directories.
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            eprintln!("WARNING: `{}` is on another filesystem than `{}`, copying it", from.display(), to.display());
+            copy_across(from, to)
+        },
+        result => result,
+    }
+}
+
+/// Moves `from` to `to` on another filesystem, through a `.part` file next to `to` so that
+/// `to` never holds half of the file.
+fn copy_across(from: &Path, to: &Path) -> io::Result<()> {
+    let mut tmp_name = to.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".part");
+    let tmp_path = to.with_file_name(tmp_name);
+
+    fs::copy(from, &tmp_path)?;
+    fs::rename(&tmp_path, to)?;
+    fs::remove_file(from)
+}
+
+/// Checks the content of `path` against the digest advertised by the server and the one
+/// listed in the sums file, whichever are known.
+pub fn verify(path: &Path, advertised: Option<Digest>, listed: Option<Digest>) -> io::Result<()> {
+    if advertised.is_none() && listed.is_none() {
+        return Ok(());
+    }
+
+    let actual = digest(&mut File::open(path)?)?;
+    for (expected, source) in [(advertised, "the server"), (listed, "the sums file")] {
+        match expected {
+            Some(expected) if expected != actual => return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "checksum mismatch with {source}, expected {} but got {}", to_hex(&expected), to_hex(&actual)
+            ))),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Applies the advertised attributes to a finished file. Only the executable bit is
+/// carried over, and only on Unix.
+#[cfg(unix)]
+pub fn apply_flags(path: &Path, flags: u8) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if flags & common::FLAG_EXECUTABLE == 0 {
+        return Ok(());
+    }
+
+    // Executable by whoever may read it, which keeps the umask applied at creation.
+    let mut permissions = path.metadata()?.permissions();
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+pub fn apply_flags(_path: &Path, _flags: u8) -> io::Result<()> {
+    Ok(())
+}
+
+pub struct Transfer {
+    pub downloadables: FileList,
+    pub paths: Box<[PathBuf]>,
+    pub part_paths: Box<[PathBuf]>,
+    pub files: Box<[DownloadableFile<Output>]>,
+    pub priorities: Box<[u8]>,
+    /// Bytes received of every file, 64 bits wide even where `usize` isn't.
+    pub progress: Box<[u64]>,
+    started: Box<[bool]>,
+    failed: Box<[bool]>,
+    strict: bool,
+    /// How many times a file that arrived damaged is downloaded again before it fails.
+    pub retries: u32,
+    attempts: Box<[u32]>,
+    restarts: Vec<usize>,
+    pub codec: Codec,
+    pub hashes: HashList,
+    pub sums: HashList,
+    pub flags: FlagList,
+    pub limiter: Option<RateLimiter>,
+    /// Bytes of every file gathered before they're written out, as chunks are small.
+    pub write_buffer: usize,
+    /// Write every file on a thread of its own instead of between receiving chunks.
+    pub offload_writes: bool,
+    /// Bytes waiting for the writer threads of every file at most.
+    pub backlog: Option<Arc<Backlog>>,
+    /// The most chunks the server sends in a round, which rounds are scaled down to.
+    pub round_budget: Option<u32>,
+    /// Bytes of a file after which it's flushed and synced to disk, for readers of the
+    /// partial file.
+    pub fsync_interval: Option<u64>,
+    /// Fail files whose destination exists by the time they're finished instead of
+    /// replacing it.
+    pub no_clobber: bool,
+}
+
+impl Transfer {
+    pub fn new(downloadables: FileList, paths: Box<[PathBuf]>, part_paths: Box<[PathBuf]>, strict: bool) -> Self {
+        let len = downloadables.len();
+        Self {
+            downloadables,
+            paths,
+            part_paths,
+            files: initialize_handlers(len),
+            priorities: priority_list::new(len),
+            progress: vec![0; len].into(),
+            started: vec![false; len].into(),
+            failed: vec![false; len].into(),
+            strict,
+            retries: 0,
+            attempts: vec![0; len].into(),
+            restarts: Vec::new(),
+            codec: Codec::None,
+            hashes: vec![None; len].into(),
+            sums: vec![None; len].into(),
+            flags: vec![0; len].into(),
+            limiter: None,
+            write_buffer: 0,
+            offload_writes: false,
+            backlog: None,
+            round_budget: None,
+            fsync_interval: None,
+            no_clobber: false,
+        }
+    }
+
+    /// Adds files the server started serving after the handshake, numbered after the others.
+    pub fn append(&mut self, files: &FileList, paths: Vec<PathBuf>, part_paths: Vec<PathBuf>, flags: &FlagList) {
+        let len = self.downloadables.len() + files.len();
+        self.downloadables = self.downloadables.iter().chain(files.iter()).cloned().collect();
+        self.paths = mem::take(&mut self.paths).into_vec().into_iter().chain(paths).collect();
+        self.part_paths = mem::take(&mut self.part_paths).into_vec().into_iter().chain(part_paths).collect();
+        self.flags = self.flags.iter().chain(flags.iter()).copied().collect();
+
+        grow(&mut self.files, len, || DownloadableFile { done: false, file: None });
+        grow(&mut self.priorities, len, || 0);
+        grow(&mut self.progress, len, || 0);
+        grow(&mut self.started, len, || false);
+        grow(&mut self.failed, len, || false);
+        grow(&mut self.attempts, len, || 0);
+        grow(&mut self.hashes, len, || None);
+        grow(&mut self.sums, len, || None);
+    }
+
+    /// Where the chunks of a file go once `file` is opened for it.
+    pub fn output(&self, file: File) -> Output {
+        Output::new(file, self.write_buffer, self.offload_writes, self.backlog.as_ref())
+    }
+
+    /// Marks a file as failed. In strict mode this aborts the whole transfer.
+    fn fail(&mut self, idx: usize, err: io::Error, observer: &mut dyn TransferObserver) -> io::Result<()> {
+        self.failed[idx] = true;
+        observer.on_failed(idx, &err);
+        if self.strict {
+            let name = &self.downloadables[idx].0;
+            return Err(io::Error::new(err.kind(), format!("`{name}`: {err}")));
+        }
+        Ok(())
+    }
+
+    /// Downloads a damaged file again if it has retries left, and fails it otherwise.
+    fn retry_or_fail(&mut self, idx: usize, err: io::Error, observer: &mut dyn TransferObserver) -> io::Result<()> {
+        if self.attempts[idx] >= self.retries {
+            return self.fail(idx, err, observer);
+        }
+
+        self.attempts[idx] += 1;
+        observer.on_retry(idx, &err, self.attempts[idx]);
+        self.files[idx].done = false;
+        self.priorities[idx] = 0;
+        self.progress[idx] = 0;
+        self.restarts.push(idx);
+        Ok(())
+    }
+
+    /// Files to be requested again from their first byte, after the server was sent a
+    /// `protocol::RESTART` for each of them.
+    pub fn take_restarts(&mut self) -> Vec<usize> {
+        mem::take(&mut self.restarts)
+    }
+
+    /// Receives one scheduling round of chunks from the server, returning how many
+    /// files were finished (completed or failed) during it.
+    pub fn receive_round<T: Read>(&mut self, stream: &mut T, observer: &mut dyn TransferObserver) -> common::Result<usize> {
+        let mut finished = 0;
+
+        // Every requested file is announced before the first chunk arrives, so that slow
+        // rounds don't hide the files further down the list.
+        for idx in 0..self.downloadables.len() {
+            if self.priorities[idx] != 0 && !self.files[idx].done && !self.started[idx] {
+                self.started[idx] = true;
+                let (name, size) = &self.downloadables[idx];
+                observer.on_start(idx, name, *size);
+            }
+        }
+
+        // The server scales the round the same way, they have to agree on every chunk.
+        let mut round: Box<[u8]> = self.priorities.iter()
+            .zip(self.files.iter())
+            .map(|(priority, handler)| if handler.done { 0 } else { *priority })
+            .collect();
+        if let Some(budget) = self.round_budget {
+            priority_list::limit_round(&mut round, budget);
+        }
+
+        for idx in 0..self.downloadables.len() {
+            let priority = round[idx];
+            if priority == 0 {
+                continue;
+            }
+
+            let size = self.downloadables[idx].1;
+
+            if self.files[idx].file.is_none() && !self.failed[idx] {
+                match File::create(&self.part_paths[idx]) {
+                    Ok(file) => self.files[idx].file = Some(self.output(file)),
+                    Err(err) => self.fail(idx, err, observer)?,
+                }
+            }
+
+            let mut sink = io::sink();
+            let handler = &mut self.files[idx];
+
+            for _ in 0..priority {
+                let chunk = Chunk::recv_with(stream, self.codec)?;
+                let before = self.progress[idx];
+                self.progress[idx] += chunk.len as u64;
+                if let Some(limiter) = &self.limiter {
+                    limiter.consume(chunk.len as u64);
+                }
+
+                let output: &mut dyn Write = match &mut handler.file {
+                    Some(file) => file,
+                    None => &mut sink,
+                };
+                if chunk.write(output)? {
+                    handler.done = true;
+                    break;
+                };
+                // A finished file is written out by `finish` below instead.
+                if let (Some(interval), Some(file)) = (self.fsync_interval, &mut handler.file) {
+                    if before / interval != self.progress[idx] / interval {
+                        file.sync()?;
+                    }
+                }
+            }
+
+            if !handler.done {
+                observer.on_progress(idx, self.progress[idx]);
+                continue;
+            }
+
+            // Dropping the output would write the rest too, but without telling whether that worked.
+            let flushed = handler.file.take().map_or(Ok(()), Output::finish);
+            finished += 1;
+            if self.failed[idx] {
+                continue;
+            }
+
+            let received = self.progress[idx];
+            observer.on_progress(idx, received);
+            if let Err(err) = flushed {
+                self.fail(idx, err, observer)?;
+            } else if received != size {
+                let err = io::Error::new(io::ErrorKind::InvalidData, format!("expected {size} bytes, received {received}"));
+                // Nothing at all means the server couldn't open the file, which won't change.
+                if received == 0 {
+                    self.fail(idx, err, observer)?;
+                } else {
+                    self.retry_or_fail(idx, err, observer)?;
+                }
+            } else if let Err(err) = verify(&self.part_paths[idx], self.hashes[idx], self.sums[idx]) {
+                self.retry_or_fail(idx, err, observer)?;
+            } else if self.no_clobber && self.paths[idx].symlink_metadata().is_ok() {
+                let err = io::Error::new(io::ErrorKind::AlreadyExists, format!(
+                    "`{}` appeared while downloading, not overwriting it", self.paths[idx].display()
+                ));
+                self.fail(idx, err, observer)?;
+            } else if let Err(err) = move_file(&self.part_paths[idx], &self.paths[idx])
+                .and_then(|_| apply_flags(&self.paths[idx], self.flags[idx])) {
+                self.fail(idx, err, observer)?;
+            } else {
+                observer.on_complete(idx);
+            }
+        }
+
+        Ok(finished)
+    }
+
+    pub fn any_failed(&self) -> bool {
+        self.failed.iter().any(|failed| *failed)
+    }
+
+    /// Removes the output of every started file that didn't complete successfully.
+    pub fn discard_partial(&mut self) {
+        for idx in 0..self.files.len() {
+            if !self.started[idx] || (self.files[idx].done && !self.failed[idx]) {
+                continue;
+            }
+
+            drop(self.files[idx].file.take());
+            self.progress[idx] = 0;
+            if let Err(err) = fs::remove_file(&self.part_paths[idx]) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    eprintln!("WARNING: Failed to remove `{}`: {err}", self.part_paths[idx].display());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+    use crate::tests::chunks_of;
+    use super::*;
+
+    /// Records every event in the order they came.
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl TransferObserver for Events {
+        fn on_start(&mut self, idx: usize, _name: &str, size: u64) {
+            self.0.push(format!("start {idx} {size}"));
+        }
+        fn on_progress(&mut self, idx: usize, received: u64) {
+            self.0.push(format!("progress {idx} {received}"));
+        }
+        fn on_complete(&mut self, idx: usize) {
+            self.0.push(format!("complete {idx}"));
+        }
+        fn on_failed(&mut self, idx: usize, _err: &io::Error) {
+            self.0.push(format!("failed {idx}"));
+        }
+        fn on_retry(&mut self, idx: usize, _err: &io::Error, attempt: u32) {
+            self.0.push(format!("retry {idx} {attempt}"));
+        }
+        fn on_error(&mut self, _err: &common::Error) {
+            self.0.push("error".to_string());
+        }
+    }
+
+    /// A transfer of files `0.bin`, `1.bin` and so on of `sizes` into a directory named
+    /// after the test, along with the content of every file.
+    fn transfer(name: &str, sizes: &[usize], strict: bool) -> (PathBuf, Transfer, Vec<Vec<u8>>) {
+        let dir = env::temp_dir().join(format!("client-test-{}-{name}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let names: Vec<String> = (0..sizes.len()).map(|idx| format!("{idx}.bin")).collect();
+        let downloadables: FileList = names.iter().zip(sizes).map(|(name, size)| (name.as_str().into(), *size as u64)).collect();
+        let paths = names.iter().map(|name| dir.join(name)).collect();
+        let part_paths = names.iter().map(|name| dir.join(format!("{name}.part"))).collect();
+        let data = sizes.iter().enumerate().map(|(idx, size)| vec![idx as u8 + 1; *size]).collect();
+        (dir, Transfer::new(downloadables, paths, part_paths, strict), data)
+    }
+
+    #[test]
+    fn files_are_copied_into_place_across_filesystems() {
+        let dir = env::temp_dir().join(format!("client-test-{}-move", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (from, to) = (dir.join("download.part"), dir.join("sub/download"));
+        fs::write(&from, b"content").unwrap();
+        fs::create_dir_all(to.parent().unwrap()).unwrap();
+        copy_across(&from, &to).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"content");
+        assert!(!from.exists());
+        assert!(!dir.join("sub/download.part").exists());
+
+        // Where a memory filesystem is mounted, the real thing is tried too.
+        let shm = Path::new("/dev/shm").join(format!("client-test-{}-move", process::id()));
+        if fs::write(&shm, b"other content").is_ok() {
+            move_file(&shm, &to).unwrap();
+            assert_eq!(fs::read(&to).unwrap(), b"other content");
+            assert!(!shm.exists());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn strict_mode_aborts_on_a_damaged_file_and_discards_partial_ones() {
+        for (strict, damaged) in [(false, true), (true, false), (true, true)] {
+            let (dir, mut transfer, data) = transfer("strict", &[3000, 1000], strict);
+            transfer.priorities = [1, 1].into();
+            let expected = digest(&mut &data[1][..]).unwrap();
+            transfer.hashes[1] = Some(if damaged { [0; 32] } else { expected });
+            // All of `1.bin` arrives while `0.bin` is under way.
+            let mut round = chunks_of(&data[0], 0, 1);
+            round.extend(chunks_of(&data[1], 0, 1));
+
+            let mut events = Events::default();
+            let result = transfer.receive_round(&mut &round[..], &mut events);
+            match (strict, damaged) {
+                (false, _) => {
+                    assert_eq!(result.unwrap(), 1);
+                    assert!(transfer.any_failed());
+                    assert_eq!(events.0.last().unwrap(), "failed 1");
+                },
+                (true, false) => {
+                    assert_eq!(result.unwrap(), 1);
+                    assert!(!transfer.any_failed());
+                    assert_eq!(fs::read(&transfer.paths[1]).unwrap(), data[1]);
+                },
+                (true, true) => {
+                    let err = io::Error::from(result.unwrap_err());
+                    assert!(err.to_string().contains("`1.bin`"), "{err}");
+                    assert!(transfer.part_paths[0].exists());
+                    transfer.discard_partial();
+                    assert!(!transfer.part_paths[0].exists() && !transfer.part_paths[1].exists());
+                    assert!(!transfer.paths[1].exists());
+                },
+            }
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn events_of_a_file_come_in_order() {
+        let (dir, mut transfer, data) = transfer("events", &[2500, 1000], false);
+        transfer.priorities = [1, 1].into();
+        transfer.hashes[1] = Some([0; 32]);
+        transfer.retries = 1;
+        let mut events = Events::default();
+
+        // `1.bin` arrives damaged twice, so it's retried once and then fails, while
+        // `0.bin` takes three rounds.
+        for (round, offset) in (0..3).zip([0, 1024, 2048]) {
+            let mut sent = chunks_of(&data[0], offset, 1);
+            if round < 2 {
+                sent.extend(chunks_of(&data[1], 0, 1));
+            }
+            transfer.receive_round(&mut &sent[..], &mut events).unwrap();
+            if round == 0 {
+                assert_eq!(transfer.take_restarts(), [1]);
+                transfer.priorities[1] = 1;
+            }
+        }
+
+        assert_eq!(events.0, [
+            "start 0 2500", "start 1 1000",
+            "progress 0 1024", "progress 1 1000", "retry 1 1",
+            "progress 0 2048", "progress 1 1000", "failed 1",
+            "progress 0 2500", "complete 0",
+        ]);
+        assert_eq!(fs::read(&transfer.paths[0]).unwrap(), data[0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_files_are_retried_until_retries_run_out() {
+        let (dir, mut transfer, data) = transfer("retries", &[3000, 3000], false);
+        transfer.priorities = [3, 3].into();
+        transfer.hashes[0] = Some([0; 32]);
+        transfer.retries = 2;
+        let mut events = Events::default();
+
+        // `0.bin` never matches its digest, and `1.bin` always comes a chunk short.
+        for _ in 0..3 {
+            let mut sent = chunks_of(&data[0], 0, 3);
+            let mut short = chunks_of(&data[1], 0, 1);
+            Chunk::empty().send_with(&mut short, Codec::None, 0).unwrap();
+            sent.extend(short);
+            assert_eq!(transfer.receive_round(&mut &sent[..], &mut events).unwrap(), 2);
+            for idx in transfer.take_restarts() {
+                assert_eq!(transfer.progress[idx], 0);
+                transfer.priorities[idx] = 3;
+            }
+        }
+
+        let outcomes: Vec<&str> = events.0.iter().map(String::as_str).filter(|event| !event.starts_with("progress")).collect();
+        assert_eq!(outcomes, [
+            "start 0 3000", "start 1 3000",
+            "retry 0 1", "retry 1 1",
+            "retry 0 2", "retry 1 2",
+            "failed 0", "failed 1",
+        ]);
+        assert!(transfer.take_restarts().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_the_server_sends_nothing_of_fail_without_a_retry() {
+        let (dir, mut transfer, _) = transfer("empty", &[3000], false);
+        transfer.priorities = [1].into();
+        transfer.retries = 3;
+        let mut sent = Vec::new();
+        Chunk::empty().send_with(&mut sent, Codec::None, 0).unwrap();
+        let mut events = Events::default();
+        transfer.receive_round(&mut &sent[..], &mut events).unwrap();
+        assert_eq!(events.0, ["start 0 3000", "progress 0 0", "failed 0"]);
+        assert!(transfer.take_restarts().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+use std::{env, io::{self, IsTerminal, Write}, time::{Duration, Instant}};
+use common::{grow, Error, FileList, TransferObserver};
+
+const PROGRESS_LEN: usize = 64;
+const MIN_PROGRESS_LEN: usize = 10;
+/// Above this many active and queued downloads the bars are replaced by a single summary line.
+const COMPACT_THRESHOLD: usize = 8;
+/// How often the bars are printed again when they can't be redrawn in place.
+const REPRINT_INTERVAL: Duration = Duration::from_secs(5);
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BarStyle {
+    Unicode,
+    Ascii,
+}
+
+/// How the bars are kept up to date, depending on what the terminal can do.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RedrawMode {
+    /// The cursor is moved up over the previous bars to draw them again.
+    InPlace,
+    /// Only `\r` is understood, so a single summary line is drawn over itself.
+    Line,
+    /// Not a terminal, such as a CI log. The bars are printed again below the previous
+    /// ones every `REPRINT_INTERVAL`.
+    Reprint,
+    /// No bars at all.
+    Off,
+}
+
+impl RedrawMode {
+    /// Picks the mode stdout supports. A terminal is taken to move the cursor unless `TERM`
+    /// says it's dumb or isn't set at all, which Windows terminals never do.
+    pub fn detect() -> Self {
+        if !io::stdout().is_terminal() {
+            return RedrawMode::Reprint;
+        }
+        match env::var("TERM") {
+            Ok(term) if term == "dumb" => RedrawMode::Line,
+            Err(_) if cfg!(unix) => RedrawMode::Line,
+            _ => RedrawMode::InPlace,
+        }
+    }
+}
+
+/// Whether output to a terminal is colored. `NO_COLOR` set to anything but an empty string
+/// turns colors off like `--no-color` does, see <https://no-color.org>.
+pub fn use_color(no_color: bool, is_terminal: bool) -> bool {
+    is_terminal
+        && !no_color
+        && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && match env::var("TERM") {
+            Ok(term) => term != "dumb",
+            Err(_) => !cfg!(unix),
+        }
+}
+
+/// Wraps `text` in `color` if `enabled`.
+fn paint(text: &str, color: &str, enabled: bool) -> String {
+    if enabled {
+        format!("{color}{text}{RESET}")
+    } else {
+        text.to_string()
+    }
+}
+
+/// How much of `size` bytes `received` is, in `scale`ths. Computed in 128 bits, so that
+/// even files of many terabytes don't overflow.
+fn scaled(received: u64, size: u64, scale: u64) -> u64 {
+    (received as u128 * scale as u128 / size.max(1) as u128) as u64
+}
+
+pub fn render_progress_bar(progress: u64, size: u64, width: usize, style: BarStyle) -> String {
+    let (full_block, blocks): (char, &[char]) = match style {
+        BarStyle::Unicode => ('█', &[' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉']),
+        BarStyle::Ascii => ('#', &['-']),
+    };
+
+    let resolution = width * blocks.len();
+    let pos = scaled(progress.min(size), size, resolution as u64) as usize;
+    let full = pos / blocks.len();
+
+    let mut progress_bar = vec![blocks[0]; width];
+    for c in progress_bar[..full].iter_mut() {
+        *c = full_block;
+    }
+    if full < progress_bar.len() {
+        progress_bar[full] = blocks[pos % blocks.len()];
+    }
+    progress_bar.into_iter().collect()
+}
+
+/// Whether a new frame is due. The frame showing a file at 100% is always drawn, so the
+/// last state of a bar is never skipped.
+fn should_redraw(last_drawn: Option<Instant>, now: Instant, interval: Duration, finished: bool) -> bool {
+    finished || last_drawn.is_none_or(|last_drawn| now.duration_since(last_drawn) >= interval)
+}
+
+#[cfg(unix)]
+fn terminal_width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_col > 0).then_some(size.ws_col as usize)
+}
+
+#[cfg(not(unix))]
+fn terminal_width() -> Option<usize> {
+    None
+}
+
+/// Bytes received over the whole transfer, sampled every `interval` into a log line.
+pub struct Throughput {
+    interval: Duration,
+    started: Instant,
+    last_sample: Instant,
+    sampled: u64,
+    received: u64,
+}
+
+impl Throughput {
+    pub fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Self { interval, started: now, last_sample: now, sampled: 0, received: 0 }
+    }
+
+    /// Counts `bytes` more and returns the line of the sample if one is due.
+    fn add(&mut self, bytes: u64) -> Option<String> {
+        self.received += bytes;
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_sample);
+        if elapsed < self.interval {
+            return None;
+        }
+
+        let bytes = self.received - self.sampled;
+        let current = bytes as f64 / elapsed.as_secs_f64() / 1e6;
+        let average = self.received as f64 / now.duration_since(self.started).as_secs_f64() / 1e6;
+        self.last_sample = now;
+        self.sampled = self.received;
+        Some(format!("Received {bytes} bytes in {:.1}s, {current:.2} MB/s now, {average:.2} MB/s on average", elapsed.as_secs_f64()))
+    }
+}
+
+/// The built-in terminal interface, drawing one progress bar per active download, or a
+/// summary line when there are too many of them.
+pub struct TerminalUi {
+    names: Box<[Box<str>]>,
+    sizes: Box<[u64]>,
+    progress: Box<[u64]>,
+    active: Vec<usize>,
+    /// Requested files held back until an active one finishes.
+    queued: Vec<usize>,
+    drawn: Vec<usize>,
+    bar_width: Option<usize>,
+    bar_style: BarStyle,
+    redraw_interval: Duration,
+    last_drawn: Option<Instant>,
+    compact: bool,
+    /// Files that were started and didn't fail, for the summary line.
+    started: Vec<usize>,
+    completed: usize,
+    current: Option<usize>,
+    mode: RedrawMode,
+    /// How long the line drawn in `RedrawMode::Line` is, so that it can be blanked.
+    line_len: usize,
+    throughput: Option<Throughput>,
+    /// Color what goes to stdout and to stderr.
+    color: bool,
+    error_color: bool,
+}
+
+impl TerminalUi {
+    pub fn new(downloadables: &FileList, bar_width: Option<usize>, bar_style: BarStyle, redraw_interval: Duration, compact: bool, mode: RedrawMode, throughput: Option<Throughput>) -> Self {
+        Self {
+            names: downloadables.iter().map(|(name, _)| name.clone()).collect(),
+            sizes: downloadables.iter().map(|(_, size)| *size).collect(),
+            progress: vec![0; downloadables.len()].into(),
+            active: Vec::new(),
+            queued: Vec::new(),
+            drawn: Vec::new(),
+            bar_width,
+            bar_style,
+            redraw_interval,
+            last_drawn: None,
+            compact,
+            started: Vec::new(),
+            completed: 0,
+            current: None,
+            mode,
+            line_len: 0,
+            throughput,
+            color: false,
+            error_color: false,
+        }
+    }
+
+    /// Colors the bars and messages of terminals, unless `no_color` is set.
+    pub fn set_color(&mut self, no_color: bool) {
+        self.color = use_color(no_color, io::stdout().is_terminal());
+        self.error_color = use_color(no_color, io::stderr().is_terminal());
+    }
+
+    /// Adds files the server started serving after the handshake.
+    pub fn append(&mut self, files: &FileList) {
+        self.names = self.names.iter().cloned().chain(files.iter().map(|(name, _)| name.clone())).collect();
+        self.sizes = self.sizes.iter().copied().chain(files.iter().map(|(_, size)| *size)).collect();
+        grow(&mut self.progress, self.names.len(), || 0);
+    }
+
+    /// Uses the configured width, or fits the bar into the terminal next to `text_len`
+    /// characters of text.
+    fn bar_width(&self, text_len: usize) -> usize {
+        self.bar_width.unwrap_or_else(|| match terminal_width() {
+            Some(columns) => columns.saturating_sub(text_len).max(MIN_PROGRESS_LEN),
+            None => PROGRESS_LEN,
+        })
+    }
+
+    fn clear(&mut self) {
+        match self.mode {
+            RedrawMode::InPlace => for _ in self.drawn.drain(..) {
+                print!("\x1b[A\x1b[K");
+            },
+            RedrawMode::Line if !self.drawn.is_empty() => {
+                self.drawn.clear();
+                print!("\r{:1$}\r", "", self.line_len);
+            },
+            // What was printed stays.
+            _ => self.drawn.clear(),
+        }
+    }
+
+    fn redraw(&mut self, force: bool) {
+        let now = Instant::now();
+        // Reprinting on every change would bury the log, "Finished downloading" lines tell
+        // about the files reaching 100% already.
+        let (interval, force) = match self.mode {
+            RedrawMode::Reprint => (self.redraw_interval.max(REPRINT_INTERVAL), false),
+            _ => (self.redraw_interval, force),
+        };
+        if should_redraw(self.last_drawn, now, interval, force) {
+            self.last_drawn = Some(now);
+            self.draw();
+        }
+    }
+
+    /// Shows `queued` as the files waiting for their turn.
+    pub fn set_queued(&mut self, queued: Vec<usize>) {
+        if queued != self.queued {
+            self.queued = queued;
+            self.redraw(true);
+        }
+    }
+
+    /// Tells which files were just asked for, as their bars only show up once the server
+    /// starts sending them.
+    pub fn on_requested(&mut self, requested: &[usize]) {
+        if requested.is_empty() || self.is_compact() {
+            return;
+        }
+        self.clear();
+        for idx in requested.iter().copied() {
+            println!("Now downloading `{}`", self.names[idx]);
+        }
+        self.redraw(true);
+    }
+
+    fn is_compact(&self) -> bool {
+        self.compact || self.active.len() + self.queued.len() > COMPACT_THRESHOLD
+    }
+
+    /// Replaces the bars on screen with the current progress of every active download.
+    fn draw(&mut self) {
+        self.clear();
+        match self.mode {
+            RedrawMode::Off => return,
+            RedrawMode::Line => {
+                let summary = self.summary();
+                print!("\r{summary}");
+                let _ = io::stdout().flush();
+                self.line_len = summary.chars().count();
+                self.drawn.push(0);
+                return;
+            },
+            RedrawMode::InPlace | RedrawMode::Reprint => {},
+        }
+        if self.is_compact() {
+            println!("{}", self.summary());
+            self.drawn.push(0);
+            return;
+        }
+
+        let max_len = self.active.iter().map(|idx| self.names[*idx].chars().count()).max().unwrap_or(0);
+        let bar_width = self.bar_width("Downloading file  [] 100%".len() + max_len);
+        for idx in self.active.iter().copied() {
+            let (received, size) = (self.progress[idx], self.sizes[idx]);
+            if size == 0 {
+                continue;
+            }
+            let progress_str = paint(&render_progress_bar(received.min(size), size, bar_width, self.bar_style), YELLOW, self.color);
+            println!("Downloading file {0:1$} [{2}] {3}%", self.names[idx], max_len, progress_str, scaled(received.min(size), size, 100));
+            self.drawn.push(idx);
+        }
+        for idx in self.queued.iter().copied() {
+            println!("Queued file      {}", self.names[idx]);
+            self.drawn.push(idx);
+        }
+    }
+}
+
+impl TerminalUi {
+    /// A single line with the overall progress and the file that was last worked on.
+    fn summary(&self) -> String {
+        let size: u64 = self.started.iter().map(|idx| self.sizes[*idx]).sum();
+        let received: u64 = self.started.iter().map(|idx| self.progress[*idx].min(self.sizes[*idx])).sum();
+        let mut summary = format!("Downloading {}/{} files", self.completed, self.started.len());
+        if !self.queued.is_empty() {
+            summary += &format!(", {} queued", self.queued.len());
+        }
+        let current = self.current.map(|idx| self.names[idx].as_ref()).unwrap_or_default();
+
+        let percent = if size == 0 { 100 } else { scaled(received, size, 100) };
+        let bar_width = self.bar_width(summary.len() + " [] 100% - ".len() + current.chars().count());
+        let progress_str = render_progress_bar(received, size.max(1), bar_width, self.bar_style);
+        format!("{summary} [{progress_str}] {percent}% - {current}")
+    }
+}
+
+impl TransferObserver for TerminalUi {
+    fn on_start(&mut self, idx: usize, _name: &str, _size: u64) {
+        self.active.push(idx);
+        self.started.push(idx);
+        self.redraw(true);
+    }
+
+    fn on_progress(&mut self, idx: usize, received: u64) {
+        let sample = self.throughput.as_mut().and_then(|throughput| throughput.add(received.saturating_sub(self.progress[idx])));
+        if let Some(sample) = sample {
+            self.clear();
+            println!("{sample}");
+        }
+        self.progress[idx] = received;
+        self.current = Some(idx);
+        self.redraw(received >= self.sizes[idx]);
+    }
+
+    fn on_complete(&mut self, idx: usize) {
+        let compact = self.is_compact();
+        self.clear();
+        self.active.retain(|active| *active != idx);
+        self.completed += 1;
+        if compact {
+            self.redraw(self.active.is_empty());
+        } else {
+            println!("{}", paint(&format!("Finished downloading `{}`", self.names[idx]), GREEN, self.color));
+        }
+    }
+
+    fn on_failed(&mut self, idx: usize, err: &io::Error) {
+        self.clear();
+        self.active.retain(|active| *active != idx);
+        self.started.retain(|started| *started != idx);
+        eprintln!("{}: Failed to download `{}`: {err}", paint("ERROR", RED, self.error_color), self.names[idx]);
+    }
+
+    fn on_retry(&mut self, idx: usize, err: &io::Error, attempt: u32) {
+        self.clear();
+        self.progress[idx] = 0;
+        eprintln!("{}: Downloading `{}` again (attempt {attempt}): {err}", paint("WARNING", YELLOW, self.error_color), self.names[idx]);
+    }
+
+    fn on_error(&mut self, _err: &Error) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentages_of_10_tb_files_dont_overflow() {
+        let size = 10_000_000_000_000;
+        assert_eq!(scaled(size / 2, size, 100), 50);
+        assert_eq!(scaled(size - 1, size, 100), 99);
+        assert_eq!(scaled(size, size, 100), 100);
+        assert_eq!(render_progress_bar(size / 4, size, 8, BarStyle::Ascii), "##------");
+    }
+
+    #[test]
+    fn redraws_are_throttled_except_for_finished_files() {
+        let interval = Duration::from_millis(100);
+        let drawn = Instant::now();
+        assert!(should_redraw(None, drawn, interval, false));
+        assert!(!should_redraw(Some(drawn), drawn + interval / 2, interval, false));
+        assert!(should_redraw(Some(drawn), drawn + interval / 2, interval, true));
+        assert!(should_redraw(Some(drawn), drawn + interval, interval, false));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{path::{Path, PathBuf}, sync::mpsc::{self, Receiver, RecvTimeoutError}, thread, time::{Duration, Instant}};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+/// How long the input file has to stay untouched before it's read again, so that the
+/// several events of one save are handled together.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchMode {
+    /// Filesystem events, falling back to polling where they aren't available.
+    Notify,
+    Poll,
+}
+
+enum Backend {
+    Events {
+        // Stops watching once dropped.
+        _watcher: RecommendedWatcher,
+        events: Receiver<()>,
+    },
+    Poll {
+        interval: Duration,
+        last: Instant,
+    },
+}
+
+/// Tells when the input file may have changed.
+pub struct InputWatcher {
+    backend: Backend,
+}
+
+impl InputWatcher {
+    pub fn new(input_path: &Path, mode: WatchMode, poll_interval: Duration) -> Self {
+        let poll = Backend::Poll { interval: poll_interval, last: Instant::now() };
+        let backend = match mode {
+            WatchMode::Notify => watch_events(input_path).unwrap_or_else(|err| {
+                eprintln!("WARNING: Can't watch `{}` for changes, polling it instead: {err}", input_path.display());
+                poll
+            }),
+            WatchMode::Poll => poll,
+        };
+        Self { backend }
+    }
+
+    /// Waits up to `timeout`, returning whether the input file should be read again.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        match &mut self.backend {
+            Backend::Events { events, .. } => match events.recv_timeout(timeout) {
+                Ok(()) => {
+                    // Editors that save by renaming a temporary file over the input file
+                    // cause a burst of events, which counts as one change.
+                    while events.recv_timeout(DEBOUNCE).is_ok() {}
+                    true
+                },
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => {
+                    thread::sleep(timeout);
+                    false
+                },
+            },
+            Backend::Poll { interval, last } => {
+                thread::sleep(timeout);
+                if last.elapsed() < *interval {
+                    return false;
+                }
+                *last = Instant::now();
+                true
+            },
+        }
+    }
+}
+
+/// Watches the directory of the input file rather than the file itself, whose inode is
+/// replaced by editors that save atomically.
+fn watch_events(input_path: &Path) -> notify::Result<Backend> {
+    let name = input_path.file_name().map(|name| name.to_os_string());
+    let dir = match input_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        if event.paths.iter().any(|path| path.file_name() == name.as_deref()) {
+            let _ = sender.send(());
+        }
+    })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    Ok(Backend::Events { _watcher: watcher, events })
+}
@@ -0,0 +1,188 @@
+use std::{fs::File, io::{self, BufWriter, Write}, mem, sync::{mpsc::{self, SyncSender}, Arc, Condvar, Mutex}, thread::{self, JoinHandle}};
+
+/// How many filled buffers of a file may wait for its writer thread before receiving
+/// blocks, which bounds what's in flight to this many times `--write-buffer` per file.
+const QUEUE_DEPTH: usize = 4;
+
+/// Bytes handed to the writer threads that they didn't write yet, shared by every file so
+/// that a disk slower than the network holds up receiving instead of filling memory.
+pub struct Backlog {
+    limit: usize,
+    queued: Mutex<usize>,
+    written: Condvar,
+}
+
+impl Backlog {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, queued: Mutex::new(0), written: Condvar::new() }
+    }
+
+    /// Waits until `len` more bytes fit. A block larger than the limit only waits for the
+    /// backlog to empty, so that it isn't held up forever.
+    fn reserve(&self, len: usize) {
+        let mut queued = self.queued.lock().unwrap();
+        while *queued > 0 && *queued + len > self.limit {
+            queued = self.written.wait(queued).unwrap();
+        }
+        *queued += len;
+    }
+
+    fn release(&self, len: usize) {
+        *self.queued.lock().unwrap() -= len;
+        self.written.notify_all();
+    }
+}
+
+/// A block on its way to a writer thread, counted in the backlog until it's dropped,
+/// whether it was written or discarded along with a failed writer.
+struct Block {
+    data: Vec<u8>,
+    backlog: Option<Arc<Backlog>>,
+}
+
+impl Drop for Block {
+    fn drop(&mut self) {
+        if let Some(backlog) = &self.backlog {
+            backlog.release(self.data.len());
+        }
+    }
+}
+
+/// Where the received chunks of a file go.
+pub enum Output {
+    Buffered(BufWriter<File>),
+    /// Written on a thread of its own, so that a slow disk doesn't hold up receiving the
+    /// chunks of the other files.
+    Offloaded(OffloadedWriter),
+}
+
+impl Output {
+    pub fn new(file: File, capacity: usize, offload: bool, backlog: Option<&Arc<Backlog>>) -> Self {
+        if offload {
+            Output::Offloaded(OffloadedWriter::new(file, capacity, backlog.cloned()))
+        } else {
+            Output::Buffered(BufWriter::with_capacity(capacity, file))
+        }
+    }
+
+    /// Writes out whatever is still buffered and has it synced to disk, so that readers of
+    /// the file see everything written so far. Offloaded writes are synced by their thread
+    /// without waiting for it.
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self {
+            Output::Buffered(file) => {
+                file.flush()?;
+                file.get_ref().sync_data()
+            },
+            Output::Offloaded(writer) => writer.sync(),
+        }
+    }
+
+    /// Writes out whatever is still buffered, returning once all of it reached the file.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Buffered(mut file) => file.flush(),
+            Output::Offloaded(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Buffered(file) => file.write(buf),
+            Output::Offloaded(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Buffered(file) => file.flush(),
+            Output::Offloaded(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Gathers up to `capacity` bytes and hands them to a thread that writes them to the file.
+pub struct OffloadedWriter {
+    buf: Vec<u8>,
+    capacity: usize,
+    sender: Option<SyncSender<Block>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    backlog: Option<Arc<Backlog>>,
+}
+
+impl OffloadedWriter {
+    fn new(mut file: File, capacity: usize, backlog: Option<Arc<Backlog>>) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Block>(QUEUE_DEPTH);
+        // Stops at the first error, which is then reported by the next write. An empty
+        // block asks for the file to be synced.
+        let thread = thread::spawn(move || {
+            for block in receiver {
+                if block.data.is_empty() {
+                    file.sync_data()?;
+                }
+                file.write_all(&block.data)?;
+            }
+            Ok(())
+        });
+        Self { buf: Vec::with_capacity(capacity), capacity, sender: Some(sender), thread: Some(thread), backlog }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        let data = mem::replace(&mut self.buf, Vec::with_capacity(self.capacity));
+        if let Some(backlog) = &self.backlog {
+            backlog.reserve(data.len());
+        }
+        let block = Block { data, backlog: self.backlog.clone() };
+        match self.sender.as_ref().map(|sender| sender.send(block)) {
+            Some(Ok(())) => Ok(()),
+            _ => Err(self.join().err().unwrap_or_else(|| io::Error::other("the writer thread stopped"))),
+        }
+    }
+
+    /// Waits for the thread to write everything sent so far and returns how that went.
+    fn join(&mut self) -> io::Result<()> {
+        drop(self.sender.take());
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| Err(io::Error::other("the writer thread panicked"))),
+            None => Ok(()),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.send()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        self.join()
+    }
+}
+
+impl Write for OffloadedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= self.capacity {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Hands the buffered bytes to the thread without waiting for them to be written.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+// The file is only closed once the thread is done with it, so that a discarded download
+// can be removed right after.
+impl Drop for OffloadedWriter {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
@@ -0,0 +1,76 @@
+use std::{io::{self, BufWriter, Read, Write}, net::{TcpListener, TcpStream}, thread};
+use common::{Chunk, Codec};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const FILE_SIZE: usize = 8 << 20;
+
+/// Text-like content, so that the codecs have something to compress.
+fn sample_data() -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..FILE_SIZE).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        b"etaoin shrdlu"[(state % 13) as usize]
+    }).collect()
+}
+
+/// Receives whole files on the other end of the connection, acknowledging each one.
+fn spawn_receiver(listener: TcpListener, codec: Codec) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        loop {
+            loop {
+                match Chunk::recv_with(&mut stream, codec) {
+                    Ok(chunk) if chunk.end() => break,
+                    Ok(_) => {},
+                    Err(_) => return,
+                }
+            }
+            stream.write_all(&[0]).unwrap();
+        }
+    })
+}
+
+fn send_file<T: Write>(mut data: &[u8], out: &mut T, codec: Codec) -> io::Result<()> {
+    loop {
+        let chunk = Chunk::read(&mut data)?;
+        chunk.send_with(out, codec, codec.default_level())?;
+        if chunk.end() {
+            return out.flush();
+        }
+    }
+}
+
+// Chunks are fixed at 1024 bytes by the header format, so the sweep varies how many of
+// them are buffered before hitting the socket instead.
+fn throughput(c: &mut Criterion) {
+    let data = sample_data();
+    let mut group = c.benchmark_group("localhost");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.sample_size(10);
+
+    for codec in [Codec::None, Codec::Deflate, Codec::Zstd] {
+        for capacity in [0, 8 << 10, 64 << 10] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let receiver = spawn_receiver(listener, codec);
+            let stream = TcpStream::connect(addr).unwrap();
+
+            let id = BenchmarkId::new(format!("{codec:?}"), format!("buffer={capacity}"));
+            group.bench_with_input(id, &capacity, |b, &capacity| b.iter(|| {
+                let mut out = BufWriter::with_capacity(capacity, &stream);
+                send_file(&data, &mut out, codec).unwrap();
+                drop(out);
+                (&stream).read_exact(&mut [0]).unwrap();
+            }));
+
+            drop(stream);
+            receiver.join().unwrap();
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,207 @@
+//! A blocking client for programs that only want some files saved, without the terminal
+//! interface, sessions or input file of the `client` binary.
+
+use std::{fs::{self, File}, io::{self, Read, Write}, path::{Component, Path, PathBuf}};
+use crate::{digest, initialize_handlers, priority_list, protocol, to_hex, Chunk, Codec, Digest, Error, FileList, HashList, Packet, RangeList, Result, Stream, RANGE_TO_END};
+
+/// What `download_all` reports while it runs, in the order described by
+/// `TransferObserver`.
+#[derive(Debug)]
+pub enum ProgressEvent<'a> {
+    Started { name: &'a str, size: u64 },
+    /// Carries the total bytes received of the file so far.
+    Progress { name: &'a str, received: u64 },
+    Completed { name: &'a str },
+    Failed { name: &'a str, err: &'a io::Error },
+}
+
+/// The outcome of every selected file.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub completed: Vec<String>,
+    pub failed: Vec<(String, io::Error)>,
+}
+
+/// Only relative names without `..` are saved, whatever the server sends.
+pub fn is_safe_name(name: &str) -> bool {
+    Path::new(name).components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+pub(crate) fn save(part_path: &Path, path: &Path, expected: u64, received: u64, hash: Option<Digest>) -> io::Result<()> {
+    if received != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("expected {expected} bytes, received {received}")));
+    }
+    if let Some(hash) = hash {
+        let actual = digest(&mut File::open(part_path)?)?;
+        if actual != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                "checksum mismatch, expected {} but got {}", to_hex(&hash), to_hex(&actual)
+            )));
+        }
+    }
+    fs::rename(part_path, path)
+}
+
+/// Connects to `addr`, downloads the files named in `selections` into `dest_dir` and
+/// returns once every one of them completed or failed. Names the server doesn't serve
+/// fail without being requested. Errors of the connection itself abort the download.
+pub fn download_all(addr: &str, selections: &[&str], dest_dir: &Path, on_event: &mut dyn FnMut(ProgressEvent)) -> Result<Summary> {
+    let mut stream = Stream::connect(addr)?;
+    download_over(&mut stream, selections, dest_dir, on_event)
+}
+
+/// Like `download_all`, over an already connected stream.
+pub fn download_over<S: Read + Write>(stream: &mut S, selections: &[&str], dest_dir: &Path, on_event: &mut dyn FnMut(ProgressEvent)) -> Result<Summary> {
+    stream.write_all(&[Codec::None as u8])?;
+    let mut codec = [0; 1];
+    stream.read_exact(&mut codec)?;
+    let codec = Codec::from_id(codec[0]).ok_or(Error::Protocol("server picked an unknown codec"))?;
+
+    let offered = protocol::FEATURE_HASHES;
+    protocol::send_features(stream, offered)?;
+    let features = protocol::recv_features(stream)?;
+    if features & protocol::REJECTED != 0 {
+        return Err(Error::MissingFeatures(features & !protocol::REJECTED));
+    }
+    let features = features & offered;
+
+    let files = FileList::recv(stream)?;
+    let hashes = if features & protocol::FEATURE_HASHES != 0 {
+        HashList::recv(stream)?
+    } else {
+        vec![None; files.len()].into()
+    };
+    if hashes.len() != files.len() {
+        return Err(Error::InvalidData("hash list doesn't match the file list".into()));
+    }
+
+    let mut summary = Summary::default();
+    let mut priorities = priority_list::new(files.len());
+    for name in selections {
+        match files.iter().position(|(served, _)| served.as_ref() == *name) {
+            Some(idx) if is_safe_name(name) => priorities[idx] = 1,
+            _ => {
+                let err = io::Error::new(io::ErrorKind::NotFound, "not served");
+                on_event(ProgressEvent::Failed { name, err: &err });
+                summary.failed.push((name.to_string(), err));
+            },
+        }
+    }
+
+    let ranges: RangeList = vec![(0, RANGE_TO_END); files.len()].into();
+    ranges.send(stream)?;
+    let mut to_download = priorities.iter().filter(|priority| **priority != 0).count();
+    if to_download == 0 {
+        return Ok(summary);
+    }
+    priority_list::send(stream, &priorities)?;
+
+    let paths: Box<[PathBuf]> = files.iter().map(|(name, _)| dest_dir.join(name.as_ref())).collect();
+    let mut handlers = initialize_handlers::<File>(files.len());
+    let mut received = vec![0; files.len()];
+    let mut errors: Vec<Option<io::Error>> = files.iter().map(|_| None).collect();
+    for idx in (0..files.len()).filter(|idx| priorities[*idx] != 0) {
+        let (name, size) = &files[idx];
+        on_event(ProgressEvent::Started { name, size: *size });
+    }
+
+    while to_download > 0 {
+        for idx in 0..files.len() {
+            if priorities[idx] == 0 || handlers[idx].done {
+                continue;
+            }
+            let name = &files[idx].0;
+            let mut part_path = paths[idx].clone().into_os_string();
+            part_path.push(".part");
+            let part_path = PathBuf::from(part_path);
+
+            if handlers[idx].file.is_none() && errors[idx].is_none() {
+                let created = paths[idx].parent().map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| File::create(&part_path));
+                match created {
+                    Ok(file) => handlers[idx].file = Some(file),
+                    Err(err) => errors[idx] = Some(err),
+                }
+            }
+
+            for _ in 0..priorities[idx] {
+                let chunk = Chunk::recv_with(stream, codec)?;
+                received[idx] += chunk.len as u64;
+                let end = chunk.end();
+                let handler = &mut handlers[idx];
+                // The rest of a file that can't be written is still received, to keep
+                // reading the chunks of the others.
+                if let Some(file) = &mut handler.file {
+                    if let Err(err) = chunk.write(file) {
+                        handler.file = None;
+                        errors[idx] = Some(err);
+                    }
+                }
+                if end {
+                    handler.done = true;
+                    break;
+                }
+            }
+            on_event(ProgressEvent::Progress { name, received: received[idx] });
+            if !handlers[idx].done {
+                continue;
+            }
+
+            to_download -= 1;
+            drop(handlers[idx].file.take());
+            let saved = match errors[idx].take() {
+                Some(err) => Err(err),
+                None => save(&part_path, &paths[idx], files[idx].1, received[idx], hashes[idx]),
+            };
+            match saved {
+                Ok(()) => {
+                    on_event(ProgressEvent::Completed { name });
+                    summary.completed.push(name.to_string());
+                },
+                Err(err) => {
+                    on_event(ProgressEvent::Failed { name, err: &err });
+                    summary.failed.push((name.to_string(), err));
+                },
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    /// Replays what a server sent and keeps what the client sends.
+    struct Replay {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Replay {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Replay {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejection_reports_the_missing_features() {
+        let mut input = vec![Codec::None as u8];
+        protocol::send_features(&mut input, protocol::REJECTED | protocol::FEATURE_RESTART).unwrap();
+        let mut stream = Replay { input: Cursor::new(input), output: Vec::new() };
+
+        let result = download_over(&mut stream, &["a"], Path::new("."), &mut |_| {});
+        assert!(matches!(result, Err(Error::MissingFeatures(protocol::FEATURE_RESTART))), "{result:?}");
+    }
+}
@@ -1,52 +1,412 @@
-use std::{fs::File, io::{self, Read, Write}, mem, str};
+use std::{error, fmt, fs::File, io::{self, Read, Write}, mem, net::{SocketAddr, TcpStream}, ops::RangeInclusive, result, str, time::Duration};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use sha2::{Digest as _, Sha256};
+
+pub mod download;
+#[cfg(feature = "quic")]
+pub mod quic;
+
+/// Why a connection or one of its packets failed, so that callers can tell a broken
+/// socket apart from a peer that doesn't follow the protocol.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The peer sent something the protocol doesn't allow, such as an unknown message kind.
+    Protocol(&'static str),
+    /// A packet or file is well-formed, but its content doesn't add up.
+    InvalidData(String),
+    /// Written by a newer version than this one, which only understands up to `supported`.
+    VersionMismatch { found: u32, supported: u32 },
+    /// The server refused a client that doesn't support these `protocol::FEATURE_*`.
+    MissingFeatures(u32),
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => err.fmt(f),
+            Error::Protocol(msg) => write!(f, "protocol error: {msg}"),
+            Error::InvalidData(msg) => f.write_str(msg),
+            Error::VersionMismatch { found, supported } => write!(f, "unsupported version {found}, expected up to {supported}"),
+            Error::MissingFeatures(missing) => {
+                write!(f, "the server requires features the client lacks: {}", protocol::feature_names(*missing).join(", "))
+            },
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Reads `len` bytes, growing the buffer as they arrive instead of trusting a length sent
+/// by the peer with one allocation up front.
+fn read_bytes<T: Read>(stream: &mut T, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    stream.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(buf)
+}
 
 pub trait Packet {
     fn send<T: Write>(&self, stream: &mut T) -> io::Result<()>;
-    fn recv<T: Read>(stream: &mut T) -> io::Result<Self> where Self: Sized;
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> where Self: Sized;
 }
 
 pub type FileList = Box<[(Box<str>, u64)]>;
 
 impl Packet for FileList {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        send_file_list(stream, self, false)
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        recv_file_list(stream, false)
+    }
+}
+
+/// Sends `files` like `FileList::send`, or with the names compressed with zstd when
+/// `compressed` is set, as negotiated with `protocol::FEATURE_COMPRESSED_LIST`.
+pub fn send_file_list<T: Write>(stream: &mut T, files: &FileList, compressed: bool) -> io::Result<()> {
+    stream.write_all(&files.len().to_be_bytes())?;
+    for (_, size) in files.iter() {
+        stream.write_all(&size.to_be_bytes())?;
+    }
+
+    let names = files.iter()
+        .fold(String::new(), |a, (name, _)| a + "\0" + name);
+
+    // Every name is preceded by a separator, but only the ones between names are sent.
+    let bytes = names.strip_prefix('\0').unwrap_or(&names).as_bytes();
+    stream.write_all(&bytes.len().to_be_bytes())?;
+    if compressed {
+        let compressed = zstd::bulk::compress(bytes, Codec::Zstd.default_level())?;
+        stream.write_all(&compressed.len().to_be_bytes())?;
+        return stream.write_all(&compressed);
+    }
+    stream.write_all(bytes)
+}
+
+/// Receives a list sent by `send_file_list`.
+pub fn recv_file_list<T: Read>(stream: &mut T, compressed: bool) -> Result<FileList> {
+    let len = {
+        let mut buf = [0; mem::size_of::<usize>()];
+        stream.read_exact(&mut buf)?;
+        usize::from_be_bytes(buf)
+    };
+
+    let sizes_len = len.checked_mul(mem::size_of::<u64>())
+        .ok_or(Error::Protocol("file list is too long"))?;
+    let buf = read_bytes(stream, sizes_len)?;
+    let filesizes = buf.chunks(mem::size_of::<u64>())
+        .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()));
+
+    let names_size = {
+        let mut buf = [0; mem::size_of::<usize>()];
+        stream.read_exact(&mut buf)?;
+        usize::from_be_bytes(buf)
+    };
+
+    let buf = if compressed {
+        let compressed_size = {
+            let mut buf = [0; mem::size_of::<usize>()];
+            stream.read_exact(&mut buf)?;
+            usize::from_be_bytes(buf)
+        };
+        // Decompressed a piece at a time, so that a bogus size can't allocate much.
+        let compressed = read_bytes(stream, compressed_size)?;
+        let mut decoder = zstd::stream::read::Decoder::new(compressed.as_slice())?;
+        let buf = read_bytes(&mut decoder, names_size)
+            .map_err(|err| Error::InvalidData(format!("compressed file names don't decompress to {names_size} bytes: {err}")))?;
+        if decoder.read(&mut [0])? != 0 {
+            return Err(Error::InvalidData("compressed file names are longer than advertised".into()));
+        }
+        buf
+    } else {
+        read_bytes(stream, names_size)?
+    };
+
+    let names = str::from_utf8(&buf)
+        .map_err(|err| Error::InvalidData(format!("file names aren't UTF-8: {err}")))?;
+    let filenames: Vec<Box<str>> = if names.is_empty() && len == 0 {
+        Vec::new()
+    } else {
+        names.split('\0').map(|name| name.into()).collect()
+    };
+
+    if filenames.len() != len {
+        return Err(Error::InvalidData(format!(
+            "file list advertises {len} files but carries {} names", filenames.len()
+        )));
+    }
+
+    Ok(filenames.into_iter().zip(filesizes).collect())
+}
+
+/// SHA-256 digest of a file's content.
+pub type Digest = [u8; 32];
+
+/// Digest of every advertised file, in `FileList` order, if the server computed it.
+pub type HashList = Box<[Option<Digest>]>;
+
+impl Packet for HashList {
     fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
         stream.write_all(&self.len().to_be_bytes())?;
-        for (_, size) in self.iter() {
-            stream.write(&size.to_be_bytes())?;
+        for hash in self.iter() {
+            match hash {
+                Some(digest) => {
+                    stream.write_all(&[1])?;
+                    stream.write_all(digest)?;
+                },
+                None => stream.write_all(&[0])?,
+            }
         }
+        Ok(())
+    }
 
-        let names = self.iter()
-            .fold(String::new(), |a, (name, _)| a + "\0" + name);
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        let len = {
+            let mut buf = [0; mem::size_of::<usize>()];
+            stream.read_exact(&mut buf)?;
+            usize::from_be_bytes(buf)
+        };
 
-        let bytes = &names.as_bytes()[1..];
-        stream.write_all(&bytes.len().to_be_bytes())?;
-        stream.write_all(&bytes)
+        (0..len).map(|_| {
+            let mut present = [0; 1];
+            stream.read_exact(&mut present)?;
+            match present[0] {
+                0 => Ok(None),
+                1 => {
+                    let mut digest = [0; 32];
+                    stream.read_exact(&mut digest)?;
+                    Ok(Some(digest))
+                },
+                _ => Err(Error::Protocol("bad hash list entry")),
+            }
+        }).collect()
     }
+}
 
-    fn recv<T: Read>(stream: &mut T) -> io::Result<Self> {
+pub fn digest<T: Read + ?Sized>(reader: &mut T) -> io::Result<Digest> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => hasher.update(&buf[..read]),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(hasher.finalize().into())
+}
+
+pub fn to_hex(digest: &Digest) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Digest> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, pair) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Attributes of every advertised file, in `FileList` order, as a set of `FLAG_*` bits.
+pub type FlagList = Box<[u8]>;
+
+/// The file is executable, at least by its owner.
+pub const FLAG_EXECUTABLE: u8 = 1;
+
+impl Packet for FlagList {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        stream.write_all(&self.len().to_be_bytes())?;
+        stream.write_all(self)
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
         let len = {
             let mut buf = [0; mem::size_of::<usize>()];
             stream.read_exact(&mut buf)?;
             usize::from_be_bytes(buf)
         };
 
-        let mut buf = vec![0; len * mem::size_of::<u64>()];
-        stream.read_exact(&mut buf)?;
-        let filesizes = buf.chunks(mem::size_of::<u64>())
-            .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()));
+        Ok(read_bytes(stream, len)?.into())
+    }
+}
+
+/// The priority the server recommends for every advertised file, in `FileList` order, 0
+/// for files it has no opinion about. Sent like a `FlagList`.
+pub type HintList = Box<[u8]>;
 
-        let names_size = {
+/// When every advertised file was last modified, in `FileList` order, as seconds since
+/// the Unix epoch, if the server knows it.
+pub type MtimeList = Box<[Option<u64>]>;
+
+impl Packet for MtimeList {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        stream.write_all(&self.len().to_be_bytes())?;
+        for mtime in self.iter() {
+            match mtime {
+                Some(mtime) => {
+                    stream.write_all(&[1])?;
+                    stream.write_all(&mtime.to_be_bytes())?;
+                },
+                None => stream.write_all(&[0])?,
+            }
+        }
+        Ok(())
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        let len = {
             let mut buf = [0; mem::size_of::<usize>()];
             stream.read_exact(&mut buf)?;
             usize::from_be_bytes(buf)
         };
 
-        let mut buf = vec![0; names_size];
-        stream.read_exact(&mut buf)?;
+        (0..len).map(|_| {
+            let mut present = [0; 1];
+            stream.read_exact(&mut present)?;
+            match present[0] {
+                0 => Ok(None),
+                1 => {
+                    let mut mtime = [0; mem::size_of::<u64>()];
+                    stream.read_exact(&mut mtime)?;
+                    Ok(Some(u64::from_be_bytes(mtime)))
+                },
+                _ => Err(Error::Protocol("bad mtime list entry")),
+            }
+        }).collect()
+    }
+}
+
+/// What the server knows about one file, the answer to a `protocol::STAT` message.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stat {
+    pub size: u64,
+    /// Only known if the server computed it before being asked.
+    pub digest: Option<Digest>,
+    pub mtime: Option<u64>,
+}
+
+const STAT_DIGEST: u8 = 1 << 0;
+const STAT_MTIME: u8 = 1 << 1;
+
+impl Packet for Stat {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        let mut present = 0;
+        if self.digest.is_some() {
+            present |= STAT_DIGEST;
+        }
+        if self.mtime.is_some() {
+            present |= STAT_MTIME;
+        }
+        stream.write_all(&self.size.to_be_bytes())?;
+        stream.write_all(&[present])?;
+        if let Some(digest) = &self.digest {
+            stream.write_all(digest)?;
+        }
+        if let Some(mtime) = self.mtime {
+            stream.write_all(&mtime.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        let mut size = [0; mem::size_of::<u64>()];
+        stream.read_exact(&mut size)?;
+        let mut present = [0; 1];
+        stream.read_exact(&mut present)?;
+        if present[0] & !(STAT_DIGEST | STAT_MTIME) != 0 {
+            return Err(Error::Protocol("bad stat entry"));
+        }
+
+        let digest = if present[0] & STAT_DIGEST != 0 {
+            let mut digest = [0; 32];
+            stream.read_exact(&mut digest)?;
+            Some(digest)
+        } else {
+            None
+        };
+        let mtime = if present[0] & STAT_MTIME != 0 {
+            let mut mtime = [0; mem::size_of::<u64>()];
+            stream.read_exact(&mut mtime)?;
+            Some(u64::from_be_bytes(mtime))
+        } else {
+            None
+        };
+        Ok(Stat { size: u64::from_be_bytes(size), digest, mtime })
+    }
+}
+
+/// The byte range `start..end` of every file the client wants. `end` may be
+/// `RANGE_TO_END` to read until the end of the file.
+pub type RangeList = Box<[(u64, u64)]>;
+
+pub const RANGE_TO_END: u64 = u64::MAX;
+
+impl Packet for RangeList {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        stream.write_all(&self.len().to_be_bytes())?;
+        for (start, end) in self.iter() {
+            stream.write_all(&start.to_be_bytes())?;
+            stream.write_all(&end.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        let len = {
+            let mut buf = [0; mem::size_of::<usize>()];
+            stream.read_exact(&mut buf)?;
+            usize::from_be_bytes(buf)
+        };
 
-        let filenames = str::from_utf8(&buf).unwrap()
-            .splitn(len, '\0').map(|name| name.into());
+        let ranges_len = len.checked_mul(2 * mem::size_of::<u64>())
+            .ok_or(Error::Protocol("range list is too long"))?;
+        let buf = read_bytes(stream, ranges_len)?;
+        let ranges: RangeList = buf.chunks(2 * mem::size_of::<u64>())
+            .map(|bytes| {
+                let (start, end) = bytes.split_at(mem::size_of::<u64>());
+                (u64::from_be_bytes(start.try_into().unwrap()), u64::from_be_bytes(end.try_into().unwrap()))
+            })
+            .collect();
 
-        Ok(filenames.zip(filesizes).collect())
+        if ranges.iter().any(|(start, end)| start > end) {
+            return Err(Error::InvalidData("range ends before it starts".into()));
+        }
+        Ok(ranges)
     }
 }
 
@@ -56,35 +416,149 @@ pub struct Chunk {
 }
 
 impl Chunk {
+    pub fn empty() -> Self {
+        Chunk { len: 0, buf: [0; 1024] }
+    }
+
     pub fn end(&self) -> bool {
         self.len < 1024
     }
-    pub fn read<T: Read>(file: &mut T) -> io::Result<Self> {
+
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+    pub fn read<T: Read + ?Sized>(file: &mut T) -> io::Result<Self> {
         let mut buf = [0; 1024];
-        let len = file.read(&mut buf)?;
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(read) => len += read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
         Ok(Chunk {len, buf})
     }
 
-    pub fn write<T: Write>(mut self, file: &mut T) -> io::Result<bool> {
-        file.write_all(&mut self.buf[..self.len])?;
+    pub fn write<T: Write + ?Sized>(self, file: &mut T) -> io::Result<bool> {
+        file.write_all(&self.buf[..self.len])?;
         Ok(self.end())
     }
 }
 
-impl Packet for Chunk {
-    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
-        let header = if self.end() { (1 << 15) | self.len as u16 } else { 0 };
-        stream.write_all(&header.to_be_bytes())?;
-        stream.write_all(&self.buf[..self.len])
+/// Per-chunk compression, negotiated once per connection.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Codec {
+    None = 0,
+    Deflate = 1,
+    Zstd = 2,
+}
+
+impl Codec {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Deflate),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none"    => Some(Codec::None),
+            "deflate" => Some(Codec::Deflate),
+            "zstd"    => Some(Codec::Zstd),
+            _         => None,
+        }
     }
 
-    fn recv<T: Read>(stream: &mut T) -> io::Result<Self> {
+    pub fn default_level(self) -> i32 {
+        match self {
+            Codec::None    => 0,
+            Codec::Deflate => 6,
+            Codec::Zstd    => 3,
+        }
+    }
+
+    /// The levels the codec compresses at, others are clamped to these.
+    pub fn levels(self) -> RangeInclusive<i32> {
+        match self {
+            Codec::None    => 0..=0,
+            Codec::Deflate => 0..=9,
+            Codec::Zstd    => zstd::compression_level_range(),
+        }
+    }
+
+    fn compress(self, data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Deflate => {
+                let level = flate2::Compression::new(level.clamp(0, 9) as u32);
+                let mut encoder = DeflateEncoder::new(Vec::new(), level);
+                encoder.write_all(data)?;
+                encoder.finish()
+            },
+            Codec::Zstd => zstd::bulk::compress(data, level),
+        }
+    }
+
+    fn decompress(self, data: &[u8], out: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Codec::None => {
+                out[..data.len()].copy_from_slice(data);
+                Ok(data.len())
+            },
+            Codec::Deflate => {
+                let mut decoder = DeflateDecoder::new(data);
+                let mut len = 0;
+                loop {
+                    match decoder.read(&mut out[len..])? {
+                        0 => break Ok(len),
+                        read => len += read,
+                    }
+                }
+            },
+            Codec::Zstd => zstd::bulk::decompress_to_buffer(data, out),
+        }
+    }
+}
+
+const CHUNK_END: u16 = 1 << 15;
+const CHUNK_COMPRESSED: u16 = 1 << 14;
+
+impl Chunk {
+    /// Sends the chunk compressed with `codec`, falling back to the raw bytes when
+    /// compression doesn't make it smaller.
+    pub fn send_with<T: Write>(&self, stream: &mut T, codec: Codec, level: i32) -> io::Result<()> {
+        let header = if self.end() { CHUNK_END | self.len as u16 } else { 0 };
+        // The whole frame goes out in one write, rather than a syscall for the header and
+        // another for the payload on unbuffered streams.
+        let mut frame = [0; 2 * mem::size_of::<u16>() + 1024];
+
+        if codec != Codec::None {
+            let compressed = codec.compress(&self.buf[..self.len], level)?;
+            if compressed.len() < self.len {
+                frame[..2].copy_from_slice(&(header | CHUNK_COMPRESSED).to_be_bytes());
+                frame[2..4].copy_from_slice(&(compressed.len() as u16).to_be_bytes());
+                frame[4..4 + compressed.len()].copy_from_slice(&compressed);
+                return stream.write_all(&frame[..4 + compressed.len()]);
+            }
+        }
+
+        frame[..2].copy_from_slice(&header.to_be_bytes());
+        frame[2..2 + self.len].copy_from_slice(&self.buf[..self.len]);
+        stream.write_all(&frame[..2 + self.len])
+    }
+
+    pub fn recv_with<T: Read>(stream: &mut T, codec: Codec) -> Result<Self> {
         let header = {
             let mut buf = [0; mem::size_of::<u16>()];
             stream.read_exact(&mut buf)?;
             u16::from_be_bytes(buf)
         };
-        let end = (header >> 15) != 0;
+        let end = (header & CHUNK_END) != 0;
         let mut buf = [0; 1024];
         let len = if end {
             (header as usize) & 0x3ff
@@ -92,37 +566,636 @@ impl Packet for Chunk {
             1024
         };
 
-        stream.read_exact(&mut buf[..len])?;
+        if header & CHUNK_COMPRESSED == 0 {
+            stream.read_exact(&mut buf[..len])?;
+            return Ok(Chunk { len, buf });
+        }
+
+        if codec == Codec::None {
+            return Err(Error::Protocol("received a compressed chunk without a negotiated codec"));
+        }
+
+        let compressed_len = {
+            let mut buf = [0; mem::size_of::<u16>()];
+            stream.read_exact(&mut buf)?;
+            u16::from_be_bytes(buf) as usize
+        };
+        if compressed_len > buf.len() {
+            return Err(Error::Protocol("compressed chunk is too large"));
+        }
+
+        let mut compressed = [0; 1024];
+        stream.read_exact(&mut compressed[..compressed_len])?;
+        if codec.decompress(&compressed[..compressed_len], &mut buf[..len])? != len {
+            return Err(Error::Protocol("compressed chunk has the wrong length"));
+        }
         Ok(Chunk { len, buf })
     }
 }
 
-pub struct DownloadableFile {
+impl Packet for Chunk {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        self.send_with(stream, Codec::None, 0)
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        Self::recv_with(stream, Codec::None)
+    }
+}
+
+/// Receives lifecycle events from a running transfer.
+///
+/// Calls for a given file are always ordered: `on_start` once, before the first chunk of
+/// the file is received, then any number of `on_progress` calls (at most one per
+/// scheduling round) carrying the total bytes received so far, then either `on_complete`
+/// or `on_failed` once. A file that is downloaded again gets `on_retry` instead, and its
+/// `on_progress` calls start over from zero. Events of different files interleave in the
+/// order the server schedules them. `on_error` is called at most once, when the transfer
+/// is aborted, and no further events follow it.
+pub trait TransferObserver {
+    fn on_start(&mut self, idx: usize, name: &str, size: u64);
+    fn on_progress(&mut self, idx: usize, received: u64);
+    fn on_complete(&mut self, idx: usize);
+    fn on_failed(&mut self, idx: usize, err: &io::Error);
+    fn on_retry(&mut self, idx: usize, err: &io::Error, attempt: u32);
+    fn on_error(&mut self, err: &Error);
+}
+
+pub struct DownloadableFile<F = File> {
     pub done: bool,
-    pub file: Option<File>
+    pub file: Option<F>
 }
 
-pub fn initialize_handlers(len: usize) -> Box<[DownloadableFile]> {
+pub fn initialize_handlers<F>(len: usize) -> Box<[DownloadableFile<F>]> {
     std::iter::repeat_with(|| DownloadableFile { done: false, file: None })
         .take(len).collect()
 }
 
+/// Extends the per-file `list` to `len` entries made by `value`, for files that are
+/// served after the others.
+pub fn grow<T>(list: &mut Box<[T]>, len: usize, value: impl FnMut() -> T) {
+    let mut grown = mem::take(list).into_vec();
+    grown.resize_with(len, value);
+    *list = grown.into();
+}
+
+/// A connection between a client and the server, over TCP or a Unix domain socket.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to `addr`, which is either `host:port` or `unix://path`.
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        match addr.strip_prefix("unix://") {
+            #[cfg(unix)]
+            Some(path) => UnixStream::connect(path).map(Stream::Unix),
+            #[cfg(not(unix))]
+            Some(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets aren't supported here")),
+            None => TcpStream::connect(addr).map(Stream::Tcp),
+        }
+    }
+
+    /// The address of the other end, unknown for Unix domain sockets.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Sends small writes right away over TCP instead of gathering them. Unix domain
+    /// sockets never hold them back.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Kinds of the messages a client sends between scheduling rounds. Each message starts
+/// with one of these bytes.
+pub mod protocol {
+    use std::{io::{self, Read, Write}, str};
+    use crate::{read_bytes, Error, Result};
+
+    /// Followed by the length of the priority list and the list itself. The server then
+    /// sends round after round without waiting for the client, until every requested file
+    /// is finished, so the connection stays busy without any requests in flight. With
+    /// `FEATURE_WINDOW` it only gets that many rounds ahead of the client's `ACK`s.
+    pub const PRIORITIES: u8 = 0;
+    /// Asks the server to answer with a single `PONG` byte.
+    pub const PING: u8 = 1;
+    pub const PONG: u8 = 2;
+    /// Followed by a file index as a big-endian `u64`. The server forgets it sent that
+    /// file and serves it again from the first byte once it's requested next.
+    pub const RESTART: u8 = 3;
+    /// Asks for the files the server started serving since the client last learned about
+    /// them. The server answers with a `FileList` of just those, followed by their
+    /// `FlagList` when `FEATURE_FLAGS` is used and their `MtimeList` when `FEATURE_MTIMES`
+    /// is. They are numbered after the known ones.
+    pub const LIST: u8 = 4;
+    /// Asks for the digests of every file the client knows about, computed then if the
+    /// server doesn't keep them. The server answers with a `HashList`.
+    pub const HASHES: u8 = 5;
+    /// Followed by a file index as a big-endian `u64`. The server answers with the `Stat`
+    /// of the file, without sending any of its content.
+    pub const STAT: u8 = 6;
+    /// Followed by the sequence number of a round as a big-endian `u64`, once the client
+    /// received all of it. Rounds are numbered from 1 on every connection.
+    pub const ACK: u8 = 7;
+
+    /// Optional parts of the protocol. Right after the codec is agreed on, the client and
+    /// then the server send the `u32` set of features they support, and only the ones both
+    /// of them support are used. A peer sending 0 speaks the base protocol.
+    ///
+    /// The server sends the `HashList` after the `FileList`.
+    pub const FEATURE_HASHES: u32 = 1 << 0;
+    /// The server sends the `FlagList` after the `HashList`, or the `FileList` without it.
+    pub const FEATURE_FLAGS: u32 = 1 << 1;
+    /// The server understands `RESTART` messages.
+    pub const FEATURE_RESTART: u32 = 1 << 2;
+    /// The server sends the `HintList` after the last of the lists above it sends.
+    pub const FEATURE_HINTS: u32 = 1 << 3;
+    /// The server understands `LIST` messages.
+    pub const FEATURE_APPEND: u32 = 1 << 4;
+    /// The server understands `HASHES` messages.
+    pub const FEATURE_HASH_REQUEST: u32 = 1 << 5;
+    /// The server sends the `MtimeList` after the last of the lists above it sends.
+    pub const FEATURE_MTIMES: u32 = 1 << 6;
+    /// The client sends the directory it wants the files of right after the features, and
+    /// the server only advertises the files under it, numbered among themselves.
+    pub const FEATURE_SUBPATH: u32 = 1 << 7;
+    /// The server sends the most chunks it sends in a round as a big-endian `u32` after the
+    /// last of the lists above it sends, and both sides scale every round down to it with
+    /// `priority_list::limit_round`.
+    pub const FEATURE_ROUND_BUDGET: u32 = 1 << 8;
+    /// The server understands `STAT` messages.
+    pub const FEATURE_STAT: u32 = 1 << 9;
+    /// Every `FileList` is sent with `send_file_list`, its names compressed.
+    pub const FEATURE_COMPRESSED_LIST: u32 = 1 << 10;
+    /// The client sends how many rounds the server may send ahead of its `ACK`s as a
+    /// big-endian `u32` after the features, or the subpath if it sends one, and then
+    /// acknowledges every round it receives.
+    pub const FEATURE_WINDOW: u32 = 1 << 11;
+
+    /// Sent by load balancers instead of a codec to check that the server is alive, if it
+    /// answers them. The server replies with `OK <idle workers> <workers>\n` and closes the
+    /// connection. No codec id starts like it.
+    pub const HEALTH_PROBE: &[u8] = b"HEALTH";
+
+    /// Sent by the server instead of its features, along with the ones it requires that the
+    /// client didn't offer, when it refuses the client. The connection is closed after it.
+    pub const REJECTED: u32 = 1 << 31;
+
+    /// The name of every feature, as operators write them.
+    const FEATURE_NAMES: [(u32, &str); 12] = [
+        (FEATURE_HASHES, "hashes"),
+        (FEATURE_FLAGS, "flags"),
+        (FEATURE_RESTART, "restart"),
+        (FEATURE_HINTS, "hints"),
+        (FEATURE_APPEND, "append"),
+        (FEATURE_HASH_REQUEST, "hash-request"),
+        (FEATURE_MTIMES, "mtimes"),
+        (FEATURE_SUBPATH, "subpath"),
+        (FEATURE_ROUND_BUDGET, "round-budget"),
+        (FEATURE_STAT, "stat"),
+        (FEATURE_COMPRESSED_LIST, "compressed-list"),
+        (FEATURE_WINDOW, "window"),
+    ];
+
+    pub fn feature_from_name(name: &str) -> Option<u32> {
+        FEATURE_NAMES.iter().find(|(_, known)| *known == name).map(|(feature, _)| *feature)
+    }
+
+    /// Names the features in `features`, and the bits of the unknown ones.
+    pub fn feature_names(features: u32) -> Vec<String> {
+        (0..u32::BITS).map(|bit| 1 << bit)
+            .filter(|feature| features & feature != 0)
+            .map(|feature| match FEATURE_NAMES.iter().find(|(known, _)| *known == feature) {
+                Some((_, name)) => name.to_string(),
+                None => format!("bit {}", feature.trailing_zeros()),
+            })
+            .collect()
+    }
+
+    /// Every feature this version knows about.
+    pub const FEATURES: u32 = FEATURE_HASHES | FEATURE_FLAGS | FEATURE_RESTART | FEATURE_HINTS | FEATURE_APPEND | FEATURE_HASH_REQUEST | FEATURE_MTIMES | FEATURE_SUBPATH | FEATURE_ROUND_BUDGET | FEATURE_STAT | FEATURE_COMPRESSED_LIST | FEATURE_WINDOW;
+
+    pub fn send_features<T: Write>(stream: &mut T, features: u32) -> io::Result<()> {
+        stream.write_all(&features.to_be_bytes())
+    }
+
+    pub fn recv_features<T: Read>(stream: &mut T) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Sent as a big-endian `u16` length followed by the UTF-8 path, using `/` between
+    /// directories like the names in the `FileList`.
+    pub fn send_subpath<T: Write>(stream: &mut T, path: &str) -> Result<()> {
+        let len = u16::try_from(path.len()).map_err(|_| Error::Protocol("subpath is too long"))?;
+        stream.write_all(&len.to_be_bytes())?;
+        stream.write_all(path.as_bytes())?;
+        Ok(())
+    }
+
+    /// Turns a subpath into the directory prefix of the names under it. Only relative paths
+    /// without `.` or `..` are taken, so that a subpath names what it looks like rather than
+    /// something outside the served files.
+    pub fn check_subpath(path: &str) -> Option<&str> {
+        let path = path.trim_end_matches('/');
+        let valid = !path.is_empty()
+            && !path.contains('\\')
+            && path.split('/').all(|part| !matches!(part, "" | "." | ".."));
+        valid.then_some(path)
+    }
+
+    pub fn recv_subpath<T: Read>(stream: &mut T) -> Result<String> {
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf)?;
+        let buf = read_bytes(stream, u16::from_be_bytes(buf) as usize)?;
+        String::from_utf8(buf).map_err(|err| Error::InvalidData(format!("subpath isn't UTF-8: {err}")))
+    }
+
+    pub fn send_round_budget<T: Write>(stream: &mut T, budget: u32) -> io::Result<()> {
+        stream.write_all(&budget.to_be_bytes())
+    }
+
+    pub fn recv_round_budget<T: Read>(stream: &mut T) -> Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf)?;
+        match u32::from_be_bytes(buf) {
+            0 => Err(Error::Protocol("round budget is zero")),
+            budget => Ok(budget),
+        }
+    }
+
+    pub fn send_window<T: Write>(stream: &mut T, window: u32) -> io::Result<()> {
+        stream.write_all(&window.to_be_bytes())
+    }
+
+    pub fn recv_window<T: Read>(stream: &mut T) -> Result<u32> {
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf)?;
+        match u32::from_be_bytes(buf) {
+            0 => Err(Error::Protocol("window is zero")),
+            window => Ok(window),
+        }
+    }
+
+    pub fn send_ack<T: Write>(stream: &mut T, seq: u64) -> io::Result<()> {
+        stream.write_all(&[ACK])?;
+        stream.write_all(&seq.to_be_bytes())
+    }
+
+    /// The rounds a server sent with `FEATURE_WINDOW` that the client didn't acknowledge.
+    pub struct Window {
+        size: u64,
+        sent: u64,
+        acked: u64,
+    }
+
+    impl Window {
+        pub fn new(size: u32) -> Self {
+            Self { size: size as u64, sent: 0, acked: 0 }
+        }
+
+        /// Whether another round may be sent before the next `ACK`.
+        pub fn is_open(&self) -> bool {
+            self.sent - self.acked < self.size
+        }
+
+        /// Numbers the round about to be sent.
+        pub fn send(&mut self) -> u64 {
+            self.sent += 1;
+            self.sent
+        }
+
+        /// Takes the `ACK` of round `seq`, which covers the rounds before it too.
+        pub fn ack(&mut self, seq: u64) -> Result<()> {
+            if seq <= self.acked || seq > self.sent {
+                return Err(Error::Protocol("acknowledged a round that isn't in flight"));
+            }
+            self.acked = seq;
+            Ok(())
+        }
+    }
+
+    pub fn send_restart<T: Write>(stream: &mut T, idx: usize) -> io::Result<()> {
+        stream.write_all(&[RESTART])?;
+        stream.write_all(&(idx as u64).to_be_bytes())
+    }
+
+    pub fn send_stat<T: Write>(stream: &mut T, idx: usize) -> io::Result<()> {
+        stream.write_all(&[STAT])?;
+        stream.write_all(&(idx as u64).to_be_bytes())
+    }
+
+    /// Receives the file index that follows a `RESTART` or `STAT` message, or the sequence
+    /// number of an `ACK`.
+    pub fn recv_index<T: Read>(stream: &mut T) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        stream.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+}
+
+/// Announcements servers broadcast over UDP so that clients on the local network can find
+/// them without being told the address.
+pub mod discovery {
+    pub const PORT: u16 = 3001;
+
+    const MAGIC: &[u8] = b"SOCKET-PROJECT";
+
+    /// An announcement is the magic followed by the TCP port the server listens on. The
+    /// address is the one the datagram came from.
+    pub fn encode(port: u16) -> Vec<u8> {
+        [MAGIC, &port.to_be_bytes()].concat()
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<u16> {
+        let port = packet.strip_prefix(MAGIC)?;
+        Some(u16::from_be_bytes(port.try_into().ok()?))
+    }
+}
+
 pub mod priority_list {
+    use std::{io::{self, Read, Write}, mem};
+    use crate::{protocol, Error, Result};
+
     pub fn new(len: usize) -> Box<[u8]> {
         vec![0; len].into()
     }
 
+    /// Parses a priority as written in input files, either a name or a share of the
+    /// bandwidth such as `30%`. Priorities are chunks per round, so a share of `n%` is `n`
+    /// of them: files given shares split the bandwidth in their ratio whatever they add up
+    /// to, and `NORMAL`, `HIGH` and `CRITICAL` weigh like 1%, 4% and 10% next to them.
+    pub fn parse(name: &str) -> Option<u8> {
+        match name {
+            "NORMAL"   => Some(1),
+            "HIGH"     => Some(4),
+            "CRITICAL" => Some(10),
+            _          => name.strip_suffix('%')?.parse().ok().filter(|share| (1..=100).contains(share)),
+        }
+    }
+
+    /// Sends `priorities` as a `PRIORITIES` message.
+    pub fn send<T: Write>(stream: &mut T, priorities: &[u8]) -> io::Result<()> {
+        stream.write_all(&[protocol::PRIORITIES])?;
+        stream.write_all(&priorities.len().to_be_bytes())?;
+        stream.write_all(priorities)
+    }
+
+    /// Receives the rest of a `PRIORITIES` message into `out`. A list of another length
+    /// is an error, since the peers disagree on the files being served.
+    pub fn recv_into<T: Read>(stream: &mut T, out: &mut [u8]) -> Result<()> {
+        let len = {
+            let mut buf = [0; mem::size_of::<usize>()];
+            stream.read_exact(&mut buf)?;
+            usize::from_be_bytes(buf)
+        };
+        if len != out.len() {
+            return Err(Error::InvalidData(format!("priority list has {len} entries, expected {}", out.len())));
+        }
+        stream.read_exact(out)?;
+        Ok(())
+    }
+
+    /// Scales the chunks the files of a round get down to about `budget` in total, in
+    /// proportion to their priorities. Every file still gets at least one chunk, so a round
+    /// only goes over the budget when more files than that are downloaded at once.
+    pub fn limit_round(round: &mut [u8], budget: u32) {
+        let total: u32 = round.iter().map(|priority| *priority as u32).sum();
+        if total <= budget {
+            return;
+        }
+        for priority in round.iter_mut().filter(|priority| **priority != 0) {
+            *priority = (*priority as u32 * budget / total).max(1) as u8;
+        }
+    }
+
     pub fn merge(current: &mut [u8], other: &[u8]) -> usize {
+        merge_changed(current, other).len()
+    }
+
+    /// Like `merge`, but returns the indices of the newly requested files in order.
+    pub fn merge_changed(current: &mut [u8], other: &[u8]) -> Vec<usize> {
         assert!(current.len() == other.len());
-        let mut modified = 0;
-        for (priority, other_priority) in current.iter_mut().zip(other.iter()) {
-            if *priority == 0 {
-                if *other_priority != 0 {
-                    modified += 1;
-                    *priority = *other_priority;
+        let mut changed = Vec::new();
+        for (idx, (priority, other_priority)) in current.iter_mut().zip(other.iter()).enumerate() {
+            if *priority == 0 && *other_priority != 0 {
+                changed.push(idx);
+                *priority = *other_priority;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_lists_of_another_length_are_rejected() {
+        let mut sent = Vec::new();
+        priority_list::send(&mut sent, &[1, 2, 4]).unwrap();
+        // The kind byte is read by the caller before the rest.
+        let message = &sent[1..];
+
+        let mut out = priority_list::new(3);
+        priority_list::recv_into(&mut &message[..], &mut out).unwrap();
+        assert_eq!(&out[..], [1, 2, 4]);
+        for len in [2, 4] {
+            let mut out = priority_list::new(len);
+            let err = priority_list::recv_into(&mut &message[..], &mut out).unwrap_err();
+            assert!(matches!(err, Error::InvalidData(_)), "{err}");
+        }
+    }
+
+    #[test]
+    fn window_holds_rounds_back_until_they_are_acknowledged() {
+        let mut window = protocol::Window::new(2);
+        assert_eq!((window.send(), window.send()), (1, 2));
+        assert!(!window.is_open());
+        window.ack(1).unwrap();
+        assert!(window.is_open());
+        assert_eq!(window.send(), 3);
+        assert!(!window.is_open());
+        // Acknowledging a round covers the ones before it.
+        window.ack(3).unwrap();
+        assert_eq!((window.send(), window.send()), (4, 5));
+        assert!(!window.is_open());
+    }
+
+    #[test]
+    fn window_rejects_acks_of_rounds_not_in_flight() {
+        let mut window = protocol::Window::new(4);
+        assert!(window.ack(1).is_err());
+        window.send();
+        window.send();
+        assert!(window.ack(0).is_err());
+        assert!(window.ack(3).is_err());
+        window.ack(2).unwrap();
+        assert!(window.ack(2).is_err());
+        assert!(window.is_open());
+    }
+
+    #[test]
+    fn merge_changed_returns_only_newly_requested_files() {
+        let mut current = [0, 4, 0, 1];
+        let changed = priority_list::merge_changed(&mut current, &[10, 1, 0, 4]);
+        assert_eq!(changed, [0]);
+        // Files that were already requested keep their priority.
+        assert_eq!(current, [10, 4, 0, 1]);
+        assert!(priority_list::merge_changed(&mut current, &[10, 4, 0, 1]).is_empty());
+    }
+
+    #[test]
+    fn file_lists_with_another_number_of_names_are_rejected() {
+        // The sizes of `len` files, then the names of `names`.
+        let message = |len: usize, names: &[&str]| {
+            let mut sent = len.to_be_bytes().to_vec();
+            for size in 0..len as u64 {
+                sent.extend(size.to_be_bytes());
+            }
+            let names = names.join("\0");
+            sent.extend(names.len().to_be_bytes());
+            sent.extend(names.as_bytes());
+            sent
+        };
+
+        let files = FileList::recv(&mut &message(2, &["a", "b/c"])[..]).unwrap();
+        assert_eq!(files[..], [("a".into(), 0), ("b/c".into(), 1)]);
+        assert!(FileList::recv(&mut &message(0, &[])[..]).unwrap().is_empty());
+        for (len, names) in [(2, &["a"][..]), (2, &["a", "b", "c"]), (0, &["a"])] {
+            let result = FileList::recv(&mut &message(len, names)[..]);
+            assert!(matches!(result, Err(Error::InvalidData(_))), "{len} {names:?}: {result:?}");
+        }
+    }
+
+    #[test]
+    fn truncated_chunks_are_unexpected_eof() {
+        let mut sent = Vec::new();
+        Chunk::read(&mut &[5; 1024][..]).unwrap().send(&mut sent).unwrap();
+        for len in [1, 2, 600, sent.len() - 1] {
+            let result = Chunk::recv_with(&mut &sent[..len], Codec::None);
+            assert!(matches!(&result, Err(Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof), "{len}: {:?}", result.err());
+        }
+    }
+
+    #[test]
+    fn chunks_of_a_bad_length_are_rejected() {
+        // Claims to carry more compressed bytes than a chunk can hold.
+        let mut sent = CHUNK_COMPRESSED.to_be_bytes().to_vec();
+        sent.extend(2000u16.to_be_bytes());
+        sent.extend([0; 2000]);
+        let result = Chunk::recv_with(&mut &sent[..], Codec::Zstd);
+        assert!(matches!(result, Err(Error::Protocol(_))), "{:?}", result.err());
+
+        // Decompresses to fewer bytes than the header says.
+        let compressed = Codec::Deflate.compress(&[5; 50], 6).unwrap();
+        let mut sent = (CHUNK_END | CHUNK_COMPRESSED | 100).to_be_bytes().to_vec();
+        sent.extend((compressed.len() as u16).to_be_bytes());
+        sent.extend(&compressed);
+        let result = Chunk::recv_with(&mut &sent[..], Codec::Deflate);
+        assert!(matches!(result, Err(Error::Protocol(_) | Error::InvalidData(_))), "{:?}", result.err());
+    }
+
+    #[test]
+    fn subpaths_must_be_relative_directories() {
+        assert_eq!(protocol::check_subpath("docs"), Some("docs"));
+        assert_eq!(protocol::check_subpath("docs/guide/"), Some("docs/guide"));
+        for path in ["", "/", ".", "..", "./docs", "docs/..", "docs/../..", "/etc", "docs//guide", "..\\docs", "C:\\docs"] {
+            assert_eq!(protocol::check_subpath(path), None, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn chunks_round_trip_through_every_codec_and_level() {
+        let text = b"the quick brown fox jumps over the lazy dog ".repeat(30);
+        // Bytes of a linear congruential generator, which don't compress.
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..1024).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+
+        for codec in [Codec::Deflate, Codec::Zstd] {
+            let levels = codec.levels();
+            for level in [*levels.start(), codec.default_level(), *levels.end()] {
+                for (data, compressible) in [(&text[..1024], true), (&text[..300], true), (&noise[..], false)] {
+                    let mut sent = Vec::new();
+                    Chunk::read(&mut &data[..]).unwrap().send_with(&mut sent, codec, level).unwrap();
+                    let compressed = u16::from_be_bytes([sent[0], sent[1]]) & CHUNK_COMPRESSED != 0;
+                    // Deflate only stores at level 0, and zstd hardly compresses at its
+                    // negative levels, so chunks may go raw there either way.
+                    if level > 0 || !compressible {
+                        assert_eq!(compressed, compressible, "{codec:?} {level}");
+                    }
+                    if !compressed {
+                        assert_eq!(sent.len(), 2 + data.len());
+                    }
+
+                    let chunk = Chunk::recv_with(&mut &sent[..], codec).unwrap();
+                    assert_eq!(chunk.data(), data, "{codec:?} {level}");
+                    assert_eq!(chunk.end(), data.len() < 1024);
                 }
             }
         }
-        modified
+    }
+
+    #[test]
+    fn announcements_carry_the_magic_and_the_port() {
+        let packet = discovery::encode(3002);
+        assert_eq!(packet, b"SOCKET-PROJECT\x0b\xba");
+        assert_eq!(discovery::decode(&packet), Some(3002));
+        for packet in [&b"SOCKET-PROJECT"[..], b"SOCKET-PROJECT\x0b", b"SOCKET-PROJECT\x0b\xba\x00", b"SOCKET-PROJEKT\x0b\xba", b""] {
+            assert_eq!(discovery::decode(packet), None, "{packet:?}");
+        }
     }
 }
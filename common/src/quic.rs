@@ -0,0 +1,305 @@
+//! QUIC transport, built with the `quic` feature. Every file travels on a stream of its
+//! own, so a lost packet only holds back the file it belongs to rather than every file
+//! interleaved on a TCP connection.
+//!
+//! The client opens a first stream and sends the codec it wants, like over TCP. The
+//! server answers with the codec it picked and the `FileList`, and finishes the stream.
+//! For every file it wants, the client then opens a stream and sends a `FileRequest`, and
+//! the server answers with the chunks of the requested range before finishing it. At
+//! most `MAX_STREAMS` of them are open at once, the client waits for one to finish
+//! before opening more.
+//!
+//! Servers present a self-signed certificate, which clients are given beforehand instead
+//! of checking it against certificate authorities.
+
+use std::{fs::{self, File}, io::{self, Read, Write}, net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs}, path::Path, sync::{mpsc, Arc, Mutex, OnceLock}, thread};
+use quinn::{rustls::{pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer}, RootCertStore}, ClientConfig, ConnectionError, Endpoint, RecvStream, SendStream, ServerConfig, TransportConfig, VarInt};
+use tokio::runtime::{self, Runtime};
+use crate::{download::{is_safe_name, save, ProgressEvent, Summary}, Chunk, Codec, Error, FileList, Packet, Result, RANGE_TO_END};
+
+/// The name certificates are issued for. Clients trust one certificate rather than a
+/// name, so it's the same for every server.
+pub const SERVER_NAME: &str = "socket-project";
+
+/// The most file streams a client may have open at once.
+pub const MAX_STREAMS: u32 = 16;
+
+/// Drives the connections of the process. Streams are used from plain threads, which
+/// wait on it for every read and write.
+fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("quic")
+            .enable_all()
+            .build()
+            .expect("failed to start the QUIC runtime")
+    })
+}
+
+/// Asks for `range` of file `idx`, where the end may be `RANGE_TO_END`, on a stream of its
+/// own. Streams with a higher `priority` are sent first, the others take turns.
+pub struct FileRequest {
+    pub idx: u64,
+    pub range: (u64, u64),
+    pub priority: u8,
+}
+
+impl Packet for FileRequest {
+    fn send<T: Write>(&self, stream: &mut T) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(25);
+        buf.extend_from_slice(&self.idx.to_be_bytes());
+        buf.extend_from_slice(&self.range.0.to_be_bytes());
+        buf.extend_from_slice(&self.range.1.to_be_bytes());
+        buf.push(self.priority);
+        stream.write_all(&buf)
+    }
+
+    fn recv<T: Read>(stream: &mut T) -> Result<Self> {
+        let mut buf = [0; 25];
+        stream.read_exact(&mut buf)?;
+        let u64_at = |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().unwrap());
+        Ok(FileRequest { idx: u64_at(0), range: (u64_at(8), u64_at(16)), priority: buf[24] })
+    }
+}
+
+/// A stream of a connection, read and written like a socket.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn set_priority(&self, priority: i32) -> io::Result<()> {
+        Ok(self.send.set_priority(priority)?)
+    }
+
+    /// Tells the peer that nothing more is sent, and waits until it received everything.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.send.finish()?;
+        runtime().block_on(self.send.stopped())?;
+        Ok(())
+    }
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(runtime().block_on(self.recv.read(buf))?.unwrap_or(0))
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(runtime().block_on(self.send.write(buf))?)
+    }
+
+    // Written data is sent as soon as the connection allows it.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Connection {
+    connection: quinn::Connection,
+    endpoint: Endpoint,
+}
+
+impl Connection {
+    /// Connects to `addr`, a `host:port`, trusting only the DER certificate `cert`.
+    pub fn connect(addr: &str, cert: &[u8]) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("`{addr}` has no address")))?;
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from(cert.to_vec())).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let config = ClientConfig::with_root_certificates(Arc::new(roots)).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        // Endpoints and handshakes spawn their tasks on the runtime they're started from.
+        let _runtime = runtime().enter();
+        let endpoint = Endpoint::client(local)?;
+        let connecting = endpoint.connect_with(config, addr, SERVER_NAME).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let connection = runtime().block_on(connecting)?;
+        Ok(Self { connection, endpoint })
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+
+    /// Opens a stream, waiting while the peer allows no more of them.
+    pub fn open(&self) -> io::Result<QuicStream> {
+        let (send, recv) = runtime().block_on(self.connection.open_bi())?;
+        Ok(QuicStream { send, recv })
+    }
+
+    /// Waits for the next stream the peer opens, `None` once it closed the connection.
+    pub fn accept(&self) -> io::Result<Option<QuicStream>> {
+        match runtime().block_on(self.connection.accept_bi()) {
+            Ok((send, recv)) => Ok(Some(QuicStream { send, recv })),
+            Err(ConnectionError::ApplicationClosed(_)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Closes the connection and waits until the peer was told, or gave up on.
+    pub fn close(self) {
+        self.connection.close(0u32.into(), b"done");
+        runtime().block_on(self.endpoint.wait_idle());
+    }
+}
+
+/// A client that reached the listener, connected once it's established.
+pub struct Incoming {
+    incoming: quinn::Incoming,
+    endpoint: Endpoint,
+}
+
+impl Incoming {
+    /// Runs the handshake of the connection.
+    pub fn establish(self) -> io::Result<Connection> {
+        let _runtime = runtime().enter();
+        let connection = runtime().block_on(self.incoming.accept()?)?;
+        Ok(Connection { connection, endpoint: self.endpoint })
+    }
+
+    /// Turns the client away without a handshake.
+    pub fn refuse(self) {
+        self.incoming.refuse();
+    }
+}
+
+pub struct Listener {
+    endpoint: Endpoint,
+}
+
+impl Listener {
+    /// Listens on `addr`, presenting the DER certificate `cert` whose PKCS #8 private key
+    /// is `key`.
+    pub fn bind(addr: SocketAddr, cert: Vec<u8>, key: Vec<u8>) -> io::Result<Self> {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key));
+        let mut config = ServerConfig::with_single_cert(vec![CertificateDer::from(cert)], key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        // The stream of the file list comes on top of the ones of files.
+        let mut transport = TransportConfig::default();
+        transport.max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS + 1));
+        transport.max_concurrent_uni_streams(VarInt::from_u32(0));
+        config.transport_config(Arc::new(transport));
+        let _runtime = runtime().enter();
+        Ok(Self { endpoint: Endpoint::server(config, addr)? })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    /// Waits for the next client. Its handshake is left to `Incoming::establish`, so that
+    /// a slow one doesn't hold back the others.
+    pub fn accept(&self) -> Option<Incoming> {
+        let incoming = runtime().block_on(self.endpoint.accept())?;
+        Some(Incoming { incoming, endpoint: self.endpoint.clone() })
+    }
+}
+
+/// What the thread of a file tells the one reporting progress.
+enum Update {
+    Progress(usize, u64),
+    Finished(usize, io::Result<()>),
+}
+
+/// Receives file `idx` over a stream of its own into `path`, through a `.part` file.
+fn fetch(connection: &Connection, codec: Codec, idx: usize, priority: u8, size: u64, path: &Path, updates: &mpsc::Sender<Update>) -> io::Result<()> {
+    let mut part_path = path.as_os_str().to_owned();
+    part_path.push(".part");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = File::create(&part_path)?;
+
+    let mut stream = connection.open()?;
+    FileRequest { idx: idx as u64, range: (0, RANGE_TO_END), priority }.send(&mut stream)?;
+    let mut received = 0;
+    loop {
+        let chunk = Chunk::recv_with(&mut stream, codec)?;
+        received += chunk.len as u64;
+        let end = chunk.write(&mut file)?;
+        let _ = updates.send(Update::Progress(idx, received));
+        if end {
+            break;
+        }
+    }
+    drop(file);
+    save(Path::new(&part_path), path, size, received, None)
+}
+
+/// Like `download::download_all`, over QUIC from `addr`, a `host:port`, whose server
+/// presents the DER certificate `cert`. `select` is given the served files and picks the
+/// priority of each of them, `0` for those that aren't wanted. Every file is downloaded
+/// on a stream of its own, so they arrive side by side, and a file whose stream breaks
+/// fails on its own.
+pub fn download_all(addr: &str, cert: &[u8], dest_dir: &Path, select: &mut dyn FnMut(&FileList) -> Box<[u8]>, on_event: &mut dyn FnMut(ProgressEvent)) -> Result<Summary> {
+    let connection = Connection::connect(addr, cert)?;
+    let (codec, files) = {
+        let mut stream = connection.open()?;
+        stream.write_all(&[Codec::None as u8])?;
+        let mut codec = [0; 1];
+        stream.read_exact(&mut codec)?;
+        let codec = Codec::from_id(codec[0]).ok_or(Error::Protocol("server picked an unknown codec"))?;
+        (codec, FileList::recv(&mut stream)?)
+    };
+    let priorities = select(&files);
+
+    let mut summary = Summary::default();
+    let mut jobs = Vec::new();
+    for (idx, ((name, size), priority)) in files.iter().zip(priorities.iter()).enumerate() {
+        if *priority == 0 {
+            continue;
+        }
+        if !is_safe_name(name) {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "not a relative name");
+            on_event(ProgressEvent::Failed { name, err: &err });
+            summary.failed.push((name.to_string(), err));
+            continue;
+        }
+        on_event(ProgressEvent::Started { name, size: *size });
+        jobs.push((idx, *priority, *size, dest_dir.join(name.as_ref())));
+    }
+
+    // As many threads as streams may be open, each taking the next file once its own
+    // is done.
+    let threads = jobs.len().min(MAX_STREAMS as usize);
+    let jobs = Mutex::new(jobs.into_iter());
+    let (sender, updates) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (connection, jobs, sender) = (&connection, &jobs, sender.clone());
+            scope.spawn(move || {
+                loop {
+                    let Some((idx, priority, size, path)) = jobs.lock().unwrap().next() else { break };
+                    let result = fetch(connection, codec, idx, priority, size, &path, &sender);
+                    let _ = sender.send(Update::Finished(idx, result));
+                }
+            });
+        }
+        drop(sender);
+
+        for update in updates {
+            match update {
+                Update::Progress(idx, received) => on_event(ProgressEvent::Progress { name: &files[idx].0, received }),
+                Update::Finished(idx, Ok(())) => {
+                    on_event(ProgressEvent::Completed { name: &files[idx].0 });
+                    summary.completed.push(files[idx].0.to_string());
+                },
+                Update::Finished(idx, Err(err)) => {
+                    on_event(ProgressEvent::Failed { name: &files[idx].0, err: &err });
+                    summary.failed.push((files[idx].0.to_string(), err));
+                },
+            }
+        }
+    });
+    connection.close();
+    Ok(summary)
+}
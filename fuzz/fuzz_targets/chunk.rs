@@ -0,0 +1,16 @@
+//! Feeds arbitrary bytes to the chunk decoder. The first byte picks the negotiated codec,
+//! the rest is what the server sent.
+
+#![no_main]
+
+use common::{Chunk, Codec};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((codec, mut stream)) = data.split_first() else {
+        return;
+    };
+    let codec = Codec::from_id(codec % 3).unwrap();
+    // A stream holds many chunks, so keep going until the bytes run out or one is rejected.
+    while Chunk::recv_with(&mut stream, codec).is_ok() {}
+});
@@ -0,0 +1,22 @@
+//! Feeds arbitrary bytes to the decoders of the lists exchanged during the handshake. The
+//! first byte picks the packet, the rest is what the peer sent.
+
+#![no_main]
+
+use common::{recv_file_list, FileList, FlagList, HashList, MtimeList, Packet, RangeList};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((kind, mut stream)) = data.split_first() else {
+        return;
+    };
+    // Only panics, hangs and runaway allocations are of interest, errors are expected.
+    match kind % 6 {
+        0 => { let _ = FileList::recv(&mut stream); },
+        1 => { let _ = HashList::recv(&mut stream); },
+        2 => { let _ = FlagList::recv(&mut stream); },
+        3 => { let _ = RangeList::recv(&mut stream); },
+        4 => { let _ = recv_file_list(&mut stream, true); },
+        _ => { let _ = MtimeList::recv(&mut stream); },
+    }
+});
@@ -0,0 +1,163 @@
+use std::{collections::HashSet, fs::File, io, path::{Component, Path}};
+use common::FileList;
+use zip::{CompressionMethod, ZipArchive};
+use crate::source::Source;
+
+fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Only relative names without `..` can be saved by the clients. Archives made of `.`
+/// name every entry `./name`, which is served as `name`.
+fn safe_name(name: &str) -> Option<String> {
+    let components: Vec<_> = Path::new(name).components()
+        .skip_while(|component| matches!(component, Component::CurDir))
+        .map(|component| match component {
+            Component::Normal(part) => part.to_str(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    (!components.is_empty()).then(|| components.join("/"))
+}
+
+fn entry_flags(mode: u32) -> u8 {
+    if mode & 0o111 != 0 { common::FLAG_EXECUTABLE } else { 0 }
+}
+
+/// One file stored in an archive.
+struct Entry {
+    name: String,
+    size: u64,
+    offset: u64,
+    len: u64,
+    deflated: bool,
+    flags: u8,
+}
+
+fn tar_entries(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut archive = tar::Archive::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        if !header.entry_type().is_file() {
+            continue;
+        }
+        entries.push(Entry {
+            name: entry.path()?.to_string_lossy().into_owned(),
+            size: entry.size(),
+            offset: entry.raw_file_position(),
+            len: entry.size(),
+            deflated: false,
+            flags: header.mode().map(entry_flags).unwrap_or(0),
+        });
+    }
+    Ok(entries)
+}
+
+fn zip_entries(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut entries = Vec::new();
+    for idx in 0..archive.len() {
+        let entry = archive.by_index_raw(idx)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let deflated = match entry.compression() {
+            CompressionMethod::Stored => false,
+            CompressionMethod::Deflated => true,
+            method => {
+                eprintln!("WARNING: Skipping `{}`, it is compressed with unsupported method {method}", entry.name());
+                continue;
+            }
+        };
+        entries.push(Entry {
+            name: entry.name().into(),
+            size: entry.size(),
+            offset: entry.data_start(),
+            len: entry.compressed_size(),
+            deflated,
+            flags: entry.unix_mode().map(entry_flags).unwrap_or(0),
+        });
+    }
+    Ok(entries)
+}
+
+/// Lists the regular files in the tar or zip archive at `path`, told apart by extension.
+pub fn list(path: &Path) -> io::Result<(FileList, Box<[Source]>)> {
+    let entries = if is_zip(path) { zip_entries(path)? } else { tar_entries(path)? };
+
+    let mut seen = HashSet::new();
+    let (files, sources): (Vec<_>, Vec<_>) = entries.into_iter().filter_map(|entry| {
+        if entry.name.contains('\0') {
+            eprintln!("ERROR: Name `{}` contains the null-terminator", entry.name);
+            return None;
+        }
+        let Some(name) = safe_name(&entry.name) else {
+            eprintln!("WARNING: Skipping `{}`, it would be saved outside of the output directory", entry.name);
+            return None;
+        };
+        if !seen.insert(name.clone()) {
+            eprintln!("ERROR: Skipping a duplicate of `{name}` in the archive");
+            return None;
+        }
+
+        let name: Box<str> = name.into();
+        let source = Source::Entry {
+            archive: path.into(),
+            name: name.clone(),
+            offset: entry.offset,
+            len: entry.len,
+            deflated: entry.deflated,
+            flags: entry.flags,
+        };
+        Some(((name, entry.size), source))
+    }).unzip();
+
+    Ok((files.into(), sources.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Read};
+    use common::RANGE_TO_END;
+    use crate::source::{FileSource, Filesystem};
+    use super::*;
+
+    #[test]
+    fn current_dir_prefix_is_stripped() {
+        assert_eq!(safe_name("./a.txt").as_deref(), Some("a.txt"));
+        assert_eq!(safe_name("./dir/./b.txt").as_deref(), Some("dir/b.txt"));
+        assert_eq!(safe_name("dir/c.txt").as_deref(), Some("dir/c.txt"));
+        assert_eq!(safe_name("./"), None);
+        assert_eq!(safe_name("./../d.txt"), None);
+        assert_eq!(safe_name("dir/../e.txt"), None);
+        assert_eq!(safe_name("/etc/passwd"), None);
+    }
+
+    /// Like `tar -C dir -cf archive.tar .`, which names every entry `./name`.
+    #[test]
+    fn serves_entries_of_a_tar_made_of_the_current_dir() {
+        let path = std::env::temp_dir().join(format!("archive-test-{}.tar", std::process::id()));
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        for (name, data) in [("./", &b""[..]), ("./a.txt", b"hello"), ("./dir/b.txt", b"world!")] {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(if name.ends_with('/') { tar::EntryType::Directory } else { tar::EntryType::Regular });
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        }
+        builder.into_inner().unwrap();
+
+        let (files, sources) = list(&path).unwrap();
+        let source = Filesystem::new(files, sources, false);
+        let names: Vec<_> = source.list().iter().map(|(name, size)| (name.as_ref(), *size)).collect();
+        assert_eq!(names, [("a.txt", 5), ("dir/b.txt", 6)]);
+        let mut content = String::new();
+        source.open(1, (0, RANGE_TO_END)).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "world!");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! `FILE_LIST`, a precomputed list of the served files that is loaded instead of scanning
+//! the input directory, and `WRITE_FILE_LIST`, which writes one from what the server would
+//! serve. Every line is a hex digest or `-`, the size, the modification time in
+//! nanoseconds, the name and, after a tab, the path of the file.
+
+use std::{fs::{self, File}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}};
+use common::{from_hex, to_hex, Digest};
+use crate::{hashing::Hashes, source::{FileSource, Stamp}};
+
+pub struct Entry {
+    pub name: Box<str>,
+    pub size: u64,
+    /// When the file was last modified as it was listed, which tells whether `digest` is
+    /// still the digest of the file.
+    pub mtime: u128,
+    pub digest: Option<Digest>,
+    pub path: PathBuf,
+}
+
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut iter = line.splitn(4, ' ');
+    let digest = match iter.next()? {
+        "-" => None,
+        hex => Some(from_hex(hex)?),
+    };
+    let size = iter.next()?.parse().ok()?;
+    let mtime = iter.next()?.parse().ok()?;
+    let (name, path) = iter.next()?.split_once('\t')?;
+    if name.is_empty() || name.contains('\0') || path.is_empty() {
+        return None;
+    }
+    Some(Entry { name: name.into(), size, mtime, digest, path: path.into() })
+}
+
+/// Reads the file list at `path`, failing on the first malformed line.
+pub fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (line_no, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        match parse_entry(&line) {
+            Some(entry) => entries.push(entry),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed line {}", line_no + 1))),
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes the files of `source` to `path` along with the digests `hashes` already knows,
+/// returning how many were written. Only files stored on their own can be listed, archive
+/// entries and synthetic files are skipped.
+pub fn save(path: &Path, source: &dyn FileSource, hashes: &Hashes) -> io::Result<usize> {
+    let tmp_path = path.with_extension("tmp");
+    let mut out = io::BufWriter::new(File::create(&tmp_path)?);
+    let mut written = 0;
+    for (idx, (name, size)) in source.list().iter().enumerate() {
+        let file = source.path(idx);
+        if source.backing_file(idx) != Some(file.as_path()) {
+            eprintln!("WARNING: Not listing `{name}`, it isn't a file of its own");
+            continue;
+        }
+        let Some(file_str) = file.to_str().filter(|file| !file.contains(['\t', '\n'])) else {
+            eprintln!("WARNING: Not listing `{name}`, its path can't be written in a file list");
+            continue;
+        };
+        if name.contains(['\t', '\n']) {
+            eprintln!("WARNING: Not listing `{name}`, its name can't be written in a file list");
+            continue;
+        }
+
+        let stamp = Stamp::of(&file)?;
+        let digest = hashes.cached(source, idx).map_or_else(|| "-".to_string(), |digest| to_hex(&digest));
+        writeln!(out, "{digest} {size} {} {name}\t{file_str}", stamp.mtime)?;
+        written += 1;
+    }
+    out.flush()?;
+    drop(out);
+    fs::rename(tmp_path, path)?;
+    Ok(written)
+}
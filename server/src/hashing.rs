@@ -0,0 +1,306 @@
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, io::{self, BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, thread};
+use common::{digest, from_hex, to_hex, Digest, HashList, RANGE_TO_END};
+use crate::source::{FileSource, Stamp};
+
+/// Digests of previously hashed files, persisted across server restarts.
+#[derive(Default)]
+pub struct HashCache {
+    path: PathBuf,
+    entries: HashMap<PathBuf, (Stamp, Digest)>,
+}
+
+fn parse_entry(line: &str) -> Option<(PathBuf, (Stamp, Digest))> {
+    let mut iter = line.splitn(4, ' ');
+    let digest = from_hex(iter.next()?)?;
+    let size = iter.next()?.parse().ok()?;
+    let mtime = iter.next()?.parse().ok()?;
+    let path = iter.next()?;
+    Some((path.into(), (Stamp { size, mtime }, digest)))
+}
+
+impl HashCache {
+    pub fn load(path: &Path) -> Self {
+        let mut entries = HashMap::new();
+        match File::open(path) {
+            Ok(file) => for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match parse_entry(&line) {
+                    Some((path, entry)) => { entries.insert(path, entry); },
+                    None => eprintln!("WARNING: Ignoring malformed line in hash cache: `{line}`"),
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => eprintln!("WARNING: Failed to read hash cache `{}`: {err}", path.display()),
+        }
+
+        Self { path: path.into(), entries }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut out = io::BufWriter::new(File::create(&tmp_path)?);
+        for (path, (stamp, digest)) in self.entries.iter() {
+            writeln!(out, "{} {} {} {}", to_hex(digest), stamp.size, stamp.mtime, path.display())?;
+        }
+        out.flush()?;
+        drop(out);
+        fs::rename(tmp_path, &self.path)
+    }
+
+    /// Adds the digest of the file at `path`, known to be `digest` while it has `stamp`.
+    pub fn insert(&mut self, path: PathBuf, stamp: Stamp, digest: Digest) {
+        self.entries.insert(path, (stamp, digest));
+    }
+
+    /// The digest of file `idx` of `source` if it's cached and the file didn't change since.
+    fn lookup(&self, source: &dyn FileSource, idx: usize) -> Option<Digest> {
+        let stamp = Stamp::of(source.backing_file(idx)?).ok()?;
+        match self.entries.get(&source.path(idx)) {
+            Some((cached, digest)) if *cached == stamp => Some(*digest),
+            _ => None,
+        }
+    }
+
+    /// Hashes every file of `source` on up to `threads` threads, reusing cached digests of
+    /// files whose stamp didn't change. Every thread has one file open at a time and the digests are returned
+    /// in the order of the file list, along with how many files had to be hashed. The cache
+    /// keeps only the entries of `source` afterwards.
+    pub fn hash_files(&mut self, source: &dyn FileSource, threads: usize) -> (HashList, usize) {
+        let len = source.list().len();
+        let paths: Vec<_> = (0..len).map(|idx| source.path(idx)).collect();
+        let stamps: Vec<_> = (0..len).map(|idx| source.backing_file(idx).map(Stamp::of).transpose()).collect();
+        let mut hashes: Vec<Option<Digest>> = paths.iter().zip(stamps.iter()).map(|(path, stamp)| {
+            match (self.entries.get(path), stamp) {
+                (Some((cached, digest)), Ok(Some(stamp))) if cached == stamp => Some(*digest),
+                _ => None,
+            }
+        }).collect();
+        let cached = hashes.iter().flatten().count();
+
+        let pending: Vec<_> = (0..len)
+            .filter(|idx| hashes[*idx].is_none() && stamps[*idx].is_ok())
+            .collect();
+        let next = AtomicUsize::new(0);
+        let hashed: Vec<_> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads.clamp(1, pending.len().max(1))).map(|_| scope.spawn(|| {
+                let mut hashed = Vec::new();
+                while let Some(idx) = pending.get(next.fetch_add(1, Ordering::Relaxed)).copied() {
+                    let digest = source.open(idx, (0, RANGE_TO_END))
+                        .and_then(|mut reader| digest(reader.as_mut()));
+                    hashed.push((idx, digest));
+                }
+                hashed
+            })).collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        });
+
+        for (idx, digest) in hashed {
+            match digest {
+                Ok(digest) => hashes[idx] = Some(digest),
+                Err(err) => eprintln!("ERROR: Failed to hash `{}`: {err}", paths[idx].display()),
+            }
+        }
+        for (path, stamp) in paths.iter().zip(stamps.iter()) {
+            if let Err(err) = stamp {
+                eprintln!("ERROR: Failed to hash `{}`: {err}", path.display());
+            }
+        }
+
+        let hashed = hashes.iter().flatten().count() - cached;
+        self.entries = paths.iter().zip(stamps).zip(hashes.iter())
+            .filter_map(|((path, stamp), digest)| Some((path.clone(), (stamp.ok()??, (*digest)?))))
+            .collect();
+        (hashes.into(), hashed)
+    }
+
+    /// Hashes the files and saves the cache, logging what was done when `quiet` isn't set
+    /// or files had to be hashed.
+    pub fn update(&mut self, source: &dyn FileSource, threads: usize, quiet: bool) -> HashList {
+        let (hashes, hashed) = self.hash_files(source, threads);
+        if !quiet || hashed != 0 {
+            self.report(&hashes, hashed);
+        }
+        hashes
+    }
+
+    fn report(&self, hashes: &HashList, hashed: usize) {
+        let cached = hashes.iter().flatten().count() - hashed;
+        println!("Hashed {hashed} files, {cached} unchanged ones came from the cache");
+        if let Err(err) = self.save() {
+            eprintln!("WARNING: Failed to save hash cache `{}`: {err}", self.path.display());
+        }
+    }
+}
+
+/// Hashes file `idx` of `source` unless it's in `cache`, holding only the lock of that
+/// file in `hashing` meanwhile. Returns the digest and whether it had to be computed.
+fn hash_entry(cache: &Mutex<HashCache>, hashing: &Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>, source: &dyn FileSource, idx: usize) -> (Option<Digest>, bool) {
+    let path = source.path(idx);
+    let lock = hashing.lock().unwrap().entry(path.clone()).or_default().clone();
+    let guard = lock.lock().unwrap();
+    // Checked once the lock is held, another client may have just hashed the file.
+    let cached = cache.lock().unwrap().lookup(source, idx);
+    let result = match cached {
+        Some(digest) => (Some(digest), false),
+        None => {
+            let digest = source.backing_file(idx).map(Stamp::of).transpose().and_then(|stamp| {
+                let digest = digest(source.open(idx, (0, RANGE_TO_END))?.as_mut())?;
+                Ok((stamp, digest))
+            });
+            match digest {
+                Ok((stamp, digest)) => {
+                    if let Some(stamp) = stamp {
+                        cache.lock().unwrap().insert(path.clone(), stamp, digest);
+                    }
+                    (Some(digest), true)
+                },
+                Err(err) => {
+                    eprintln!("ERROR: Failed to hash `{}`: {err}", path.display());
+                    (None, false)
+                },
+            }
+        },
+    };
+    drop(guard);
+    let mut hashing = hashing.lock().unwrap();
+    // Nobody else waits for the file once only the map and this call hold its lock.
+    if Arc::strong_count(&lock) == 2 {
+        hashing.remove(&path);
+    }
+    result
+}
+
+/// Like `HashCache::hash_files`, but the cache is only locked to look digests up and add
+/// them, not while files are hashed.
+fn hash_lazily(cache: &Mutex<HashCache>, hashing: &Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>, source: &dyn FileSource, threads: usize) -> (HashList, usize) {
+    let len = source.list().len();
+    let mut hashes: Vec<_> = {
+        let cache = cache.lock().unwrap();
+        (0..len).map(|idx| cache.lookup(source, idx)).collect()
+    };
+    let pending: Vec<_> = (0..len).filter(|idx| hashes[*idx].is_none()).collect();
+    let next = AtomicUsize::new(0);
+    let results: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.clamp(1, pending.len().max(1))).map(|_| scope.spawn(|| {
+            let mut results = Vec::new();
+            while let Some(idx) = pending.get(next.fetch_add(1, Ordering::Relaxed)).copied() {
+                results.push((idx, hash_entry(cache, hashing, source, idx)));
+            }
+            results
+        })).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+
+    let mut hashed = 0;
+    for (idx, (digest, computed)) in results {
+        hashes[idx] = digest;
+        hashed += computed as usize;
+    }
+    // Like `hash_files`, only the entries of `source` are kept.
+    let paths: HashSet<_> = (0..len).map(|idx| source.path(idx)).collect();
+    cache.lock().unwrap().entries.retain(|path, _| paths.contains(path));
+    (hashes.into(), hashed)
+}
+
+/// Where the digests advertised to clients come from.
+pub enum Hashes {
+    /// Computed once at startup.
+    Fixed(HashList),
+    /// Computed when a client connects, so that startup isn't held up. Files that didn't
+    /// change since the last connection keep their cached digests.
+    Lazy {
+        cache: Mutex<HashCache>,
+        /// A lock for every file being hashed. Clients connecting at the same time wait for
+        /// each other on the files they both need rather than on the whole cache.
+        hashing: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+        threads: usize,
+    },
+    /// Not advertised, only computed for the clients asking for them, without a cache.
+    OnRequest {
+        threads: usize,
+    },
+}
+
+impl Hashes {
+    pub fn get(&self, source: &dyn FileSource) -> HashList {
+        match self {
+            Hashes::Fixed(hashes) => hashes.clone(),
+            Hashes::Lazy { cache, hashing, threads } => {
+                let (hashes, hashed) = hash_lazily(cache, hashing, source, *threads);
+                if hashed != 0 {
+                    cache.lock().unwrap().report(&hashes, hashed);
+                }
+                hashes
+            },
+            Hashes::OnRequest { threads } => HashCache::default().hash_files(source, *threads).0,
+        }
+    }
+
+    /// The digest of file `idx` of `source` if it was already computed, without hashing
+    /// anything.
+    pub fn cached(&self, source: &dyn FileSource, idx: usize) -> Option<Digest> {
+        match self {
+            // Computed for the files listed at startup.
+            Hashes::Fixed(hashes) => hashes.get(idx).copied().flatten(),
+            Hashes::Lazy { cache, .. } => cache.lock().unwrap().lookup(source, idx),
+            Hashes::OnRequest { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::FileList;
+    use crate::source::{Filesystem, Source};
+
+    fn source(paths: &[PathBuf]) -> Filesystem {
+        let files: FileList = paths.iter()
+            .map(|path| (path.file_name().unwrap().to_string_lossy().into(), path.metadata().unwrap().len()))
+            .collect();
+        Filesystem::new(files, paths.iter().cloned().map(Source::File).collect(), false)
+    }
+
+    fn temp_files(name: &str, count: usize) -> Vec<PathBuf> {
+        (0..count).map(|idx| {
+            let path = std::env::temp_dir().join(format!("hashing-test-{}-{name}-{idx}", std::process::id()));
+            fs::write(&path, format!("file {idx}")).unwrap();
+            path
+        }).collect()
+    }
+
+    #[test]
+    fn lazy_digests_are_cached_until_the_file_changes() {
+        let paths = temp_files("changes", 1);
+        let (cache, hashing) = (Mutex::default(), Mutex::default());
+        let (hashes, hashed) = hash_lazily(&cache, &hashing, &source(&paths), 1);
+        assert_eq!(hashed, 1);
+        assert_eq!(hashes[0], Some(digest(&mut &b"file 0"[..]).unwrap()));
+        assert_eq!(hash_lazily(&cache, &hashing, &source(&paths), 1).1, 0);
+
+        fs::write(&paths[0], "changed").unwrap();
+        let (hashes, hashed) = hash_lazily(&cache, &hashing, &source(&paths), 1);
+        assert_eq!(hashed, 1);
+        assert_eq!(hashes[0], Some(digest(&mut &b"changed"[..]).unwrap()));
+        assert!(hashing.lock().unwrap().is_empty());
+        fs::remove_file(&paths[0]).unwrap();
+    }
+
+    #[test]
+    fn clients_connecting_at_once_hash_every_file_once() {
+        let paths = temp_files("at-once", 16);
+        let source = source(&paths);
+        let (cache, hashing) = (Mutex::default(), Mutex::default());
+        let hashed: usize = thread::scope(|scope| {
+            let clients: Vec<_> = (0..4).map(|_| scope.spawn(|| hash_lazily(&cache, &hashing, &source, 2))).collect();
+            clients.into_iter().map(|client| {
+                let (hashes, hashed) = client.join().unwrap();
+                assert!(hashes.iter().all(Option::is_some));
+                hashed
+            }).sum()
+        });
+        assert_eq!(hashed, paths.len());
+        for path in paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! Faults injected into every session to test how clients cope with them. `INJECT_FAILURE`
+//! is ignored unless the server is built with the `failure-injection` feature, so that a
+//! production build can't be made to misbehave by accident.
+
+use std::{collections::HashMap, io, thread, time::Duration};
+use common::Chunk;
+
+/// What goes wrong, counted from the start of every session.
+#[derive(Clone, Copy)]
+pub enum Fault {
+    /// The connection is closed instead of sending the chunk that would cross this many
+    /// bytes of file content.
+    Drop(u64),
+    /// The first byte of the chunk with this index, counting from 0, is flipped.
+    Corrupt(u64),
+    /// Every chunk is sent after waiting this long.
+    Delay(Duration),
+}
+
+impl Fault {
+    /// Reads `INJECT_FAILURE` of `vars`, one of `drop:<bytes>`, `corrupt:<chunk>` or `delay:<ms>`.
+    pub fn from_env(vars: &HashMap<String, String>) -> Result<Option<Self>, String> {
+        let Some(spec) = vars.get("INJECT_FAILURE") else {
+            return Ok(None);
+        };
+        if !cfg!(feature = "failure-injection") {
+            eprintln!("WARNING: Ignoring `INJECT_FAILURE`, the server was built without the `failure-injection` feature");
+            return Ok(None);
+        }
+
+        let fault = spec.split_once(':').and_then(|(kind, value)| {
+            let value = value.parse().ok()?;
+            match kind {
+                "drop" => Some(Fault::Drop(value)),
+                "corrupt" => Some(Fault::Corrupt(value)),
+                "delay" => Some(Fault::Delay(Duration::from_millis(value))),
+                _ => None,
+            }
+        });
+        match fault {
+            Some(fault) => {
+                eprintln!("WARNING: Injecting `{spec}` into every session, don't serve real clients");
+                Ok(Some(fault))
+            },
+            None => Err(format!("`INJECT_FAILURE` expects `drop:<bytes>`, `corrupt:<chunk>` or `delay:<ms>`, got `{spec}`")),
+        }
+    }
+}
+
+/// Applies a fault to the chunks of one session.
+pub struct Injector {
+    fault: Fault,
+    chunks: u64,
+    bytes: u64,
+}
+
+impl Injector {
+    pub fn new(fault: Fault) -> Self {
+        Self { fault, chunks: 0, bytes: 0 }
+    }
+
+    /// Called with every chunk about to be sent, returning the one to send instead.
+    pub fn apply(&mut self, chunk: Chunk) -> io::Result<Chunk> {
+        let idx = self.chunks;
+        self.chunks += 1;
+        self.bytes += chunk.len as u64;
+        match self.fault {
+            Fault::Drop(limit) if self.bytes > limit => {
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, "injected connection drop"))
+            },
+            Fault::Corrupt(target) if idx == target && chunk.len > 0 => {
+                let mut data = chunk.data().to_vec();
+                data[0] ^= 0xff;
+                Chunk::read(&mut data.as_slice())
+            },
+            Fault::Delay(delay) => {
+                thread::sleep(delay);
+                Ok(chunk)
+            },
+            _ => Ok(chunk),
+        }
+    }
+}
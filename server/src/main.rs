@@ -1,45 +1,330 @@
-use std::{env, fs::File, io::{self, Read}, net::{TcpListener, TcpStream}, path::{Path, PathBuf}, process, sync::mpsc, thread};
-use common::{initialize_handlers, priority_list, Chunk, FileList, Packet};
+use std::{cmp, collections::{HashMap, HashSet}, env, fs::{self, File}, io::{self, Read, Write}, net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket}, path::{Path, PathBuf}, process, sync::{atomic::{AtomicUsize, Ordering}, mpsc, Arc, Mutex}, thread, time::{Duration, Instant}};
+use common::{discovery, grow, initialize_handlers, priority_list, protocol, send_file_list, Chunk, Codec, DownloadableFile, Error, FileList, FlagList, HashList, HintList, MtimeList, Packet, RangeList, Stat, Stream, RANGE_TO_END};
+use flate2::read::MultiGzDecoder;
+use hashing::{HashCache, Hashes};
+use inject::{Fault, Injector};
+use scheduler::Scheduler;
+use socket2::{Domain, Protocol, Socket, Type};
+use source::{is_gz, Catalog, FileSource, Filesystem, Source, Stamp, Subtree};
+use stats::SessionStats;
+use synthetic::Pattern;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+mod archive;
+mod file_list;
+mod hashing;
+mod inject;
+#[cfg(feature = "quic")]
+mod quic;
+mod scheduler;
+mod source;
+mod stats;
+mod synthetic;
+
+/// Each worker is a thread with its own stack, so more than this would exhaust memory
+/// long before it helped throughput.
+const MAX_THREAD_COUNT: usize = 1024;
+
+/// How long a new connection may take to send its first bytes before it's handed to a
+/// worker as a client, when health probes are answered.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often the files being sent are checked for changes, besides when they're opened.
+/// Checking every round would cost a `stat` per chunk at low priorities.
+const CHANGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 struct WorkerContext {
-    file_list: FileList,
-    path_list: Box<[PathBuf]>
+    source: Arc<dyn FileSource>,
+    hashes: Arc<Hashes>,
+    flag_list: FlagList,
+    hint_list: HintList,
+    /// Where files found after startup are added, if the input directory is scanned again.
+    catalog: Option<Arc<Catalog>>,
+    /// The optional `protocol::FEATURE_*` this server offers.
+    features: u32,
+    /// The `protocol::FEATURE_*` clients must support to be served.
+    required_features: u32,
+    /// The most chunks sent to a client in one round.
+    round_chunks: Option<u32>,
+    compression_level: Option<i32>,
+    scheduler: Option<Arc<Scheduler>>,
+    fault: Option<Fault>,
 }
 
 impl WorkerContext {
-    fn new(files: &FileList, paths: &[PathBuf]) -> Self {
+    fn new(source: Arc<dyn FileSource>, hashes: Arc<Hashes>, flags: &FlagList, hints: &HintList, catalog: Option<Arc<Catalog>>, scheduler: Option<Arc<Scheduler>>, opt: &Config) -> Self {
+        let mut features = protocol::FEATURES;
+        if opt.hash_mode == HashMode::Off {
+            features &= !protocol::FEATURE_HASHES;
+        }
+        if opt.priority_hints.is_none() {
+            features &= !protocol::FEATURE_HINTS;
+        }
+        if catalog.is_none() {
+            features &= !protocol::FEATURE_APPEND;
+        }
+        if opt.round_chunks.is_none() {
+            features &= !protocol::FEATURE_ROUND_BUDGET;
+        }
+
         Self {
-            file_list: files.clone(),
-            path_list: paths.into(),
+            source,
+            hashes,
+            flag_list: flags.clone(),
+            hint_list: hints.clone(),
+            catalog,
+            features,
+            required_features: opt.required_features,
+            round_chunks: opt.round_chunks,
+            compression_level: opt.compression_level,
+            scheduler,
+            fault: opt.fault,
+        }
+    }
+
+    /// Every file served so far, including the ones found after startup.
+    fn latest_source(&self) -> Arc<dyn FileSource> {
+        match &self.catalog {
+            Some(catalog) => catalog.latest(),
+            None => self.source.clone(),
+        }
+    }
+
+    /// The digests of the files of `source`, which is `subtree` if the client asked for one.
+    fn hashes_of(&self, source: &dyn FileSource, subtree: Option<&Subtree>) -> HashList {
+        match subtree {
+            // Digests computed at startup cover every file, so they're picked from those.
+            Some(subtree) => subtree.select(&self.hashes.get(subtree.inner())),
+            None => self.hashes.get(source),
         }
     }
 
-    fn execute(&self, mut stream: TcpStream) -> io::Result<()> {
-        self.file_list.send(&mut stream)?;
+    /// Serves one client. Only the byte stream is needed, so any transport will do.
+    fn execute<S: Read + Write>(&self, mut stream: S, stats: &mut SessionStats) -> common::Result<()> {
+        let codec = {
+            let mut buf = [0; 1];
+            stream.read_exact(&mut buf)?;
+            Codec::from_id(buf[0]).unwrap_or(Codec::None)
+        };
+        stream.write_all(&[codec as u8])?;
+        let level = self.compression_level.unwrap_or(codec.default_level());
+
+        let offered = protocol::recv_features(&mut stream)?;
+        let missing = self.required_features & !offered;
+        if missing != 0 {
+            protocol::send_features(&mut stream, protocol::REJECTED | missing)?;
+            return Err(Error::MissingFeatures(missing));
+        }
+        let features = offered & self.features;
+        protocol::send_features(&mut stream, self.features)?;
+        let prefix = if features & protocol::FEATURE_SUBPATH != 0 {
+            let path = protocol::recv_subpath(&mut stream)?;
+            let prefix = protocol::check_subpath(&path).ok_or(Error::Protocol("subpath must be a relative directory without `.` or `..`"))?;
+            Some(prefix.to_owned())
+        } else {
+            None
+        };
+        // Without one, rounds are sent as fast as the connection takes them.
+        let mut window = if features & protocol::FEATURE_WINDOW != 0 {
+            Some(protocol::Window::new(protocol::recv_window(&mut stream)?))
+        } else {
+            None
+        };
+
+        // Grows with the files this client learns about through `LIST` messages. Indices
+        // in messages refer to the subtree the client asked for, the stats to every file.
+        let mut subtree = prefix.as_deref().map(|prefix| Arc::new(Subtree::new(self.source.clone(), prefix)));
+        let mut source: Arc<dyn FileSource> = match &subtree {
+            Some(subtree) => subtree.clone(),
+            None => self.source.clone(),
+        };
+        let file_list = source.list();
+        let compressed_list = features & protocol::FEATURE_COMPRESSED_LIST != 0;
+        send_file_list(&mut stream, file_list, compressed_list)?;
+        if features & protocol::FEATURE_HASHES != 0 {
+            self.hashes_of(source.as_ref(), subtree.as_deref()).send(&mut stream)?;
+        }
+        if features & protocol::FEATURE_FLAGS != 0 {
+            narrow(&self.flag_list, subtree.as_deref()).send(&mut stream)?;
+        }
+        if features & protocol::FEATURE_HINTS != 0 {
+            narrow(&self.hint_list, subtree.as_deref()).send(&mut stream)?;
+        }
+        if features & protocol::FEATURE_MTIMES != 0 {
+            let mtimes: MtimeList = (0..file_list.len()).map(|idx| source.mtime(idx)).collect();
+            mtimes.send(&mut stream)?;
+        }
+        // Clients that can't follow the budget get rounds as large as they ask for.
+        let round_budget = self.round_chunks.filter(|_| features & protocol::FEATURE_ROUND_BUDGET != 0);
+        if let Some(budget) = round_budget {
+            protocol::send_round_budget(&mut stream, budget)?;
+        }
+        // The empty list tells the client there's nothing to download, and none can come
+        // up without `LIST`.
+        if file_list.is_empty() && features & protocol::FEATURE_APPEND == 0 {
+            return Ok(());
+        }
+
+        let mut ranges = RangeList::recv(&mut stream)?;
+        if ranges.len() != file_list.len() {
+            return Err(Error::InvalidData("range list doesn't match the file list".into()));
+        }
 
-        let mut files = initialize_handlers(self.file_list.len());
-        let mut priorities = priority_list::new(self.file_list.len());
-        let mut next_priorities = priority_list::new(self.file_list.len());
+        let mut injector = self.fault.map(Injector::new);
+        let mut files = initialize_handlers(file_list.len());
+        let mut priorities = priority_list::new(file_list.len());
+        let mut next_priorities = priority_list::new(file_list.len());
 
         loop {
-            stream.read_exact(&mut next_priorities)?;
+            let mut kind = [0; 1];
+            match stream.read_exact(&mut kind) {
+                Ok(()) => {},
+                // Clients close the connection between rounds once they are done.
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err.into()),
+            }
+            match kind[0] {
+                protocol::PRIORITIES => priority_list::recv_into(&mut stream, &mut next_priorities)?,
+                protocol::PING => {
+                    stream.write_all(&[protocol::PONG])?;
+                    continue;
+                },
+                protocol::RESTART => {
+                    let idx = protocol::recv_index(&mut stream)?;
+                    let Some(idx) = usize::try_from(idx).ok().filter(|idx| *idx < files.len()) else {
+                        return Err(Error::Protocol("restart of an unknown file"));
+                    };
+                    files[idx].done = false;
+                    files[idx].file = None;
+                    priorities[idx] = 0;
+                    ranges[idx].0 = 0;
+                    continue;
+                },
+                protocol::LIST if features & protocol::FEATURE_APPEND != 0 => {
+                    let mut latest = self.latest_source();
+                    let all = latest.list().len();
+                    if let Some(prefix) = &prefix {
+                        let latest_subtree = Arc::new(Subtree::new(latest, prefix));
+                        subtree = Some(latest_subtree.clone());
+                        latest = latest_subtree;
+                    }
+                    let (known, len) = (source.list().len(), latest.list().len());
+                    let added: FileList = latest.list()[known..].into();
+                    send_file_list(&mut stream, &added, compressed_list)?;
+                    if features & protocol::FEATURE_FLAGS != 0 {
+                        let flags: FlagList = (known..len).map(|idx| latest.flags(idx)).collect();
+                        flags.send(&mut stream)?;
+                    }
+                    if features & protocol::FEATURE_MTIMES != 0 {
+                        let mtimes: MtimeList = (known..len).map(|idx| latest.mtime(idx)).collect();
+                        mtimes.send(&mut stream)?;
+                    }
+
+                    grow(&mut files, len, || DownloadableFile { done: false, file: None });
+                    grow(&mut priorities, len, || 0);
+                    grow(&mut next_priorities, len, || 0);
+                    grow(&mut ranges, len, || (0, RANGE_TO_END));
+                    grow(&mut stats.priorities, all, || 0);
+                    grow(&mut stats.delivered, all, || 0);
+                    source = latest;
+                    continue;
+                },
+                protocol::STAT => {
+                    let idx = protocol::recv_index(&mut stream)?;
+                    let Some(idx) = usize::try_from(idx).ok().filter(|idx| *idx < files.len()) else {
+                        return Err(Error::Protocol("stat of an unknown file"));
+                    };
+                    let all = subtree.as_ref().map_or(source.as_ref(), |subtree| subtree.inner());
+                    Stat {
+                        size: source.list()[idx].1,
+                        digest: self.hashes.cached(all, original(subtree.as_deref(), idx)),
+                        mtime: source.mtime(idx),
+                    }.send(&mut stream)?;
+                    continue;
+                },
+                // The last rounds of a batch are acknowledged after it.
+                protocol::ACK => match &mut window {
+                    Some(window) => {
+                        window.ack(protocol::recv_index(&mut stream)?)?;
+                        continue;
+                    },
+                    None => return Err(Error::Protocol("unknown message kind")),
+                },
+                protocol::HASHES => {
+                    // Digests computed at startup don't cover the files found since.
+                    let mut hashes = self.hashes_of(source.as_ref(), subtree.as_deref()).into_vec();
+                    hashes.resize(source.list().len(), None);
+                    HashList::from(hashes).send(&mut stream)?;
+                    continue;
+                },
+                _ => return Err(Error::Protocol("unknown message kind")),
+            }
             let mut to_download = priority_list::merge(&mut priorities, &next_priorities);
+            for (idx, priority) in priorities.iter().enumerate() {
+                stats.priorities[original(subtree.as_deref(), idx)] = *priority;
+            }
 
+            let member = self.scheduler.as_ref().map(|scheduler| scheduler.join());
+            let mut last_check = Instant::now();
             while to_download > 0 {
-                for ((handler, path), priority) in files.iter_mut()
-                    .zip(self.path_list.iter())
-                    .zip(priorities.iter()) {
+                let check_due = last_check.elapsed() >= CHANGE_CHECK_INTERVAL;
+                if check_due {
+                    last_check = Instant::now();
+                }
+                let turn = member.as_ref().map(|member| member.turn());
+                // With a turn held the round is gathered here and written once the turn is
+                // passed on, so that a client that stopped reading only blocks itself.
+                let mut buffered = Vec::new();
+                let mut out: &mut dyn Write = if turn.is_some() { &mut buffered } else { &mut stream };
+                let mut round: Box<[u8]> = files.iter()
+                    .zip(priorities.iter())
+                    .map(|(handler, priority)| if handler.done { 0 } else { *priority })
+                    .collect();
+                if let Some(budget) = round_budget {
+                    priority_list::limit_round(&mut round, budget);
+                }
+                for (idx, ((handler, priority), range)) in files.iter_mut()
+                    .zip(round.iter())
+                    .zip(ranges.iter())
+                    .enumerate() {
                     if *priority == 0 || handler.done {
                         continue;
                     }
 
-                    let opened = handler.file.get_or_insert_with(|| {
-                        File::open(path).unwrap()
-                    });
+                    // Ending a file early makes the client see that it's incomplete, rather
+                    // than saving bytes that don't match what was advertised.
+                    let opened = if (handler.file.is_none() || check_due) && source.changed(idx) {
+                        Err(format!("`{}` changed since it was listed", source.path(idx).display()))
+                    } else {
+                        match &mut handler.file {
+                            Some(file) => Ok(file),
+                            None => source.open(idx, *range)
+                                .map(|file| handler.file.insert(file))
+                                .map_err(|err| format!("Failed to open `{}`: {err}", source.path(idx).display())),
+                        }
+                    };
+                    let opened = match opened {
+                        Ok(opened) => opened,
+                        Err(msg) => {
+                            eprintln!("ERROR: {msg}");
+                            Chunk::empty().send(&mut out)?;
+                            handler.done = true;
+                            drop(handler.file.take());
+                            to_download -= 1;
+                            continue;
+                        }
+                    };
                     for _ in 0..*priority {
-                        let chunk = Chunk::read(opened)?;
+                        let mut chunk = Chunk::read(opened.as_mut())?;
+                        if let Some(injector) = &mut injector {
+                            chunk = injector.apply(chunk)?;
+                        }
 
-                        chunk.send(&mut stream)?;
+                        // A client that went away makes this fail with `BrokenPipe` rather
+                        // than kill the server: the Rust runtime ignores SIGPIPE on Unix
+                        // before `main` runs, so the session just ends with the error.
+                        chunk.send_with(&mut out, codec, level)?;
+                        stats.delivered[original(subtree.as_deref(), idx)] += chunk.len as u64;
 
                         if chunk.end() {
                             handler.done = true;
@@ -49,143 +334,1506 @@ impl WorkerContext {
                         }
                     }
                 }
+                drop(turn);
+                stream.write_all(&buffered)?;
+                if let Some(window) = &mut window {
+                    window.send();
+                    // The client sends nothing but acknowledgements while receiving.
+                    while !window.is_open() {
+                        let mut kind = [0; 1];
+                        stream.read_exact(&mut kind)?;
+                        if kind[0] != protocol::ACK {
+                            return Err(Error::Protocol("expected the acknowledgement of a round"));
+                        }
+                        window.ack(protocol::recv_index(&mut stream)?)?;
+                    }
+                }
             }
         }
     }
 }
 
+/// What the advertised files are ordered by. Ties are broken by name.
+#[derive(Clone, Copy)]
+enum SortBy {
+    Name,
+    Size,
+    Mtime,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HashMode {
+    Off,
+    /// Hash every file before serving.
+    Eager,
+    /// Hash when clients connect.
+    Lazy,
+}
+
+#[derive(Clone)]
 struct Config {
     thread_count: usize,
-    ip: Box<str>,
-    port: Box<str>,
+    /// Every address gets a listener of its own on `port`.
+    ips: Vec<IpAddr>,
+    port: u16,
     input_dir: PathBuf,
+    archive: Option<PathBuf>,
+    /// Files to serve instead of scanning `input_dir`, from `WRITE_FILE_LIST`.
+    file_list: Option<PathBuf>,
+    /// Write the files that would be served to this path and exit.
+    write_file_list: Option<PathBuf>,
+    /// Refuse to start without any file to serve.
+    require_files: bool,
+    sort_by: SortBy,
+    sort_descending: bool,
+    scan_progress: Option<Duration>,
+    scan_max_files: Option<usize>,
+    scan_timeout: Option<Duration>,
+    /// How often the input directory is scanned again for new files.
+    rescan_interval: Option<Duration>,
+    max_name_len: Option<usize>,
+    truncate_names: bool,
+    normalize_names: bool,
+    decompress_gz: bool,
+    compression_level: Option<i32>,
+    check_readable: bool,
+    hash_mode: HashMode,
+    hash_cache: PathBuf,
+    hash_threads: usize,
+    /// Lines of `name PRIORITY` recommending priorities to the clients.
+    priority_hints: Option<PathBuf>,
+    fair_scheduling: bool,
+    announce: bool,
+    discovery_port: u16,
+    unix_sock: Option<PathBuf>,
+    burst_threads: usize,
+    /// Files generated on the fly, served after the ones on disk.
+    synthetic_files: Vec<(Box<str>, u64, Pattern)>,
+    /// Answer `protocol::HEALTH_PROBE` on the TCP port.
+    health_check: bool,
+    /// `protocol::FEATURE_*` that clients are turned away without.
+    required_features: u32,
+    /// Chunks sent to a client per round at most, whatever its priorities add up to.
+    round_chunks: Option<u32>,
+    /// Connections accepted per second at most, across all listeners.
+    accept_rate: Option<u32>,
+    /// Close connections beyond `accept_rate` right away instead of making them wait.
+    accept_reject: bool,
+    /// UDP port clients can also download from over QUIC, on the first of `ips`.
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    quic_port: Option<u16>,
+    /// Where the certificate QUIC clients are to trust is written.
+    #[cfg_attr(not(feature = "quic"), allow(dead_code))]
+    quic_cert: PathBuf,
+    fault: Option<Fault>,
 }
 
 impl Config {
     fn get() -> Self {
-        Self {
-            thread_count: if let Ok(count) = env::var("THREAD_COUNT") {
-                count.parse().unwrap()
-            } else {
-                thread::available_parallelism().unwrap().get()
+        // Like `env::var`, variables that aren't UTF-8 are taken as unset.
+        let vars = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Self::from_env(&vars).unwrap_or_else(|err| {
+            eprintln!("ERROR: {err}");
+            process::exit(1);
+        })
+    }
+
+    /// Reads the configuration from the environment variables `vars`, failing with a
+    /// message for the first one that's invalid.
+    fn from_env(vars: &HashMap<String, String>) -> Result<Self, String> {
+        let var = |name: &str| vars.get(name).cloned().ok_or(env::VarError::NotPresent);
+        let thread_count = match var("THREAD_COUNT") {
+            Ok(count) => match count.parse() {
+                Ok(0) | Err(_) => return Err(format!("`THREAD_COUNT` expects a positive number, got `{count}`")),
+                Ok(count) if count > MAX_THREAD_COUNT => {
+                    eprintln!("WARNING: Limiting `THREAD_COUNT` of {count} to {MAX_THREAD_COUNT} workers");
+                    MAX_THREAD_COUNT
+                },
+                Ok(count) => count,
             },
-            ip: if let Ok(ip) = env::var("IP") {
-                ip.into()
-            } else {
-                "127.0.0.1".into()
+            Err(_) => thread::available_parallelism().map(|count| count.get()).unwrap_or(1),
+        };
+
+        Ok(Self {
+            thread_count,
+            ips: match var("IP") {
+                Ok(ips) => ips.split(',')
+                    .map(|ip| ip.trim().parse().map_err(|_| format!("`IP` expects comma separated IPv4 or IPv6 addresses, got `{ip}`")))
+                    .collect::<Result<_, _>>()?,
+                Err(_) => vec![Ipv4Addr::LOCALHOST.into()],
             },
-            port: if let Ok(port) = env::var("PORT") {
-                port.into()
-            } else {
-                "3000".into()
+            port: match var("PORT") {
+                Ok(port) => match port.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`PORT` must be a number from 1 to 65535, got `{port}`")),
+                    Ok(port) => port,
+                },
+                Err(_) => 3000,
             },
-            input_dir: if let Ok(input_dir) = env::var("INPUT_DIR") {
+            input_dir: if let Ok(input_dir) = var("INPUT_DIR") {
                 input_dir.into()
             } else {
                 "input".into()
             },
+            archive: var("ARCHIVE").ok().map(PathBuf::from),
+            file_list: match (var("FILE_LIST"), var("ARCHIVE")) {
+                (Ok(_), Ok(_)) => return Err("`FILE_LIST` and `ARCHIVE` can't be used together".into()),
+                (file_list, _) => file_list.ok().map(PathBuf::from),
+            },
+            write_file_list: var("WRITE_FILE_LIST").ok().map(PathBuf::from),
+            require_files: var("REQUIRE_FILES").is_ok(),
+            sort_by: match var("SORT_BY").as_deref() {
+                Ok("name") | Err(_) => SortBy::Name,
+                Ok("size") => SortBy::Size,
+                Ok("mtime") => SortBy::Mtime,
+                Ok(key) => return Err(format!("Unknown sort key `{key}`, expected `name`, `size` or `mtime`")),
+            },
+            sort_descending: var("SORT_DESCENDING").is_ok(),
+            scan_progress: match var("SCAN_PROGRESS") {
+                Ok(secs) => match secs.parse() {
+                    Ok(secs) => Some(Duration::from_secs(secs)).filter(|interval| !interval.is_zero()),
+                    Err(_) => return Err(format!("`SCAN_PROGRESS` expects a number of seconds, or 0 to stay quiet, got `{secs}`")),
+                },
+                Err(_) => Some(Duration::from_secs(5)),
+            },
+            scan_max_files: match var("SCAN_MAX_FILES") {
+                Ok(count) => match count.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`SCAN_MAX_FILES` expects a positive number, got `{count}`")),
+                    Ok(count) => Some(count),
+                },
+                Err(_) => None,
+            },
+            scan_timeout: match var("SCAN_TIMEOUT") {
+                Ok(secs) => match secs.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`SCAN_TIMEOUT` expects a positive number of seconds, got `{secs}`")),
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                },
+                Err(_) => None,
+            },
+            rescan_interval: match var("RESCAN_INTERVAL") {
+                Ok(secs) => match secs.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`RESCAN_INTERVAL` expects a positive number of seconds, got `{secs}`")),
+                    Ok(secs) => Some(Duration::from_secs(secs)),
+                },
+                Err(_) => None,
+            },
+            max_name_len: match var("MAX_NAME_LEN") {
+                Ok(len) => match len.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`MAX_NAME_LEN` expects a positive number of bytes, got `{len}`")),
+                    Ok(len) => Some(len),
+                },
+                Err(_) => None,
+            },
+            truncate_names: var("TRUNCATE_NAMES").is_ok(),
+            normalize_names: var("NORMALIZE_NAMES").is_ok(),
+            decompress_gz: var("DECOMPRESS_GZ").is_ok(),
+            compression_level: match var("COMPRESSION_LEVEL") {
+                Ok(level) => {
+                    let (deflate, zstd) = (Codec::Deflate.levels(), Codec::Zstd.levels());
+                    match level.parse() {
+                        Ok(level) if zstd.contains(&level) => {
+                            if !deflate.contains(&level) {
+                                eprintln!("WARNING: Deflate compresses at levels {} to {}, clients using it get the closest one to {level}", deflate.start(), deflate.end());
+                            }
+                            Some(level)
+                        },
+                        _ => return Err(format!("`COMPRESSION_LEVEL` expects a level from {} to {}, got `{level}`", zstd.start(), zstd.end())),
+                    }
+                },
+                Err(_) => None,
+            },
+            check_readable: var("CHECK_READABLE").is_ok(),
+            hash_mode: match var("HASH_FILES").as_deref() {
+                Ok("lazy") => HashMode::Lazy,
+                Ok(_) => HashMode::Eager,
+                Err(_) => HashMode::Off,
+            },
+            hash_cache: if let Ok(hash_cache) = var("HASH_CACHE") {
+                hash_cache.into()
+            } else {
+                ".hash-cache".into()
+            },
+            hash_threads: match var("HASH_THREADS") {
+                Ok(count) => match count.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`HASH_THREADS` expects a positive number, got `{count}`")),
+                    Ok(count) if count > MAX_THREAD_COUNT => {
+                        eprintln!("WARNING: Limiting `HASH_THREADS` of {count} to {MAX_THREAD_COUNT} threads");
+                        MAX_THREAD_COUNT
+                    },
+                    Ok(count) => count,
+                },
+                Err(_) => thread_count,
+            },
+            priority_hints: var("PRIORITY_HINTS").ok().map(PathBuf::from),
+            fair_scheduling: var("FAIR_SCHEDULING").is_ok(),
+            announce: var("ANNOUNCE").is_ok(),
+            discovery_port: match var("DISCOVERY_PORT") {
+                Ok(port) => match port.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`DISCOVERY_PORT` must be a number from 1 to 65535, got `{port}`")),
+                    Ok(port) => port,
+                },
+                Err(_) => discovery::PORT,
+            },
+            unix_sock: var("UNIX_SOCK").ok().map(PathBuf::from),
+            burst_threads: match var("BURST_THREADS") {
+                Ok(count) => match count.parse() {
+                    Ok(count) if count > MAX_THREAD_COUNT => {
+                        eprintln!("WARNING: Limiting `BURST_THREADS` of {count} to {MAX_THREAD_COUNT} threads");
+                        MAX_THREAD_COUNT
+                    },
+                    Ok(count) => count,
+                    Err(_) => return Err(format!("`BURST_THREADS` expects a number, or 0 to only use the workers, got `{count}`")),
+                },
+                Err(_) => 0,
+            },
+            synthetic_files: match var("SYNTHETIC_FILES") {
+                Ok(specs) => specs.split(',')
+                    .map(|spec| parse_synthetic(spec).ok_or_else(|| format!("`SYNTHETIC_FILES` expects `name:size[:zeros|random]` entries, got `{spec}`")))
+                    .collect::<Result<_, _>>()?,
+                Err(_) => Vec::new(),
+            },
+            health_check: var("HEALTH_CHECK").is_ok(),
+            required_features: match var("REQUIRE_FEATURES") {
+                Ok(names) => names.split(',')
+                    .map(|name| protocol::feature_from_name(name.trim()).ok_or_else(|| format!(
+                        "Unknown feature `{name}` in `REQUIRE_FEATURES`, expected `hashes`, `flags`, `restart`, `hints`, `append`, `hash-request`, `mtimes`, `subpath`, `round-budget`, `stat`, `compressed-list` or `window`"
+                    )))
+                    .try_fold(0, |required, feature| feature.map(|feature| required | feature))?,
+                Err(_) => 0,
+            },
+            round_chunks: match var("ROUND_CHUNKS") {
+                Ok(chunks) => match chunks.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`ROUND_CHUNKS` expects a positive number, got `{chunks}`")),
+                    Ok(chunks) => Some(chunks),
+                },
+                Err(_) => None,
+            },
+            accept_rate: match var("ACCEPT_RATE") {
+                Ok(rate) => match rate.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`ACCEPT_RATE` expects a positive number of connections per second, got `{rate}`")),
+                    Ok(rate) => Some(rate),
+                },
+                Err(_) => None,
+            },
+            accept_reject: var("ACCEPT_REJECT").is_ok(),
+            quic_port: match var("QUIC_PORT") {
+                Ok(_) if !cfg!(feature = "quic") => {
+                    eprintln!("WARNING: Ignoring `QUIC_PORT`, the server was built without the `quic` feature");
+                    None
+                },
+                Ok(port) => match port.parse() {
+                    Ok(0) | Err(_) => return Err(format!("`QUIC_PORT` must be a number from 1 to 65535, got `{port}`")),
+                    Ok(port) => Some(port),
+                },
+                Err(_) => None,
+            },
+            quic_cert: if let Ok(quic_cert) = var("QUIC_CERT") {
+                quic_cert.into()
+            } else {
+                "quic-cert.der".into()
+            },
+            fault: Fault::from_env(vars)?,
+        })
+    }
+}
+
+/// Parses `name:size[:pattern]`, where the size may end in `K`, `M` or `G` and the
+/// pattern defaults to zeros.
+fn parse_synthetic(spec: &str) -> Option<(Box<str>, u64, Pattern)> {
+    let mut iter = spec.split(':');
+    let name = iter.next().filter(|name| !name.is_empty())?;
+    let size = iter.next()?;
+    let pattern = Pattern::from_name(iter.next().unwrap_or("zeros"), name)?;
+    if iter.next().is_some() {
+        return None;
+    }
+
+    let (digits, unit) = match size.char_indices().last()? {
+        (idx, 'K') => (&size[..idx], 1 << 10),
+        (idx, 'M') => (&size[..idx], 1 << 20),
+        (idx, 'G') => (&size[..idx], 1 << 30),
+        _ => (size, 1),
+    };
+    let size = digits.parse::<u64>().ok()?.checked_mul(unit)?;
+    Some((name.into(), size, pattern))
+}
+
+/// Describes the kind of a directory entry that can't be served, `None` for regular files.
+fn unservable_kind(file_type: fs::FileType) -> Option<&'static str> {
+    if file_type.is_file() {
+        return None;
+    }
+    if file_type.is_dir() {
+        return Some("a directory");
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return Some("a FIFO");
+        }
+        if file_type.is_socket() {
+            return Some("a socket");
+        }
+        if file_type.is_block_device() {
+            return Some("a block device");
+        }
+        if file_type.is_char_device() {
+            return Some("a character device");
         }
     }
+
+    Some("not a regular file")
 }
 
-fn get_files(input_dir: &Path) -> (FileList, Box<[PathBuf]>) {
-    let files = match input_dir.read_dir() {
-        Ok(files) => files,
+/// Broadcasts the listening port every second so that `--discover` clients can find
+/// this server. Stops at the first failure, the server itself keeps running.
+fn announce(ip: IpAddr, port: u16, discovery_port: u16) -> io::Result<()> {
+    let socket = UdpSocket::bind((ip, 0))?;
+    socket.set_broadcast(true)?;
+    let packet = discovery::encode(port);
+    loop {
+        socket.send_to(&packet, ("255.255.255.255", discovery_port))?;
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+fn decompressed_size(path: &Path) -> io::Result<u64> {
+    io::copy(&mut MultiGzDecoder::new(File::open(path)?), &mut io::sink())
+}
+
+/// Lists the served files in the configured order, `read_dir` doesn't promise any.
+fn get_files(opt: &Config, listed: Option<&[file_list::Entry]>) -> Filesystem {
+    let (files, sources) = match (&opt.archive, listed) {
+        (Some(archive), _) => archive::list(archive).unwrap_or_else(|err| {
+            eprintln!("ERROR: Failed to read archive `{}`: {err}", archive.display());
+            ([].into(), [].into())
+        }),
+        (None, Some(listed)) => check_listed(listed, opt),
+        (None, None) => scan_input_dir(opt),
+    };
+    let (files, sources) = rename_files(files, sources, opt);
+    let (files, sources) = add_synthetic(files, sources, opt);
+
+    let mtimes: Vec<_> = match opt.sort_by {
+        SortBy::Mtime => sources.iter()
+            .map(|source| source.backing_file().and_then(|path| path.metadata().and_then(|metadata| metadata.modified()).ok()))
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    order.sort_by(|a, b| {
+        let ordering = match opt.sort_by {
+            SortBy::Name => cmp::Ordering::Equal,
+            SortBy::Size => files[*a].1.cmp(&files[*b].1),
+            SortBy::Mtime => mtimes[*a].cmp(&mtimes[*b]),
+        }.then_with(|| files[*a].0.cmp(&files[*b].0));
+        if opt.sort_descending { ordering.reverse() } else { ordering }
+    });
+
+    Filesystem::new(
+        order.iter().map(|idx| files[*idx].clone()).collect(),
+        order.iter().map(|idx| sources[*idx].clone()).collect(),
+        opt.decompress_gz,
+    )
+}
+
+/// Reads the recommended priorities of the served files, written like the input file of
+/// the client. Lines that don't apply to any served file are skipped with a warning.
+fn read_hints(path: &Path, files: &FileList) -> HintList {
+    let mut hints = priority_list::new(files.len());
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
         Err(err) => {
-            eprintln!("ERROR: Failed to read directory `{}`: {err}", input_dir.display());
-            return ([].into(), [].into());
+            eprintln!("ERROR: Failed to read priority hints `{}`: {err}", path.display());
+            return hints;
         }
     };
 
-    let iter = files.into_iter().filter_map(|entry| {
-        let file = match entry {
-            Ok(file) => file.path(),
-            Err(err) => {
-                eprintln!("ERROR: {err}");
+    let indices: HashMap<&str, usize> = files.iter().enumerate().map(|(idx, (name, _))| (name.as_ref(), idx)).collect();
+    for line in content.lines() {
+        let mut iter = line.split_whitespace();
+        let (Some(name), Some(priority)) = (iter.next(), iter.next()) else {
+            continue;
+        };
+        let Some(priority) = priority_list::parse(priority) else {
+            eprintln!("WARNING: Unknown priority `{priority}` for `{name}` in `{}`", path.display());
+            continue;
+        };
+        match indices.get(name) {
+            Some(idx) => hints[*idx] = priority,
+            None => eprintln!("WARNING: Not hinting `{name}`, it isn't served"),
+        }
+    }
+    hints
+}
+
+/// Normalizes `name` to NFC and fits it into `MAX_NAME_LEN` bytes if configured to,
+/// returning `None` when the name has to be skipped.
+fn served_name(name: &str, opt: &Config) -> Option<Box<str>> {
+    let mut served = if opt.normalize_names && !is_nfc(name) {
+        let normalized: String = name.nfc().collect();
+        eprintln!("WARNING: Serving `{name}` as `{normalized}`, its NFC form");
+        normalized
+    } else {
+        name.to_string()
+    };
+
+    if let Some(max_len) = opt.max_name_len {
+        if served.len() > max_len {
+            if !opt.truncate_names {
+                eprintln!("WARNING: Skipping `{name}`, its name is longer than {max_len} bytes");
                 return None;
             }
-        };
+            let end = (0..=max_len).rev().find(|end| served.is_char_boundary(*end)).unwrap_or(0);
+            served.truncate(end);
+            eprintln!("WARNING: Serving `{name}` as `{served}`, truncated to {max_len} bytes");
+        }
+    }
+
+    if served.is_empty() {
+        eprintln!("WARNING: Skipping `{name}`, nothing is left of its name");
+        return None;
+    }
+    Some(served.into())
+}
+
+/// Applies `served_name` to every file, dropping the ones whose new names collide.
+fn rename_files(files: FileList, sources: Box<[Source]>, opt: &Config) -> (FileList, Box<[Source]>) {
+    if opt.max_name_len.is_none() && !opt.normalize_names {
+        return (files, sources);
+    }
+
+    let mut seen = HashSet::new();
+    let (files, sources): (Vec<_>, Vec<_>) = files.iter().zip(sources).filter_map(|((name, size), source)| {
+        let served = served_name(name, opt)?;
+        if !seen.insert(served.clone()) {
+            eprintln!("ERROR: Skipping `{name}`, another file is already served as `{served}`");
+            return None;
+        }
+        Some(((served, *size), source))
+    }).unzip();
+    (files.into(), sources.into())
+}
+
+/// Adds the configured synthetic files, unless a file on disk is already served under
+/// the same name.
+fn add_synthetic(files: FileList, sources: Box<[Source]>, opt: &Config) -> (FileList, Box<[Source]>) {
+    if opt.synthetic_files.is_empty() {
+        return (files, sources);
+    }
+
+    let (mut files, mut sources) = (files.into_vec(), sources.into_vec());
+    let mut seen: HashSet<Box<str>> = files.iter().map(|(name, _)| name.clone()).collect();
+    for (name, size, pattern) in opt.synthetic_files.iter() {
+        if !seen.insert(name.clone()) {
+            eprintln!("ERROR: Skipping synthetic file `{name}`, another file is already served under that name");
+            continue;
+        }
+        files.push((name.clone(), *size));
+        sources.push(Source::Synthetic { name: name.clone(), size: *size, pattern: *pattern });
+    }
+    (files.into(), sources.into())
+}
 
-        if !file.is_file() {
+/// Checks whether the directory entry can be served and under which name.
+fn scan_entry(entry: io::Result<fs::DirEntry>, opt: &Config, seen: &mut HashSet<Box<str>>) -> Option<((Box<str>, u64), Source)> {
+    let file = match entry {
+        Ok(file) => file.path(),
+        Err(err) => {
+            eprintln!("ERROR: {err}");
             return None;
         }
+    };
 
-        let name: Box<str> = file.file_name()?.to_str()?.into();
-        if name.contains('\0') {
-            eprintln!("ERROR: Name `{name}` contains the null-terminator");
+    let metadata = match fs::metadata(&file) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if fs::symlink_metadata(&file).is_ok() {
+                eprintln!("WARNING: Skipping `{}`, it is a dangling symlink", file.display());
+            } else {
+                eprintln!("WARNING: Skipping `{}`, it disappeared during the scan", file.display());
+            }
+            return None;
+        },
+        Err(err) => {
+            eprintln!("ERROR: Failed to read metadata of `{}`: {err}", file.display());
             return None;
         }
+    };
+
+    if let Some(kind) = unservable_kind(metadata.file_type()) {
+        eprintln!("WARNING: Skipping `{}`, it is {kind}", file.display());
+        return None;
+    }
 
-        let size = match file.metadata() {
-            Ok(metadata) => metadata.len(),
+    if opt.check_readable {
+        if let Err(err) = File::open(&file) {
+            eprintln!("ERROR: Skipping unreadable file `{}`: {err}", file.display());
+            return None;
+        }
+    }
+
+    let decompress = opt.decompress_gz && is_gz(&file);
+    let name: Box<str> = if decompress {
+        file.file_stem()?.to_str()?.into()
+    } else {
+        file.file_name()?.to_str()?.into()
+    };
+    if name.contains('\0') {
+        eprintln!("ERROR: Name `{name}` contains the null-terminator");
+        return None;
+    }
+
+    let size = if decompress {
+        decompressed_size(&file)
+    } else {
+        Ok(metadata.len())
+    };
+    let size = match size {
+        Ok(size) => size,
+        Err(err) => {
+            eprintln!("ERROR: Failed to get size of file `{}`: {err}", file.display());
+            return None;
+        }
+    };
+
+    if !seen.insert(name.clone()) {
+        eprintln!("ERROR: Skipping `{}`, a file named `{name}` is already served", file.display());
+        return None;
+    }
+
+    Some(((name, size), Source::File(file)))
+}
+
+/// Serves the files of a file list without scanning anything. Files that are gone or
+/// whose size changed since they were listed are skipped, which only takes one look at the
+/// metadata of every file.
+fn check_listed(listed: &[file_list::Entry], opt: &Config) -> (FileList, Box<[Source]>) {
+    let mut seen = HashSet::new();
+    let (files, sources): (Vec<_>, Vec<_>) = listed.iter().filter_map(|entry| {
+        let path = &entry.path;
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            Ok(_) => {
+                eprintln!("WARNING: Skipping listed `{}`, it isn't a regular file", path.display());
+                return None;
+            },
             Err(err) => {
-                eprintln!("ERROR: Failed to get size of file `{}`: {err}", file.display());
+                eprintln!("WARNING: Skipping listed `{}`: {err}", path.display());
                 return None;
-            }
+            },
         };
-
-        Some(((name, size), file))
+        // The size of a decompressed file is only known by decompressing it.
+        if !(opt.decompress_gz && is_gz(path)) && metadata.len() != entry.size {
+            eprintln!("WARNING: Skipping listed `{}`, it has {} bytes instead of {}", path.display(), metadata.len(), entry.size);
+            return None;
+        }
+        if !seen.insert(entry.name.clone()) {
+            eprintln!("ERROR: Skipping `{}`, a file named `{}` is already served", path.display(), entry.name);
+            return None;
+        }
+        Some(((entry.name.clone(), entry.size), Source::File(path.clone())))
     }).unzip();
+    (files.into(), sources.into())
+}
 
-    let (files, paths): (Vec<_>, Vec<_>) = iter;
-    (files.into(), paths.into())
+fn scan_input_dir(opt: &Config) -> (FileList, Box<[Source]>) {
+    let input_dir = &opt.input_dir;
+    let files = match input_dir.read_dir() {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("ERROR: Failed to read directory `{}`: {err}", input_dir.display());
+            return ([].into(), [].into());
+        }
+    };
+
+    let started = Instant::now();
+    let mut last_report = started;
+    let (mut scanned, mut found, mut bytes) = (0, 0, 0);
+    let mut seen = HashSet::new();
+    let (files, sources): (Vec<_>, Vec<_>) = files.into_iter().map_while(|entry| {
+        if opt.scan_max_files.is_some_and(|max| scanned >= max) {
+            eprintln!("WARNING: Stopped scanning `{}` after {scanned} entries, the limit set by SCAN_MAX_FILES", input_dir.display());
+            return None;
+        }
+        if opt.scan_timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            eprintln!("WARNING: Stopped scanning `{}` after {scanned} entries, it took longer than SCAN_TIMEOUT", input_dir.display());
+            return None;
+        }
+
+        scanned += 1;
+        let file = scan_entry(entry, opt, &mut seen);
+        if let Some(((_, size), _)) = &file {
+            found += 1;
+            bytes += size;
+        }
+        if opt.scan_progress.is_some_and(|interval| last_report.elapsed() >= interval) {
+            println!("Scanned {scanned} entries so far, {found} files with {bytes} bytes to serve");
+            last_report = Instant::now();
+        }
+        Some(file)
+    }).flatten().unzip();
+
+    if last_report != started {
+        println!("Scanned {scanned} entries in {:.1}s, {found} files with {bytes} bytes to serve", started.elapsed().as_secs_f64());
+    }
+    (files.into(), sources.into())
+}
+
+/// Narrows a list about every served file down to the subtree a client asked for.
+fn narrow<T: Clone + Default>(list: &[T], subtree: Option<&Subtree>) -> Box<[T]> {
+    match subtree {
+        Some(subtree) => subtree.select(list),
+        None => list.into(),
+    }
+}
+
+/// The index among every served file of file `idx` of a session.
+fn original(subtree: Option<&Subtree>, idx: usize) -> usize {
+    subtree.map_or(idx, |subtree| subtree.original(idx))
+}
+
+/// Serves one client to completion and logs how the session went.
+fn serve_client(ctx: &WorkerContext, label: &str, stream: Stream) {
+    let ip = stream.peer_addr();
+    let mut stats = SessionStats::new(ctx.source.list().len());
+    if let Err(err) = ctx.execute(stream, &mut stats) {
+        eprintln!("[{label}] {err}")
+    }
+    println!("{}", stats.to_json(ctx.latest_source().list(), ip));
+    match ip {
+        Some(addr) => println!("[{label}] Client `{addr}` disconnected"),
+        None => println!("[{label}] Local client disconnected"),
+    }
+}
+
+/// Scans the input directory every `interval` and starts serving the files that weren't
+/// there before, in name order after the others.
+fn rescan(catalog: &Catalog, opt: &Config, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let (files, sources) = scan_input_dir(opt);
+        let (files, sources) = rename_files(files, sources, opt);
+
+        let latest = catalog.latest();
+        let known: HashSet<&str> = latest.list().iter().map(|(name, _)| name.as_ref()).collect();
+        let mut added: Vec<_> = files.iter().cloned().zip(sources)
+            .filter(|((name, _), _)| !known.contains(name.as_ref()))
+            .collect();
+        if added.is_empty() {
+            continue;
+        }
+        added.sort_by(|((a, _), _), ((b, _), _)| a.cmp(b));
+
+        println!("Serving {} new files", added.len());
+        let (files, sources): (Vec<_>, Vec<_>) = added.into_iter().unzip();
+        catalog.append(files, sources);
+    }
+}
+
+fn log_connected(label: &str, stream: &Stream) {
+    match stream.peer_addr() {
+        Some(addr) => println!("[{label}] Client `{addr}` connected"),
+        None => println!("[{label}] Local client connected"),
+    }
+}
+
+/// Temporary threads that each serve a single connection while every worker is busy.
+struct Burst {
+    ctx: Arc<WorkerContext>,
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl Burst {
+    /// Serves `stream` on a new thread, unless `max` of them are running already, in which
+    /// case the stream is handed back.
+    fn spawn(&self, stream: Stream) -> Result<(), Stream> {
+        if self.active.fetch_add(1, Ordering::SeqCst) >= self.max {
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            return Err(stream);
+        }
+
+        log_connected("Burst thread", &stream);
+        let ctx = self.ctx.clone();
+        let active = self.active.clone();
+        thread::spawn(move || {
+            serve_client(&ctx, "Burst thread", stream);
+            active.fetch_sub(1, Ordering::SeqCst);
+        });
+        Ok(())
+    }
+}
+
+/// The worker threads, each announcing its id on `receiver` when it's idle.
+struct Pool {
+    /// Locked while a connection is handed to a worker, so that they're handed in turn.
+    receiver: Mutex<mpsc::Receiver<usize>>,
+    workers: Vec<mpsc::Sender<Stream>>,
+    /// How many of the workers are serving a client.
+    busy: Arc<AtomicUsize>,
+    burst: Burst,
+    health_check: bool,
+    accept_rate: Option<u32>,
+    accept_reject: bool,
+}
+
+/// Whether `stream` starts with a health probe rather than the codec of a client. Only
+/// peeks, so that a client's bytes are left for its worker.
+fn is_probe(stream: &TcpStream) -> bool {
+    let mut buf = [0; protocol::HEALTH_PROBE.len()];
+    let _ = stream.set_read_timeout(Some(PROBE_TIMEOUT));
+    let peeked = stream.peek(&mut buf);
+    let _ = stream.set_read_timeout(None);
+    matches!(peeked, Ok(len) if len > 0 && protocol::HEALTH_PROBE.starts_with(&buf[..len]))
+}
+
+fn answer_probe(mut stream: &TcpStream, pool: &Pool) -> io::Result<()> {
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    let mut probe = [0; protocol::HEALTH_PROBE.len()];
+    stream.read_exact(&mut probe)?;
+    if probe != protocol::HEALTH_PROBE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete health probe"));
+    }
+    let workers = pool.workers.len();
+    let idle = workers.saturating_sub(pool.busy.load(Ordering::SeqCst));
+    stream.write_all(format!("OK {idle} {workers}\n").as_bytes())
+}
+
+/// Paces connections to `rate` a second, like a bucket of up to a second's worth of
+/// tokens that refills at that rate.
+struct AcceptRate {
+    interval: Duration,
+    /// When the last connection let through was due.
+    last_due: Option<Instant>,
+}
+
+impl AcceptRate {
+    fn new(rate: u32) -> Self {
+        Self { interval: Duration::from_secs(1) / rate, last_due: None }
+    }
+
+    /// When a connection arriving at `now` is due to be dispatched. With `reject`, one
+    /// that would have to wait gets `None` instead, and doesn't use up the rate.
+    fn admit(&mut self, now: Instant, reject: bool) -> Option<Instant> {
+        let since = now.checked_sub(Duration::from_secs(1)).unwrap_or(now);
+        let due = self.last_due.map_or(since, |last_due| last_due.max(since)) + self.interval;
+        if due > now && reject {
+            return None;
+        }
+        self.last_due = Some(due);
+        Some(due)
+    }
+}
+
+/// Hands every incoming connection to a worker with `hand_off`. When health probes are
+/// answered, TCP connections are told apart from probes on short-lived threads first.
+///
+/// With an accept rate, connections beyond it wait before being dispatched, and while
+/// they do the ones behind them queue up in the listen backlog until the OS refuses more.
+/// Up to a second's worth is let through at once after a quiet period. When rejecting,
+/// they're closed instead, so that a storm of reconnecting clients backs off and retries
+/// rather than piling up.
+fn dispatch(incoming: impl Iterator<Item = io::Result<Stream>>, pool: &Pool) {
+    let mut accept_rate = pool.accept_rate.map(AcceptRate::new);
+    thread::scope(|scope| {
+        for stream in incoming {
+            if let Some(accept_rate) = &mut accept_rate {
+                let now = Instant::now();
+                let Some(due) = accept_rate.admit(now, pool.accept_reject) else {
+                    // Dropping the stream closes it.
+                    continue;
+                };
+                thread::sleep(due.saturating_duration_since(now));
+            }
+            match stream {
+                // Telling a probe apart waits for its first bytes, which mustn't hold up
+                // the connections behind it.
+                Ok(Stream::Tcp(stream)) if pool.health_check => {
+                    scope.spawn(move || {
+                        if !is_probe(&stream) {
+                            hand_off(Stream::Tcp(stream), pool);
+                        } else if let Err(err) = answer_probe(&stream, pool) {
+                            eprintln!("WARNING: Failed to answer health probe: {err}");
+                        }
+                    });
+                },
+                Ok(stream) => hand_off(stream, pool),
+                Err(err) => {
+                    eprintln!("ERROR: Failed to retrieve incoming stream: {err}");
+                }
+            }
+        }
+    });
+}
+
+/// Hands `stream` to the next idle worker, or to a burst thread when all of them are busy.
+/// Once the burst limit is reached too, waits for a worker.
+fn hand_off(stream: Stream, pool: &Pool) {
+    let receiver = pool.receiver.lock().unwrap();
+    let (worker_id, stream) = match receiver.try_recv() {
+        Ok(worker_id) => (worker_id, stream),
+        Err(_) => match pool.burst.spawn(stream) {
+            Ok(()) => return,
+            Err(stream) => (receiver.recv().unwrap(), stream),
+        },
+    };
+    drop(receiver);
+
+    log_connected(&format!("Thread {worker_id}"), &stream);
+    pool.workers[worker_id].send(stream).unwrap();
+}
+
+/// Like `TcpListener::bind`, but an IPv6 listener can be kept from accepting IPv4
+/// connections, so that another one can listen on the same port for those.
+fn bind(addr: SocketAddr, v6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only)?;
+    }
+    // What the standard library does too, so that a restarted server can bind right away.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// What to do about failing to bind `addr` with `err`, when it's likely a mistake in the
+/// configuration.
+fn bind_hint(err: &io::Error, addr: SocketAddr) -> Option<String> {
+    match err.kind() {
+        io::ErrorKind::AddrInUse => Some(format!("Another process already listens on port {}, pick another `PORT`", addr.port())),
+        io::ErrorKind::AddrNotAvailable => Some(format!("`{}` isn't an address of this machine, check `IP`", addr.ip())),
+        io::ErrorKind::PermissionDenied if addr.port() < 1024 => Some("Ports below 1024 are reserved for privileged processes, pick a higher `PORT`".into()),
+        _ => None,
+    }
+}
+
+/// Serves clients on the Unix domain socket at `path` instead of TCP. A socket left over
+/// from a previous run is replaced.
+#[cfg(unix)]
+fn serve_unix(path: &Path, pool: &Pool) -> ! {
+    use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        if let Err(err) = fs::remove_file(path) {
+            eprintln!("WARNING: Failed to remove stale socket `{}`: {err}", path.display());
+        }
+    }
+
+    let listener = match UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("ERROR: failed to bind Unix socket `{}`: {err}", path.display());
+            process::exit(1);
+        },
+    };
+
+    println!("Server listening on: unix://{}", path.display());
+    dispatch(listener.incoming().map(|stream| stream.map(Stream::Unix)), pool);
+    process::exit(0);
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_path: &Path, _pool: &Pool) -> ! {
+    eprintln!("ERROR: Unix domain sockets aren't supported on this platform");
+    process::exit(1);
 }
 
 fn main() {
     let opt = Config::get();
+    if opt.quic_port.is_some() {
+        // See `quic` for why these don't apply.
+        let unsupported = [
+            ("FAIR_SCHEDULING", opt.fair_scheduling),
+            ("REQUIRE_FEATURES", opt.required_features != 0),
+            ("ROUND_CHUNKS", opt.round_chunks.is_some()),
+            ("ACCEPT_RATE", opt.accept_rate.is_some()),
+            ("ACCEPT_REJECT", opt.accept_reject),
+        ];
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            eprintln!("ERROR: `{name}` isn't supported over QUIC, unset it or `QUIC_PORT`");
+            process::exit(1);
+        }
+    }
 
     let (sender, receiver) = mpsc::channel();
     let mut workers = Vec::with_capacity(opt.thread_count);
+    let busy = Arc::new(AtomicUsize::new(0));
 
-    let (files, paths) = get_files(&opt.input_dir);
+    let listed = opt.file_list.as_ref().map(|path| file_list::load(path).unwrap_or_else(|err| {
+        eprintln!("ERROR: Failed to read file list `{}`: {err}", path.display());
+        process::exit(1);
+    }));
+    let files = Arc::new(get_files(&opt, listed.as_deref()));
+    let source: Arc<dyn FileSource> = files.clone();
+    // Digests in the file list are as good as cached ones, as long as the files weren't
+    // modified since.
+    let load_cache = || {
+        let mut cache = HashCache::load(&opt.hash_cache);
+        for entry in listed.iter().flatten() {
+            if let (Some(digest), Ok(stamp)) = (entry.digest, Stamp::of(&entry.path)) {
+                if stamp.mtime == entry.mtime {
+                    cache.insert(entry.path.clone(), stamp, digest);
+                }
+            }
+        }
+        cache
+    };
+    let hashes = Arc::new(match opt.hash_mode {
+        HashMode::Off => Hashes::OnRequest { threads: opt.hash_threads },
+        HashMode::Eager => Hashes::Fixed(load_cache().update(source.as_ref(), opt.hash_threads, false)),
+        HashMode::Lazy => Hashes::Lazy {
+            cache: Mutex::new(load_cache()),
+            hashing: Mutex::default(),
+            threads: opt.hash_threads,
+        },
+    });
+
+    if let Some(path) = &opt.write_file_list {
+        match file_list::save(path, source.as_ref(), &hashes) {
+            Ok(count) => println!("Listed {count} files in `{}`", path.display()),
+            Err(err) => {
+                eprintln!("ERROR: Failed to write file list `{}`: {err}", path.display());
+                process::exit(1);
+            },
+        }
+        return;
+    }
+
+    if source.list().is_empty() {
+        if opt.require_files {
+            eprintln!("ERROR: There are no files to serve");
+            process::exit(1);
+        }
+        if opt.rescan_interval.is_none() {
+            eprintln!("WARNING: There are no files to serve, clients are disconnected after the handshake");
+        }
+    }
+
+    let flags: FlagList = (0..source.list().len()).map(|idx| source.flags(idx)).collect();
+    let hints = match &opt.priority_hints {
+        Some(path) => read_hints(path, source.list()),
+        None => priority_list::new(source.list().len()),
+    };
+    let scheduler = opt.fair_scheduling.then(|| Arc::new(Scheduler::new()));
+    let catalog = match opt.rescan_interval {
+        Some(_) if opt.archive.is_some() => {
+            eprintln!("WARNING: Not rescanning, the files of an archive are all listed at startup");
+            None
+        },
+        Some(_) if opt.file_list.is_some() => {
+            eprintln!("WARNING: Not rescanning, the files of `FILE_LIST` are all listed at startup");
+            None
+        },
+        Some(interval) => {
+            let catalog = Arc::new(Catalog::new(files));
+            let (catalog_ref, opt) = (catalog.clone(), opt.clone());
+            thread::spawn(move || rescan(&catalog_ref, &opt, interval));
+            Some(catalog)
+        },
+        None => None,
+    };
 
     for id in 0..opt.thread_count {
         let local_sender = sender.clone();
-        let (worker_sender, worker_receiver) = mpsc::channel::<TcpStream>();
+        let (worker_sender, worker_receiver) = mpsc::channel::<Stream>();
 
-        let ctx = WorkerContext::new(&files, &paths);
+        let ctx = WorkerContext::new(source.clone(), hashes.clone(), &flags, &hints, catalog.clone(), scheduler.clone(), &opt);
 
         workers.push(worker_sender);
+        let busy = busy.clone();
         thread::spawn(move || {
             local_sender.send(id).unwrap();
             while let Ok(job) = worker_receiver.recv() {
-                let ip = job.peer_addr();
-                if let Err(err) = ctx.execute(job) {
-                    eprintln!("[Thread {id}] {err}")
-                }
-                if let Ok(addr) = ip {
-                    println!("[Thread {id}] Client `{addr}` disconnected");
-                }
+                busy.fetch_add(1, Ordering::SeqCst);
+                serve_client(&ctx, &format!("Thread {id}"), job);
+                busy.fetch_sub(1, Ordering::SeqCst);
                 local_sender.send(id).unwrap();
             }
         });
     }
 
-    let addr = format!("{}:{}", opt.ip, opt.port);
-    let listener = match TcpListener::bind(&addr) {
-        Ok(listener) => listener,
-        Err(err) => {
-            eprintln!("ERROR: failed to bind TCP listener: {err}");
-            process::exit(1);
+    let pool = Pool {
+        receiver: Mutex::new(receiver),
+        workers,
+        busy,
+        burst: Burst {
+            ctx: Arc::new(WorkerContext::new(source.clone(), hashes.clone(), &flags, &hints, catalog.clone(), scheduler.clone(), &opt)),
+            active: Arc::new(AtomicUsize::new(0)),
+            max: opt.burst_threads,
         },
+        health_check: opt.health_check,
+        accept_rate: opt.accept_rate,
+        accept_reject: opt.accept_reject,
     };
 
-    println!("Server listening on: {addr}");
+    #[cfg(feature = "quic")]
+    if let Some(port) = opt.quic_port {
+        let ctx = Arc::new(WorkerContext::new(source.clone(), hashes.clone(), &flags, &hints, catalog.clone(), None, &opt));
+        match quic::start(ctx, SocketAddr::new(opt.ips[0], port), &opt.quic_cert, opt.thread_count) {
+            Ok(addr) => println!("Server listening on: {addr} over QUIC, clients are to trust `{}`", opt.quic_cert.display()),
+            Err(err) => {
+                eprintln!("ERROR: failed to serve QUIC on port {port}: {err}");
+                process::exit(1);
+            },
+        }
+    }
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let worker_id = receiver.recv().unwrap();
+    if let Some(path) = &opt.unix_sock {
+        if opt.announce {
+            eprintln!("WARNING: Not announcing the server, clients can't discover a Unix socket");
+        }
+        serve_unix(path, &pool);
+    }
 
-                if let Ok(addr) = stream.peer_addr() {
-                    println!("[Thread {worker_id}] Client `{addr}` connected");
-                }
+    // Once an IPv4 address is listened on too, IPv6 listeners must leave it alone, or the
+    // wildcard addresses of the two would collide.
+    let v6_only = opt.ips.iter().any(IpAddr::is_ipv4);
+    let listeners: Vec<TcpListener> = opt.ips.iter()
+        .filter_map(|&ip| {
+            let addr = SocketAddr::new(ip, opt.port);
+            match bind(addr, v6_only) {
+                Ok(listener) => {
+                    println!("Server listening on: {addr}");
+                    Some(listener)
+                },
+                Err(err) => {
+                    eprintln!("ERROR: failed to bind TCP listener to `{addr}`: {err}");
+                    if let Some(hint) = bind_hint(&err, addr) {
+                        eprintln!("{hint}");
+                    }
+                    None
+                },
+            }
+        })
+        .collect();
+
+    // Serving on the addresses that could be bound beats not serving at all, the errors
+    // above say which ones are missing.
+    if listeners.is_empty() {
+        process::exit(1);
+    }
 
-                workers[worker_id].send(stream).unwrap();
+    if opt.announce {
+        match listeners.iter().filter_map(|listener| listener.local_addr().ok()).find(SocketAddr::is_ipv4) {
+            Some(addr) => {
+                let discovery_port = opt.discovery_port;
+                thread::spawn(move || {
+                    if let Err(err) = announce(addr.ip(), addr.port(), discovery_port) {
+                        eprintln!("ERROR: Stopped announcing the server: {err}");
+                    }
+                });
             },
-            Err(err) => {
-                eprintln!("ERROR: Failed to retrieve incoming stream: {err}");
+            None => eprintln!("WARNING: Not announcing the server, discovery broadcasts need an IPv4 listener"),
+        }
+    }
+
+    if let [listener] = listeners.as_slice() {
+        dispatch(listener.incoming().map(|stream| stream.map(Stream::Tcp)), &pool);
+        return;
+    }
+
+    // An accept loop per listener, all feeding the same pool.
+    let (stream_sender, streams) = mpsc::channel();
+    for listener in listeners {
+        let stream_sender = stream_sender.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stream_sender.send(stream.map(Stream::Tcp)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(stream_sender);
+    dispatch(streams.into_iter(), &pool);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file of `size` bytes in the temporary directory, named after the test using it.
+    fn temp_file(name: &str, size: usize) -> PathBuf {
+        let path = env::temp_dir().join(format!("server-test-{}-{name}", process::id()));
+        fs::write(&path, vec![7; size]).unwrap();
+        path
+    }
+
+    /// The configuration of an environment with only `vars` set.
+    pub(crate) fn config(vars: &[(&str, &str)]) -> Result<Config, String> {
+        Config::from_env(&vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    /// Serves `paths` like files of the input directory, with the defaults for everything
+    /// else.
+    fn context(paths: &[&Path]) -> Arc<WorkerContext> {
+        let files: FileList = paths.iter()
+            .map(|path| (path.file_name().unwrap().to_string_lossy().into(), path.metadata().unwrap().len()))
+            .collect();
+        let sources = paths.iter().map(|path| Source::File(path.to_path_buf())).collect();
+        let source = Arc::new(Filesystem::new(files, sources, false));
+        let none = priority_list::new(paths.len());
+        Arc::new(WorkerContext::new(source, Arc::new(Hashes::OnRequest { threads: 1 }), &none, &none, None, None, &config(&[]).unwrap()))
+    }
+
+    /// A connected pair of sockets, the client's end first.
+    fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    /// Runs a session of `ctx` on a thread of its own.
+    fn serve(ctx: &Arc<WorkerContext>, stream: TcpStream) -> thread::JoinHandle<common::Result<()>> {
+        let ctx = ctx.clone();
+        thread::spawn(move || ctx.execute(stream, &mut SessionStats::new(ctx.source.list().len())))
+    }
+
+    /// Goes through the handshake without any optional feature and asks for `priorities`.
+    fn request(stream: &mut TcpStream, priorities: &[u8]) -> FileList {
+        stream.write_all(&[Codec::None as u8]).unwrap();
+        stream.read_exact(&mut [0]).unwrap();
+        protocol::send_features(stream, 0).unwrap();
+        protocol::recv_features(stream).unwrap();
+        let files = FileList::recv(stream).unwrap();
+        let ranges: RangeList = vec![(0, RANGE_TO_END); files.len()].into();
+        ranges.send(stream).unwrap();
+        priority_list::send(stream, priorities).unwrap();
+        files
+    }
+
+    /// Receives the chunks of one file, returning how many bytes it had.
+    fn receive_file(stream: &mut TcpStream) -> u64 {
+        let mut received = 0;
+        loop {
+            let chunk = Chunk::recv_with(stream, Codec::None).unwrap();
+            received += chunk.len as u64;
+            if chunk.end() {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn thread_count_must_be_positive_and_is_capped() {
+        let opt = config(&[("THREAD_COUNT", "4")]).unwrap();
+        assert_eq!((opt.thread_count, opt.hash_threads), (4, 4));
+        assert_eq!(config(&[("THREAD_COUNT", "100000")]).unwrap().thread_count, MAX_THREAD_COUNT);
+        for count in ["0", "-1", "four", ""] {
+            let err = config(&[("THREAD_COUNT", count)]).err().unwrap();
+            assert!(err.contains("`THREAD_COUNT` expects a positive number"), "{err}");
+        }
+    }
+
+    #[test]
+    fn addresses_and_ports_must_be_valid() {
+        let opt = config(&[("IP", "127.0.0.1, ::1"), ("PORT", "8080")]).unwrap();
+        assert_eq!(opt.ips, ["127.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert_eq!(opt.port, 8080);
+
+        let invalid = [
+            ("IP", "localhost", "`IP` expects comma separated IPv4 or IPv6 addresses, got `localhost`"),
+            ("IP", "127.0.0.1,", "`IP` expects comma separated IPv4 or IPv6 addresses, got ``"),
+            ("IP", "256.0.0.1", "`IP` expects comma separated IPv4 or IPv6 addresses, got `256.0.0.1`"),
+            ("PORT", "0", "`PORT` must be a number from 1 to 65535, got `0`"),
+            ("PORT", "65536", "`PORT` must be a number from 1 to 65535, got `65536`"),
+            ("DISCOVERY_PORT", "http", "`DISCOVERY_PORT` must be a number from 1 to 65535, got `http`"),
+        ];
+        for (name, value, expected) in invalid {
+            assert_eq!(config(&[(name, value)]).err().as_deref(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn bind_failures_hint_at_the_setting_to_change() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        let hint = bind_hint(&denied, (Ipv4Addr::LOCALHOST, 80).into()).unwrap();
+        assert!(hint.contains("below 1024") && hint.contains("`PORT`"), "{hint}");
+        assert_eq!(bind_hint(&denied, (Ipv4Addr::LOCALHOST, 8080).into()), None);
+
+        let in_use = io::Error::from(io::ErrorKind::AddrInUse);
+        assert!(bind_hint(&in_use, (Ipv4Addr::LOCALHOST, 3000).into()).unwrap().contains("port 3000"));
+        let unavailable = io::Error::from(io::ErrorKind::AddrNotAvailable);
+        assert!(bind_hint(&unavailable, (Ipv4Addr::new(10, 1, 2, 3), 3000).into()).unwrap().contains("`10.1.2.3`"));
+    }
+
+    #[test]
+    fn file_changed_mid_transfer_ends_early() {
+        // Far more than the socket buffers hold, so that the server is still sending it
+        // when the change is noticed.
+        let size = 32 << 20;
+        let path = temp_file("changed", size);
+        let ctx = context(&[&path]);
+        let (mut client, server) = socket_pair();
+        let session = serve(&ctx, server);
+
+        request(&mut client, &[1]);
+        let first = Chunk::recv_with(&mut client, Codec::None).unwrap();
+        File::options().append(true).open(&path).unwrap().write_all(b"more").unwrap();
+        thread::sleep(CHANGE_CHECK_INTERVAL + Duration::from_millis(200));
+        let received = first.len as u64 + receive_file(&mut client);
+        assert!(received < size as u64, "received {received} of {size} bytes");
+
+        drop(client);
+        session.join().unwrap().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn shares_split_rounds_in_their_ratio() {
+        // Told apart by their bytes, as chunks don't say which file they're of.
+        let paths = [1, 2].map(|byte| {
+            let path = env::temp_dir().join(format!("server-test-{}-share-{byte}", process::id()));
+            fs::write(&path, vec![byte; 256 << 10]).unwrap();
+            path
+        });
+        let ctx = context(&[&paths[0], &paths[1]]);
+        let (mut client, server) = socket_pair();
+        let session = serve(&ctx, server);
+
+        let shares = ["70%", "30%"].map(|share| priority_list::parse(share).unwrap());
+        request(&mut client, &shares);
+        let mut first_round = [0; 2];
+        for _ in 0..100 {
+            let chunk = Chunk::recv_with(&mut client, Codec::None).unwrap();
+            for byte in chunk.data() {
+                first_round[*byte as usize - 1] += 1;
+            }
+        }
+        assert_eq!(first_round, [70 << 10, 30 << 10]);
+
+        let mut ended = 0;
+        while ended < 2 {
+            ended += Chunk::recv_with(&mut client, Codec::None).unwrap().end() as usize;
+        }
+        drop(client);
+        session.join().unwrap().unwrap();
+        for path in paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn windowed_session_waits_for_acknowledgements() {
+        let size = 64 << 10;
+        let path = temp_file("window", size);
+        let ctx = context(&[&path]);
+        let (mut client, server) = socket_pair();
+        let session = serve(&ctx, server);
+
+        client.write_all(&[Codec::None as u8]).unwrap();
+        client.read_exact(&mut [0]).unwrap();
+        protocol::send_features(&mut client, protocol::FEATURE_WINDOW).unwrap();
+        protocol::recv_features(&mut client).unwrap();
+        protocol::send_window(&mut client, 2).unwrap();
+        FileList::recv(&mut client).unwrap();
+        RangeList::from(vec![(0, RANGE_TO_END)]).send(&mut client).unwrap();
+        priority_list::send(&mut client, &[1]).unwrap();
+
+        // A round is one chunk at this priority, only two of them come unacknowledged.
+        let nothing_sent = |client: &mut TcpStream| {
+            client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let peeked = client.peek(&mut [0]);
+            client.set_read_timeout(None).unwrap();
+            peeked.is_err()
+        };
+        let mut received = 0;
+        for _ in 0..2 {
+            received += Chunk::recv_with(&mut client, Codec::None).unwrap().len as u64;
+        }
+        assert!(nothing_sent(&mut client));
+        protocol::send_ack(&mut client, 1).unwrap();
+        received += Chunk::recv_with(&mut client, Codec::None).unwrap().len as u64;
+        assert!(nothing_sent(&mut client));
+
+        let mut seq = 3;
+        protocol::send_ack(&mut client, seq).unwrap();
+        loop {
+            let chunk = Chunk::recv_with(&mut client, Codec::None).unwrap();
+            received += chunk.len as u64;
+            seq += 1;
+            protocol::send_ack(&mut client, seq).unwrap();
+            if chunk.end() {
+                break;
             }
         }
+        assert_eq!(received, size as u64);
+
+        drop(client);
+        session.join().unwrap().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn client_without_features_gets_the_base_protocol() {
+        let path = temp_file("base", 3000);
+        let ctx = context(&[&path]);
+        let (mut client, server) = socket_pair();
+        let session = serve(&ctx, server);
+
+        client.write_all(&[Codec::None as u8]).unwrap();
+        client.read_exact(&mut [0]).unwrap();
+        protocol::send_features(&mut client, 0).unwrap();
+        // The server has more to offer, but only uses what both sides have.
+        assert_ne!(protocol::recv_features(&mut client).unwrap() & protocol::FEATURE_FLAGS, 0);
+        let files = FileList::recv(&mut client).unwrap();
+        assert_eq!(files[..], [(path.file_name().unwrap().to_string_lossy().into(), 3000)]);
+        RangeList::from(vec![(0, RANGE_TO_END)]).send(&mut client).unwrap();
+        priority_list::send(&mut client, &[1]).unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let chunk = Chunk::recv_with(&mut client, Codec::None).unwrap();
+            if chunk.write(&mut received).unwrap() {
+                break;
+            }
+        }
+        assert_eq!(received, fs::read(&path).unwrap());
+
+        drop(client);
+        session.join().unwrap().unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn accept_rate_lets_a_second_worth_through_after_a_quiet_period() {
+        // Far enough from when the clock started that a second before it exists.
+        let start = Instant::now() + Duration::from_secs(10);
+        let mut accept_rate = AcceptRate::new(10);
+        for _ in 0..10 {
+            assert!(accept_rate.admit(start, false).unwrap() <= start);
+        }
+        // The rest wait for their turn, a tenth of a second apart.
+        for idx in 1..=5 {
+            assert_eq!(accept_rate.admit(start, false), Some(start + Duration::from_millis(100) * idx));
+        }
+
+        let later = start + Duration::from_secs(5);
+        for _ in 0..10 {
+            assert!(accept_rate.admit(later, false).unwrap() <= later);
+        }
+        assert_eq!(accept_rate.admit(later, false), Some(later + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn accept_rate_rejects_without_using_up_the_rate() {
+        let start = Instant::now() + Duration::from_secs(10);
+        let mut accept_rate = AcceptRate::new(10);
+        for _ in 0..10 {
+            assert!(accept_rate.admit(start, true).is_some());
+        }
+        for _ in 0..100 {
+            assert_eq!(accept_rate.admit(start, true), None);
+        }
+        // A token is back a tenth of a second later, however many were turned away.
+        assert_eq!(accept_rate.admit(start + Duration::from_millis(50), true), None);
+        let next = start + Duration::from_millis(100);
+        assert_eq!(accept_rate.admit(next, true), Some(next));
+        assert_eq!(accept_rate.admit(next, true), None);
+    }
+
+    #[test]
+    fn burst_thread_serves_a_client_when_every_worker_is_busy() {
+        let path = temp_file("burst", 5000);
+        let ctx = context(&[&path]);
+        // No worker ever announces itself as idle.
+        let (_idle_sender, receiver) = mpsc::channel();
+        let pool = Pool {
+            receiver: Mutex::new(receiver),
+            workers: Vec::new(),
+            busy: Arc::new(AtomicUsize::new(0)),
+            burst: Burst { ctx: ctx.clone(), active: Arc::new(AtomicUsize::new(0)), max: 1 },
+            health_check: false,
+            accept_rate: None,
+            accept_reject: false,
+        };
+
+        let (mut client, server) = socket_pair();
+        dispatch([Ok(Stream::Tcp(server))].into_iter(), &pool);
+        assert_eq!(pool.burst.active.load(Ordering::SeqCst), 1);
+        request(&mut client, &[1]);
+        assert_eq!(receive_file(&mut client), 5000);
+
+        // The one burst thread allowed is taken, so another client has to wait for a worker.
+        let (_other_client, other_server) = socket_pair();
+        assert!(pool.burst.spawn(Stream::Tcp(other_server)).is_err());
+
+        drop(client);
+        while pool.burst.active.load(Ordering::SeqCst) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn probes_are_answered_without_holding_up_clients() {
+        let path = temp_file("probe", 5000);
+        let ctx = context(&[&path]);
+        let (_idle_sender, receiver) = mpsc::channel();
+        let pool = Pool {
+            receiver: Mutex::new(receiver),
+            workers: Vec::new(),
+            busy: Arc::new(AtomicUsize::new(0)),
+            burst: Burst { ctx, active: Arc::new(AtomicUsize::new(0)), max: 2 },
+            health_check: true,
+            accept_rate: None,
+            accept_reject: false,
+        };
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // A connection that doesn't send anything yet comes before the probe and the client.
+        let silent = TcpStream::connect(addr).unwrap();
+        let mut probe = TcpStream::connect(addr).unwrap();
+        probe.write_all(protocol::HEALTH_PROBE).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        let started = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(|| dispatch(listener.incoming().take(3).map(|stream| stream.map(Stream::Tcp)), &pool));
+            let mut answer = String::new();
+            probe.read_to_string(&mut answer).unwrap();
+            assert_eq!(answer, "OK 0 0\n");
+            request(&mut client, &[1]);
+            assert_eq!(receive_file(&mut client), 5000);
+            assert!(started.elapsed() < PROBE_TIMEOUT, "{:?}", started.elapsed());
+            drop(client);
+            drop(silent);
+        });
+
+        while pool.burst.active.load(Ordering::SeqCst) != 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn client_leaving_mid_transfer_only_ends_its_session() {
+        let path = temp_file("leaving", 32 << 20);
+        let ctx = context(&[&path]);
+        let (mut client, server) = socket_pair();
+        let session = serve(&ctx, server);
+        request(&mut client, &[1]);
+        Chunk::recv_with(&mut client, Codec::None).unwrap();
+        drop(client);
+
+        let err = session.join().unwrap().unwrap_err();
+        assert!(matches!(&err, Error::Io(err) if matches!(err.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset)), "{err}");
+
+        // Still serving after the write failed.
+        let (mut client, server) = socket_pair();
+        let session = serve(&ctx, server);
+        request(&mut client, &[1]);
+        assert_eq!(receive_file(&mut client), 32 << 20);
+        drop(client);
+        session.join().unwrap().unwrap();
+        fs::remove_file(path).unwrap();
     }
 }
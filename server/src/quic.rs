@@ -0,0 +1,216 @@
+//! Serves clients over QUIC, with every file on a stream of its own as described in
+//! `common::quic`. Only downloads are served this way, the rest of the protocol needs TCP.
+//!
+//! Some settings of TCP sessions don't apply, and QUIC is refused at startup when they're
+//! set: every file is sent as fast as its stream allows, so there are no rounds for
+//! `FAIR_SCHEDULING` to share or `ROUND_CHUNKS` to cap, and clients don't negotiate
+//! features, so there are none for `REQUIRE_FEATURES` to require. `ACCEPT_RATE` and
+//! `ACCEPT_REJECT` only pace the TCP listeners. Like workers, at most `THREAD_COUNT`
+//! clients are served at once, the others are turned away.
+
+use std::{fs, io::{self, Read, Write}, net::SocketAddr, path::Path, sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex}, thread};
+use common::{quic::{Connection, FileRequest, Listener, QuicStream, SERVER_NAME}, Chunk, Codec, Packet};
+use crate::{source::FileSource, stats::SessionStats, WorkerContext};
+
+/// Listens on `addr` with a new self-signed certificate, written to `cert_path` for clients
+/// to trust, and serves up to `max_clients` clients at once on threads of their own.
+pub fn start(ctx: Arc<WorkerContext>, addr: SocketAddr, cert_path: &Path, max_clients: usize) -> io::Result<SocketAddr> {
+    let certified = rcgen::generate_simple_self_signed([SERVER_NAME.to_string()])
+        .map_err(|err| io::Error::other(format!("failed to make a certificate: {err}")))?;
+    let cert = certified.cert.der().to_vec();
+    fs::write(cert_path, &cert)?;
+    let listener = Listener::bind(addr, cert, certified.key_pair.serialize_der())?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || serve(ctx, listener, max_clients));
+    Ok(addr)
+}
+
+fn serve(ctx: Arc<WorkerContext>, listener: Listener, max_clients: usize) {
+    let clients = Arc::new(AtomicUsize::new(0));
+    while let Some(incoming) = listener.accept() {
+        if clients.fetch_add(1, Ordering::SeqCst) >= max_clients {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            incoming.refuse();
+            continue;
+        }
+
+        let (ctx, clients) = (ctx.clone(), clients.clone());
+        thread::spawn(move || {
+            match incoming.establish() {
+                Ok(connection) => {
+                    let addr = connection.remote_address();
+                    println!("[QUIC] Client `{addr}` connected");
+                    // Files found while the client is connected are left to its next connection.
+                    let source = ctx.latest_source();
+                    let stats = Mutex::new(SessionStats::new(source.list().len()));
+                    if let Err(err) = serve_connection(&ctx, source.as_ref(), &connection, &stats) {
+                        eprintln!("[QUIC] {err}");
+                    }
+                    println!("{}", stats.into_inner().unwrap().to_json(source.list(), Some(addr)));
+                    println!("[QUIC] Client `{addr}` disconnected");
+                },
+                Err(err) => eprintln!("[QUIC] Failed to accept a client: {err}"),
+            }
+            clients.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// Answers the first stream of `connection` with the file list, and every other one with
+/// the file it asks for. The connection allows `common::quic::MAX_STREAMS` of them at
+/// once, so as many threads serve it at most.
+fn serve_connection(ctx: &WorkerContext, source: &dyn FileSource, connection: &Connection, stats: &Mutex<SessionStats>) -> io::Result<()> {
+    let Some(mut stream) = connection.accept()? else {
+        return Ok(());
+    };
+    let codec = {
+        let mut buf = [0; 1];
+        stream.read_exact(&mut buf)?;
+        Codec::from_id(buf[0]).unwrap_or(Codec::None)
+    };
+    stream.write_all(&[codec as u8])?;
+    source.list().send(&mut stream)?;
+    stream.finish()?;
+
+    let level = ctx.compression_level.unwrap_or(codec.default_level());
+    thread::scope(|scope| {
+        while let Some(stream) = connection.accept()? {
+            scope.spawn(move || {
+                if let Err(err) = serve_file(source, stream, codec, level, stats) {
+                    eprintln!("[QUIC] {err}");
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+/// Sends the file `stream` asks for, and finishes it.
+fn serve_file(source: &dyn FileSource, mut stream: QuicStream, codec: Codec, level: i32, stats: &Mutex<SessionStats>) -> io::Result<()> {
+    let request = FileRequest::recv(&mut stream)?;
+    let Some(idx) = usize::try_from(request.idx).ok().filter(|idx| *idx < source.list().len()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request of an unknown file"));
+    };
+    if request.range.0 > request.range.1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "range ends before it starts"));
+    }
+    stream.set_priority(request.priority as i32)?;
+    stats.lock().unwrap().priorities[idx] = request.priority;
+
+    // Like over TCP, a file that can't be sent as listed ends right away.
+    let opened = if source.changed(idx) {
+        Err(format!("`{}` changed since it was listed", source.path(idx).display()))
+    } else {
+        source.open(idx, request.range).map_err(|err| format!("Failed to open `{}`: {err}", source.path(idx).display()))
+    };
+    match opened {
+        Ok(mut file) => loop {
+            let chunk = Chunk::read(file.as_mut())?;
+            chunk.send_with(&mut stream, codec, level)?;
+            stats.lock().unwrap().delivered[idx] += chunk.len as u64;
+            if chunk.end() {
+                break;
+            }
+        },
+        Err(msg) => {
+            eprintln!("ERROR: {msg}");
+            Chunk::empty().send_with(&mut stream, codec, level)?;
+        },
+    }
+    stream.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf, process, time::Duration};
+    use common::{download::ProgressEvent, quic, FileList};
+    use crate::{hashing::Hashes, source::{Filesystem, Source}, tests::config};
+    use super::*;
+
+    /// Serves like `inner`, with every read of file `slow` held back.
+    struct Delayed {
+        inner: Filesystem,
+        slow: usize,
+    }
+
+    struct SlowReader(Box<dyn Read>);
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(10));
+            self.0.read(buf)
+        }
+    }
+
+    impl FileSource for Delayed {
+        fn list(&self) -> &FileList {
+            self.inner.list()
+        }
+
+        fn flags(&self, idx: usize) -> u8 {
+            self.inner.flags(idx)
+        }
+
+        fn open(&self, idx: usize, range: (u64, u64)) -> io::Result<Box<dyn Read>> {
+            let file = self.inner.open(idx, range)?;
+            Ok(if idx == self.slow { Box::new(SlowReader(file)) } else { file })
+        }
+
+        fn path(&self, idx: usize) -> PathBuf {
+            self.inner.path(idx)
+        }
+
+        fn backing_file(&self, idx: usize) -> Option<&Path> {
+            self.inner.backing_file(idx)
+        }
+
+        fn changed(&self, idx: usize) -> bool {
+            self.inner.changed(idx)
+        }
+
+        fn mtime(&self, idx: usize) -> Option<u64> {
+            self.inner.mtime(idx)
+        }
+    }
+
+    #[test]
+    fn a_delayed_stream_holds_back_only_its_file() {
+        let dir = env::temp_dir().join(format!("server-test-{}-quic", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // More files than streams may be open at once.
+        let names: Vec<String> = ["slow.bin".to_string()].into_iter()
+            .chain((1..quic::MAX_STREAMS as usize + 4).map(|idx| format!("{idx}.bin")))
+            .collect();
+        let sizes: Vec<usize> = (0..names.len()).map(|idx| if idx == 0 { 100_000 } else { 300_000 + idx }).collect();
+        let paths: Vec<PathBuf> = names.iter().zip(&sizes).enumerate().map(|(idx, (name, size))| {
+            let path = dir.join(name);
+            fs::write(&path, vec![idx as u8 + 1; *size]).unwrap();
+            path
+        }).collect();
+
+        let files: FileList = names.iter().zip(&sizes).map(|(name, size)| (name.as_str().into(), *size as u64)).collect();
+        let sources = paths.iter().map(|path| Source::File(path.clone())).collect();
+        let source = Arc::new(Delayed { inner: Filesystem::new(files, sources, false), slow: 0 });
+        let none = vec![0; names.len()].into();
+        let ctx = Arc::new(WorkerContext::new(source, Arc::new(Hashes::Fixed(vec![None; names.len()].into())), &none, &none, None, None, &config(&[]).unwrap()));
+        let addr = start(ctx, (std::net::Ipv4Addr::LOCALHOST, 0).into(), &dir.join("cert.der"), 1).unwrap();
+        let cert = fs::read(dir.join("cert.der")).unwrap();
+
+        let out = dir.join("out");
+        let mut completed = Vec::new();
+        let summary = quic::download_all(&addr.to_string(), &cert, &out, &mut |files| vec![1; files.len()].into(), &mut |event| {
+            if let ProgressEvent::Completed { name } = event {
+                completed.push(name.to_string());
+            }
+        }).unwrap();
+
+        assert!(summary.failed.is_empty(), "{:?}", summary.failed);
+        // The smallest file was asked for first, yet the others aren't held back by it.
+        assert_eq!(completed.len(), names.len());
+        assert_eq!(completed.last().map(String::as_str), Some("slow.bin"));
+        for (name, path) in names.iter().zip(&paths) {
+            assert_eq!(fs::read(out.join(name)).unwrap(), fs::read(path).unwrap(), "{name}");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{Condvar, Mutex};
+
+/// Passes turns around the sessions that are downloading. A session sends one scheduling
+/// round per turn, that is `priority` chunks of every requested file, so the share each
+/// client gets is proportional to the priorities it asked for. Sessions that aren't
+/// waiting for a turn, such as one still writing its last round to a slow client, are
+/// skipped rather than waited for.
+pub struct Scheduler {
+    rotation: Mutex<Rotation>,
+    turn_changed: Condvar,
+}
+
+struct Rotation {
+    /// The id of every member and whether it's waiting for a turn.
+    members: Vec<(u64, bool)>,
+    /// Where the search for the next member to get a turn starts.
+    next: usize,
+    /// Whether a member holds the turn.
+    taken: bool,
+    next_id: u64,
+}
+
+impl Rotation {
+    /// The first waiting member from `next` on, wrapping around.
+    fn up_next(&self) -> Option<u64> {
+        let len = self.members.len();
+        (0..len).map(|offset| self.members[(self.next + offset) % len])
+            .find(|(_, waiting)| *waiting)
+            .map(|(id, _)| id)
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.members.iter().position(|(member, _)| *member == id)
+    }
+}
+
+/// A session taking part in the rotation, it leaves it once dropped.
+pub struct Member<'a> {
+    scheduler: &'a Scheduler,
+    id: u64,
+}
+
+/// Held by a session while it's sending, the turn passes on once it's dropped.
+pub struct Turn<'a> {
+    scheduler: &'a Scheduler,
+    id: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            rotation: Mutex::new(Rotation { members: Vec::new(), next: 0, taken: false, next_id: 0 }),
+            turn_changed: Condvar::new(),
+        }
+    }
+
+    pub fn join(&self) -> Member<'_> {
+        let mut rotation = self.rotation.lock().unwrap();
+        let id = rotation.next_id;
+        rotation.next_id += 1;
+        rotation.members.push((id, false));
+        Member { scheduler: self, id }
+    }
+}
+
+impl Member<'_> {
+    /// Waits until every other waiting member had its turn since the last one of this
+    /// session.
+    pub fn turn(&self) -> Turn<'_> {
+        let mut rotation = self.scheduler.rotation.lock().unwrap();
+        if let Some(idx) = rotation.position(self.id) {
+            rotation.members[idx].1 = true;
+        }
+        let mut rotation = self.scheduler.turn_changed
+            .wait_while(rotation, |rotation| rotation.taken || rotation.up_next() != Some(self.id))
+            .unwrap();
+        rotation.taken = true;
+        if let Some(idx) = rotation.position(self.id) {
+            rotation.members[idx].1 = false;
+        }
+        Turn { scheduler: self.scheduler, id: self.id }
+    }
+}
+
+impl Drop for Member<'_> {
+    fn drop(&mut self) {
+        let mut rotation = self.scheduler.rotation.lock().unwrap();
+        let Some(idx) = rotation.position(self.id) else {
+            return;
+        };
+        rotation.members.remove(idx);
+        if idx < rotation.next {
+            rotation.next -= 1;
+        }
+        if rotation.next >= rotation.members.len() {
+            rotation.next = 0;
+        }
+        self.scheduler.turn_changed.notify_all();
+    }
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        let mut rotation = self.scheduler.rotation.lock().unwrap();
+        rotation.taken = false;
+        if let Some(idx) = rotation.position(self.id) {
+            rotation.next = (idx + 1) % rotation.members.len();
+        }
+        self.scheduler.turn_changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_not_waiting_are_skipped() {
+        let scheduler = Scheduler::new();
+        // Joined but never asks for a turn, like a session blocked writing to its client.
+        let _stalled = scheduler.join();
+        let active = scheduler.join();
+        for _ in 0..3 {
+            drop(active.turn());
+        }
+    }
+
+    #[test]
+    fn turn_passes_to_the_next_waiting_member() {
+        let scheduler = Scheduler::new();
+        let (first, second) = (scheduler.join(), scheduler.join());
+        let turn = first.turn();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| drop(second.turn()));
+            drop(turn);
+            waiter.join().unwrap();
+        });
+        let rotation = scheduler.rotation.lock().unwrap();
+        assert!(!rotation.taken);
+        assert_eq!(rotation.next, 0);
+    }
+}
@@ -0,0 +1,331 @@
+use std::{fs::File, io::{self, Read, Seek, SeekFrom}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::UNIX_EPOCH};
+use common::{FileList, RANGE_TO_END};
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use crate::synthetic::{Generator, Pattern};
+
+/// Where the served files come from, so that serving isn't tied to files on disk.
+pub trait FileSource: Send + Sync {
+    /// Every served file, in the order it is advertised.
+    fn list(&self) -> &FileList;
+
+    /// The `FLAG_*` bits of file `idx`.
+    fn flags(&self, idx: usize) -> u8;
+
+    /// Opens `start..end` of file `idx`, where `end` may be `RANGE_TO_END`.
+    fn open(&self, idx: usize, range: (u64, u64)) -> io::Result<Box<dyn Read>>;
+
+    /// Names file `idx` in logs and in the hash cache.
+    fn path(&self, idx: usize) -> PathBuf;
+
+    /// The file on disk whose changes invalidate the content of `idx`. Digests of files
+    /// without one aren't cached.
+    fn backing_file(&self, idx: usize) -> Option<&Path>;
+
+    /// Whether file `idx` changed since it was listed, so that its content may no longer
+    /// match what was advertised.
+    fn changed(&self, idx: usize) -> bool;
+
+    /// When file `idx` was last modified as it was listed, in seconds since the Unix epoch.
+    fn mtime(&self, idx: usize) -> Option<u64>;
+}
+
+/// The size and modification time of a file, which change whenever its content does.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Stamp {
+    pub size: u64,
+    pub mtime: u128,
+}
+
+impl Stamp {
+    pub fn of(path: &Path) -> io::Result<Self> {
+        let metadata = path.metadata()?;
+        let mtime = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos())
+            .unwrap_or(0);
+        Ok(Stamp { size: metadata.len(), mtime })
+    }
+}
+
+pub fn is_gz(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Opens the served content of `path` restricted to `range`, decompressing `.gz` files
+/// when `decompress_gz` is set.
+fn open_source(path: &Path, decompress_gz: bool, (start, end): (u64, u64)) -> io::Result<Box<dyn Read>> {
+    let mut file = File::open(path)?;
+    let reader: Box<dyn Read> = if decompress_gz && is_gz(path) {
+        let mut decoder = MultiGzDecoder::new(file);
+        io::copy(&mut (&mut decoder).take(start), &mut io::sink())?;
+        Box::new(decoder)
+    } else {
+        file.seek(SeekFrom::Start(start))?;
+        Box::new(file)
+    };
+
+    if end == RANGE_TO_END {
+        return Ok(reader);
+    }
+    Ok(Box::new(reader.take(end - start)))
+}
+
+/// One file of a `Filesystem`, stored on its own or in an archive.
+#[derive(Clone)]
+pub enum Source {
+    File(PathBuf),
+    /// A file stored in an archive, taking up `len` bytes at `offset`.
+    Entry {
+        archive: PathBuf,
+        name: Box<str>,
+        offset: u64,
+        len: u64,
+        deflated: bool,
+        flags: u8,
+    },
+    /// Generated on the fly instead of read from disk.
+    Synthetic {
+        name: Box<str>,
+        size: u64,
+        pattern: Pattern,
+    },
+}
+
+impl Source {
+    /// Names the file in logs and in the hash cache.
+    fn path(&self) -> PathBuf {
+        match self {
+            Source::File(path) => path.clone(),
+            Source::Entry { archive, name, .. } => archive.join(name.as_ref()),
+            Source::Synthetic { name, .. } => PathBuf::from(name.as_ref()),
+        }
+    }
+
+    /// The file on disk whose changes invalidate the content, if there is one.
+    pub fn backing_file(&self) -> Option<&Path> {
+        match self {
+            Source::File(path) => Some(path),
+            Source::Entry { archive, .. } => Some(archive),
+            Source::Synthetic { .. } => None,
+        }
+    }
+
+    fn stamp(&self) -> Option<Stamp> {
+        self.backing_file().and_then(|path| Stamp::of(path).ok())
+    }
+
+    fn flags(&self) -> u8 {
+        match self {
+            Source::File(path) => file_flags(path),
+            Source::Entry { flags, .. } => *flags,
+            Source::Synthetic { .. } => 0,
+        }
+    }
+
+    /// Opens the served content restricted to `range`. Deflated archive entries are
+    /// decompressed on the fly, skipping everything before `start`.
+    fn open(&self, decompress_gz: bool, (start, end): (u64, u64)) -> io::Result<Box<dyn Read>> {
+        let (archive, offset, len, deflated) = match self {
+            Source::File(path) => return open_source(path, decompress_gz, (start, end)),
+            Source::Entry { archive, offset, len, deflated, .. } => (archive, *offset, *len, *deflated),
+            Source::Synthetic { size, pattern, .. } => return Ok(Box::new(Generator::new(*pattern, start, end.min(*size)))),
+        };
+
+        let mut file = File::open(archive)?;
+        let reader: Box<dyn Read> = if deflated {
+            file.seek(SeekFrom::Start(offset))?;
+            let mut decoder = DeflateDecoder::new(file.take(len));
+            io::copy(&mut (&mut decoder).take(start), &mut io::sink())?;
+            Box::new(decoder)
+        } else {
+            let start = start.min(len);
+            file.seek(SeekFrom::Start(offset + start))?;
+            Box::new(file.take(len - start))
+        };
+
+        if end == RANGE_TO_END {
+            return Ok(reader);
+        }
+        Ok(Box::new(reader.take(end - start)))
+    }
+}
+
+#[cfg(unix)]
+fn file_flags(path: &Path) -> u8 {
+    use std::os::unix::fs::PermissionsExt;
+    match path.metadata() {
+        Ok(metadata) if metadata.permissions().mode() & 0o111 != 0 => common::FLAG_EXECUTABLE,
+        _ => 0,
+    }
+}
+
+#[cfg(not(unix))]
+fn file_flags(_path: &Path) -> u8 {
+    0
+}
+
+/// Files in the input directory or in an archive, the default `FileSource`.
+pub struct Filesystem {
+    files: FileList,
+    sources: Box<[Source]>,
+    /// Stamps of the backing files when they were listed.
+    stamps: Box<[Option<Stamp>]>,
+    decompress_gz: bool,
+}
+
+impl Filesystem {
+    pub fn new(files: FileList, sources: Box<[Source]>, decompress_gz: bool) -> Self {
+        let stamps = sources.iter().map(Source::stamp).collect();
+        Self { files, sources, stamps, decompress_gz }
+    }
+
+    /// The same files followed by `files`, keeping the stamps taken when each was listed.
+    pub fn appended(&self, files: Vec<(Box<str>, u64)>, sources: Vec<Source>) -> Self {
+        let stamps = sources.iter().map(Source::stamp);
+        Self {
+            files: self.files.iter().cloned().chain(files).collect(),
+            stamps: self.stamps.iter().copied().chain(stamps).collect(),
+            sources: self.sources.iter().cloned().chain(sources).collect(),
+            decompress_gz: self.decompress_gz,
+        }
+    }
+}
+
+/// The served files as they grow while the server runs. Files are only ever appended, so
+/// the indices sessions already use keep referring to the same files.
+pub struct Catalog {
+    latest: Mutex<Arc<Filesystem>>,
+}
+
+impl Catalog {
+    pub fn new(files: Arc<Filesystem>) -> Self {
+        Self { latest: Mutex::new(files) }
+    }
+
+    pub fn latest(&self) -> Arc<Filesystem> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    pub fn append(&self, files: Vec<(Box<str>, u64)>, sources: Vec<Source>) {
+        let mut latest = self.latest.lock().unwrap();
+        *latest = Arc::new(latest.appended(files, sources));
+    }
+}
+
+impl FileSource for Filesystem {
+    fn list(&self) -> &FileList {
+        &self.files
+    }
+
+    fn flags(&self, idx: usize) -> u8 {
+        self.sources[idx].flags()
+    }
+
+    fn open(&self, idx: usize, range: (u64, u64)) -> io::Result<Box<dyn Read>> {
+        self.sources[idx].open(self.decompress_gz, range)
+    }
+
+    fn path(&self, idx: usize) -> PathBuf {
+        self.sources[idx].path()
+    }
+
+    fn backing_file(&self, idx: usize) -> Option<&Path> {
+        self.sources[idx].backing_file()
+    }
+
+    fn changed(&self, idx: usize) -> bool {
+        self.sources[idx].stamp() != self.stamps[idx]
+    }
+
+    // Entries of an archive share the time of the archive, synthetic files have none.
+    fn mtime(&self, idx: usize) -> Option<u64> {
+        self.stamps[idx].map(|stamp| (stamp.mtime / 1_000_000_000) as u64)
+    }
+}
+
+/// The files of another source under one directory, for clients that only want those.
+pub struct Subtree {
+    source: Arc<dyn FileSource>,
+    /// The index in `source` of every file of the subtree.
+    indices: Box<[usize]>,
+    files: FileList,
+}
+
+impl Subtree {
+    /// `prefix` is a directory as returned by `protocol::check_subpath`.
+    pub fn new(source: Arc<dyn FileSource>, prefix: &str) -> Self {
+        let indices: Box<[usize]> = source.list().iter()
+            .enumerate()
+            .filter(|(_, (name, _))| name.strip_prefix(prefix).is_some_and(|rest| rest.starts_with('/')))
+            .map(|(idx, _)| idx)
+            .collect();
+        let files = indices.iter().map(|&idx| source.list()[idx].clone()).collect();
+        Self { source, indices, files }
+    }
+
+    /// The source the subtree is taken from.
+    pub fn inner(&self) -> &dyn FileSource {
+        self.source.as_ref()
+    }
+
+    /// The index in the whole source of file `idx` of the subtree.
+    pub fn original(&self, idx: usize) -> usize {
+        self.indices[idx]
+    }
+
+    /// Picks the entries of the subtree out of a list about every file of the source,
+    /// which may not cover the files added to it since.
+    pub fn select<T: Clone + Default>(&self, list: &[T]) -> Box<[T]> {
+        self.indices.iter().map(|&idx| list.get(idx).cloned().unwrap_or_default()).collect()
+    }
+}
+
+impl FileSource for Subtree {
+    fn list(&self) -> &FileList {
+        &self.files
+    }
+
+    fn flags(&self, idx: usize) -> u8 {
+        self.source.flags(self.indices[idx])
+    }
+
+    fn open(&self, idx: usize, range: (u64, u64)) -> io::Result<Box<dyn Read>> {
+        self.source.open(self.indices[idx], range)
+    }
+
+    fn path(&self, idx: usize) -> PathBuf {
+        self.source.path(self.indices[idx])
+    }
+
+    fn backing_file(&self, idx: usize) -> Option<&Path> {
+        self.source.backing_file(self.indices[idx])
+    }
+
+    fn changed(&self, idx: usize) -> bool {
+        self.source.changed(self.indices[idx])
+    }
+
+    fn mtime(&self, idx: usize) -> Option<u64> {
+        self.source.mtime(self.indices[idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtree_holds_only_the_files_under_its_prefix() {
+        let names = ["docs/a.md", "docsx/b.md", "docs/sub/c.md", "docs", "other/docs/d.md", "docs/e.md"];
+        let files: FileList = names.iter().map(|name| (Box::from(*name), 1)).collect();
+        let sources = names.iter().map(|name| Source::File(name.into())).collect();
+        let subtree = Subtree::new(Arc::new(Filesystem::new(files, sources, false)), "docs");
+
+        let listed: Vec<&str> = subtree.list().iter().map(|(name, _)| name.as_ref()).collect();
+        assert_eq!(listed, ["docs/a.md", "docs/sub/c.md", "docs/e.md"]);
+        assert_eq!((0..3).map(|idx| subtree.original(idx)).collect::<Vec<_>>(), [0, 2, 5]);
+        assert_eq!(subtree.select(&[10, 11, 12, 13, 14, 15]), [10, 12, 15].into());
+        // Lists that don't cover every file yet get defaults for the rest.
+        assert_eq!(subtree.select(&[10, 11, 12]), [10, 12, 0].into());
+    }
+}
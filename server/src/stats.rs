@@ -0,0 +1,57 @@
+use std::{fmt::Write, net::SocketAddr};
+use common::FileList;
+
+/// What a single client asked for and what it actually received.
+pub struct SessionStats {
+    pub priorities: Box<[u8]>,
+    pub delivered: Box<[u64]>,
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"'  => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl SessionStats {
+    pub fn new(len: usize) -> Self {
+        Self {
+            priorities: vec![0; len].into(),
+            delivered: vec![0; len].into(),
+        }
+    }
+
+    /// Formats the session summary as a single JSON line, listing only requested files.
+    /// `files` may list more files than the session knew about.
+    pub fn to_json(&self, files: &FileList, client: Option<SocketAddr>) -> String {
+        let mut json = String::from("{\"event\":\"session_end\"");
+        if let Some(client) = client {
+            write!(json, ",\"client\":\"{client}\"").unwrap();
+        }
+
+        json.push_str(",\"files\":[");
+        let requested = (0..self.priorities.len()).filter(|idx| self.priorities[*idx] != 0);
+        for (nth, idx) in requested.enumerate() {
+            if nth > 0 {
+                json.push(',');
+            }
+            let (name, size) = &files[idx];
+            write!(
+                json, "{{\"name\":\"{}\",\"size\":{size},\"priority\":{},\"delivered\":{}}}",
+                escape_json(name), self.priorities[idx], self.delivered[idx]
+            ).unwrap();
+        }
+        json.push_str("]}");
+        json
+    }
+}
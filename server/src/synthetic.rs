@@ -0,0 +1,68 @@
+use std::io::{self, Read};
+
+/// What the bytes of a synthetic file are.
+#[derive(Clone, Copy)]
+pub enum Pattern {
+    Zeros,
+    /// A pseudo-random stream determined by the seed, the same every time it's read.
+    Random(u64),
+}
+
+impl Pattern {
+    /// The random stream of every file is seeded by its name, so that files differ from
+    /// each other but not across restarts.
+    pub fn from_name(pattern: &str, file_name: &str) -> Option<Self> {
+        match pattern {
+            "zeros"  => Some(Pattern::Zeros),
+            // FNV-1a, which unlike the hasher of the standard library is stable.
+            "random" => Some(Pattern::Random(file_name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            }))),
+            _        => None,
+        }
+    }
+}
+
+/// One SplitMix64 step for every 8 bytes of the random stream, so that any range can be
+/// generated without the ones before it.
+fn random_word(seed: u64, word: u64) -> [u8; 8] {
+    let mut x = seed.wrapping_add(word.wrapping_mul(0x9e3779b97f4a7c15));
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (x ^ (x >> 31)).to_le_bytes()
+}
+
+/// Generates `start..end` of a synthetic file.
+pub struct Generator {
+    pattern: Pattern,
+    offset: u64,
+    end: u64,
+}
+
+impl Generator {
+    pub fn new(pattern: Pattern, start: u64, end: u64) -> Self {
+        Self { pattern, offset: start.min(end), end }
+    }
+}
+
+impl Read for Generator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(usize::try_from(self.end - self.offset).unwrap_or(usize::MAX));
+        let buf = &mut buf[..len];
+        match self.pattern {
+            Pattern::Zeros => buf.fill(0),
+            Pattern::Random(seed) => {
+                let mut filled = 0;
+                while filled < len {
+                    let offset = self.offset + filled as u64;
+                    let skip = (offset % 8) as usize;
+                    let count = (8 - skip).min(len - filled);
+                    buf[filled..filled + count].copy_from_slice(&random_word(seed, offset / 8)[skip..skip + count]);
+                    filled += count;
+                }
+            },
+        }
+        self.offset += len as u64;
+        Ok(len)
+    }
+}